@@ -11,9 +11,17 @@ cp rust/target/release/lights-app /opt/lights-app/bin/
 # Copy over the front-end
 cp -r frontend/dist/* /opt/lights-app/www/
 
-# Copy over the key and cert
-cp lights.key /opt/lights-app/secrets/
-cp lights.crt /opt/lights-app/secrets/
+# Copy over the key and cert if there are any, otherwise a self-signed cert is
+# generated when the service first starts
+if [ -f lights.key ] && [ -f lights.crt ]; then
+    cp lights.key /opt/lights-app/secrets/
+    cp lights.crt /opt/lights-app/secrets/
+fi
+
+# The audio of sequences is played with ffplay
+if ! command -v ffplay > /dev/null; then
+    echo "ffplay not found, install ffmpeg to play the audio of sequences"
+fi
 
 # Copy over the systemd service file and start it
 cp lights.service /etc/systemd/system/
@@ -0,0 +1,184 @@
+//! Short alert effects shown on top of whatever the lights are doing
+//!
+//! An alert is drawn on its own layer with the highest priority, so when it
+//! ends (or is cancelled) the layer goes away and the lights are back to
+//! exactly what was running before.
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::lights::{Blend, LedColor, Layer, LightsCommand, LightsError, LightsRemote};
+
+/// Name of the layer alerts are drawn on
+pub const ALERT_LAYER: &str = "alert";
+/// Alerts are drawn above every other layer
+const ALERT_PRIORITY: i32 = i32::MAX;
+/// Time between the frames of an alert
+const FRAME_TIME: Duration = Duration::from_millis(20);
+/// Each frame of the layer removes itself after this long, so the alert
+/// doesn't stay stuck on the lights if the task goes away
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What an alert looks like
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertEffect {
+    /// all the LEDs blink on and off
+    Flash,
+    /// all the LEDs fade in and out
+    Pulse,
+    /// a short run of LEDs moves along the strip
+    Chase,
+}
+
+/// An alert effect with its color and timing
+#[derive(Clone, Debug)]
+pub struct Alert {
+    pub effect: AlertEffect,
+    pub color: LedColor,
+    /// length of one flash, pulse or chase
+    pub period: Duration,
+    /// how long the alert is shown in total
+    pub length: Duration,
+}
+
+impl Alert {
+    /// The alert layer at time `t` into the alert, `None` once it is over
+    pub fn layer(&self, t: Duration, count: usize) -> Option<Layer> {
+        if t >= self.length || self.period.is_zero() {
+            return None;
+        }
+        // position in the current period from 0 to 1
+        let phase = (t.as_secs_f64() / self.period.as_secs_f64()).fract();
+        let black = LedColor::from([0, 0, 0]);
+        let (blend, opacity, colors) = match self.effect {
+            AlertEffect::Flash => {
+                let opacity = if phase < 0.5 { 255 } else { 0 };
+                (Blend::Replace, opacity, vec![self.color; count])
+            },
+            AlertEffect::Pulse => {
+                let opacity = (1.0 - (2.0 * PI * phase).cos()) / 2.0 * 255.0;
+                (Blend::Replace, opacity.round() as u8, vec![self.color; count])
+            },
+            AlertEffect::Chase => {
+                // black LEDs in an alpha layer leave the colors below showing
+                let width = (count / 10).max(1);
+                let head = (phase * (count + width) as f64) as usize;
+                let colors = (0..count)
+                    .map(|i| if i < head && i + width >= head { self.color } else { black })
+                    .collect();
+                (Blend::Alpha, 255, colors)
+            },
+        };
+        Some(Layer {
+            name: ALERT_LAYER.into(),
+            priority: ALERT_PRIORITY,
+            blend,
+            opacity,
+            colors,
+            timeout: Some(FRAME_TIMEOUT),
+        })
+    }
+}
+
+/// Plays one alert at a time, a new alert replaces the one showing
+pub struct Alerts {
+    remote: LightsRemote,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Alerts {
+    pub fn new(remote: LightsRemote) -> Self {
+        Alerts { remote, task: Mutex::new(None) }
+    }
+
+    /// Start showing an alert
+    pub fn play(&self, alert: Alert) {
+        debug!("Showing {:?} alert for {:?}", alert.effect, alert.length);
+        let task = tokio::spawn(play(self.remote.clone(), alert));
+        if let Some(old) = self.task.lock().unwrap().replace(task) {
+            old.abort();
+        }
+    }
+
+    /// Stop the alert that is showing, returns false if there wasn't one
+    pub async fn cancel(&self) -> bool {
+        let task = self.task.lock().unwrap().take();
+        match task {
+            Some(task) if !task.is_finished() => {
+                task.abort();
+                let _ = self.remote.send(LightsCommand::ClearLayer(ALERT_LAYER.into())).await;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// If an alert is showing
+    pub fn active(&self) -> bool {
+        self.task.lock().unwrap().as_ref().is_some_and(|task| !task.is_finished())
+    }
+}
+
+/// Send the frames of the alert to the controller, then remove the layer
+async fn play(remote: LightsRemote, alert: Alert) {
+    let start = Instant::now();
+    let mut interval = tokio::time::interval(FRAME_TIME);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let Some(layer) = alert.layer(start.elapsed(), remote.led_count()) else {
+            break;
+        };
+        match remote.send(LightsCommand::SetLayer(layer)).await {
+            Ok(()) => {},
+            Err(LightsError::Closed) => return,
+            Err(e) => {
+                error!("Unable to show alert: {e:?}");
+                break;
+            },
+        }
+    }
+    let _ = remote.send(LightsCommand::ClearLayer(ALERT_LAYER.into())).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn alert(effect: AlertEffect) -> Alert {
+        Alert {
+            effect,
+            color: [255, 0, 0].into(),
+            period: Duration::from_millis(1000),
+            length: Duration::from_millis(3000),
+        }
+    }
+
+    #[test]
+    fn effects() {
+        let flash = alert(AlertEffect::Flash);
+        assert_eq!(flash.layer(Duration::from_millis(100), 10).unwrap().opacity, 255);
+        assert_eq!(flash.layer(Duration::from_millis(1600), 10).unwrap().opacity, 0);
+        assert!(flash.layer(Duration::from_millis(3000), 10).is_none());
+
+        let pulse = alert(AlertEffect::Pulse);
+        assert_eq!(pulse.layer(Duration::ZERO, 10).unwrap().opacity, 0);
+        assert_eq!(pulse.layer(Duration::from_millis(500), 10).unwrap().opacity, 255);
+
+        let chase = alert(AlertEffect::Chase);
+        let layer = chase.layer(Duration::from_millis(500), 20).unwrap();
+        assert_eq!(layer.blend, Blend::Alpha);
+        let lit: Vec<usize> = layer.colors.iter().enumerate()
+            .filter(|(_, color)| color.r == 255)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(lit, vec![9, 10]);
+    }
+}
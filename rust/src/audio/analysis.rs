@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// Number of past spectral flux values used for the onset threshold (~1 sec)
+const FLUX_HISTORY: usize = 43;
+/// How far above the average flux a chunk has to be to count as an onset
+const ONSET_THRESHOLD: f32 = 1.5;
+/// Smallest flux that can be an onset, so silence doesn't trigger beats
+const ONSET_MIN_FLUX: f32 = 0.01;
+
+/// The result of analyzing a chunk of audio
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    /// RMS level of the chunk
+    pub level: f32,
+    /// Normalized magnitude of every FFT bin up to the Nyquist frequency
+    pub spectrum: Vec<f32>,
+    /// Whether the chunk starts a new sound (a beat)
+    pub onset: bool,
+}
+
+/// Analyzes chunks of audio for level, spectrum and onsets
+pub struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    size: usize,
+    sample_rate: u32,
+    window: Vec<f32>,
+    previous: Vec<f32>,
+    flux: VecDeque<f32>,
+    /// lowest and highest frequency considered for onsets
+    band: (f32, f32),
+}
+
+impl Analyzer {
+    /// Create an analyzer for chunks of `size` samples
+    pub fn new(sample_rate: u32, size: usize) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(size);
+        // hann window to keep the chunk edges from smearing the spectrum
+        let window = (0..size)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos())
+            .collect();
+        Analyzer {
+            fft,
+            size,
+            sample_rate,
+            window,
+            previous: vec![0.0; size / 2],
+            flux: VecDeque::with_capacity(FLUX_HISTORY),
+            band: (20.0, 20000.0),
+        }
+    }
+
+    /// Sample rate the analyzer was made for
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Only look for onsets between `low` and `high` Hz
+    pub fn set_band(&mut self, low: f32, high: f32) {
+        self.band = (low, high);
+    }
+
+    /// Analyze a chunk of samples, short chunks are padded with silence
+    pub fn process(&mut self, samples: &[f32]) -> Analysis {
+        let level = if samples.is_empty() {
+            0.0
+        } else {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+
+        let mut buf: Vec<Complex<f32>> = (0..self.size)
+            .map(|i| Complex::new(samples.get(i).copied().unwrap_or(0.0) * self.window[i], 0.0))
+            .collect();
+        self.fft.process(&mut buf);
+        let scale = 2.0 / self.size as f32;
+        let spectrum: Vec<f32> = buf[..self.size / 2].iter().map(|c| c.norm() * scale).collect();
+
+        // spectral flux: how much louder the band got since the last chunk
+        let (lo, hi) = self.bins(self.band.0, self.band.1);
+        let flux: f32 = (lo..hi)
+            .map(|i| (spectrum[i] - self.previous[i]).max(0.0))
+            .sum();
+        let average = if self.flux.is_empty() {
+            f32::INFINITY
+        } else {
+            self.flux.iter().sum::<f32>() / self.flux.len() as f32
+        };
+        let onset = flux > ONSET_MIN_FLUX && flux > average * ONSET_THRESHOLD;
+        if self.flux.len() == FLUX_HISTORY {
+            self.flux.pop_front();
+        }
+        self.flux.push_back(flux);
+        self.previous.copy_from_slice(&spectrum);
+
+        Analysis { level, spectrum, onset }
+    }
+
+    /// Split `low` to `high` Hz into `count` logarithmically spaced bands and
+    /// get the peak magnitude in each
+    pub fn bands(&self, analysis: &Analysis, low: f32, high: f32, count: usize) -> Vec<f32> {
+        let low = low.max(1.0);
+        let ratio = (high.max(low + 1.0) / low).powf(1.0 / count as f32);
+        (0..count)
+            .map(|i| {
+                let (lo, hi) = self.bins(low * ratio.powi(i as i32), low * ratio.powi(i as i32 + 1));
+                analysis.spectrum[lo..hi].iter().fold(0.0, |a: f32, b| a.max(*b))
+            })
+            .collect()
+    }
+
+    /// The (non-empty) range of FFT bins covering `low` to `high` Hz
+    fn bins(&self, low: f32, high: f32) -> (usize, usize) {
+        let bins = self.size / 2;
+        let hz_per_bin = self.sample_rate as f32 / self.size as f32;
+        let lo = ((low / hz_per_bin) as usize).min(bins - 1);
+        let hi = ((high / hz_per_bin).ceil() as usize).clamp(lo + 1, bins);
+        (lo, hi)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(freq: f32, amplitude: f32) -> Vec<f32> {
+        (0..1024)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * freq * i as f32 / 44100.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_tone() {
+        let mut analyzer = Analyzer::new(44100, 1024);
+        let analysis = analyzer.process(&sine(1000.0, 0.5));
+        assert!((analysis.level - 0.5 / 2f32.sqrt()).abs() < 0.01);
+        let tone = analyzer.bands(&analysis, 900.0, 1100.0, 1)[0];
+        let other = analyzer.bands(&analysis, 4000.0, 8000.0, 1)[0];
+        assert!(tone > 10.0 * other);
+        let bands = analyzer.bands(&analysis, 100.0, 10000.0, 7);
+        let loudest = bands.iter().enumerate().fold(0, |m, (i, b)| if *b > bands[m] { i } else { m });
+        // 1kHz is half way between 100Hz and 10kHz on a log scale
+        assert_eq!(loudest, 3);
+    }
+
+    #[test]
+    fn detects_onset_after_silence() {
+        let mut analyzer = Analyzer::new(44100, 1024);
+        for _ in 0..10 {
+            assert!(!analyzer.process(&[0.0; 1024]).onset);
+        }
+        assert!(analyzer.process(&sine(200.0, 0.8)).onset);
+        // a steady tone is not a new onset
+        assert!(!analyzer.process(&sine(200.0, 0.8)).onset);
+    }
+}
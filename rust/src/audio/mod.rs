@@ -0,0 +1,272 @@
+//! Audio input for the audio-reactive lights modes
+//!
+//! Audio is read on a blocking thread and handed to the async side as chunks
+//! of mono samples in the range -1 to 1. The source is chosen with a short
+//! spec string:
+//!
+//! - `alsa:<device>` captures from an ALSA device (through `arecord`), either
+//!   `default` or one of the capture devices listed in `/proc/asound/pcm`
+//! - `wav:<name>` plays a WAV file from the audio directory on a loop
+//! - `stdin` reads raw 16 bit little-endian mono PCM from standard input,
+//!   one thread reads it for the whole app and hands the chunks to every
+//!   source using it
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::sync::{broadcast, mpsc};
+
+mod analysis;
+pub use analysis::{Analysis, Analyzer};
+
+/// Directory (relative to the working directory) that holds the WAV files
+pub const AUDIO_DIR: &str = "audio";
+/// File listing the sound card devices
+const ALSA_PCM: &str = "/proc/asound/pcm";
+/// Sample rate used for ALSA capture and stdin
+pub const SAMPLE_RATE: u32 = 44100;
+/// Number of samples in each chunk handed to the analyzer
+pub const CHUNK_SIZE: usize = 1024;
+
+/// A chunk of mono audio samples
+#[derive(Clone)]
+pub struct Chunk {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+/// Where the audio comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioSource {
+    /// Capture from an ALSA device
+    Alsa(String),
+    /// Loop a WAV file from the audio directory
+    Wav(String),
+    /// Raw PCM on standard input
+    Stdin,
+}
+
+impl AudioSource {
+    /// Parse a source spec string. WAV files have to be directly in the
+    /// audio directory and ALSA devices have to be capture devices
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec.split_once(':') {
+            Some(("alsa", device)) if alsa_devices().iter().any(|d| d == device) => {
+                Ok(AudioSource::Alsa(device.into()))
+            },
+            Some(("alsa", device)) => Err(anyhow!("Unknown ALSA capture device {device}")),
+            Some(("wav", name)) if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') => {
+                Err(anyhow!("Invalid audio file name {name}"))
+            },
+            Some(("wav", name)) => Ok(AudioSource::Wav(name.into())),
+            None if spec == "stdin" => Ok(AudioSource::Stdin),
+            _ => Err(anyhow!("Unknown audio source {spec}")),
+        }
+    }
+
+    /// The spec string for the source
+    pub fn spec(&self) -> String {
+        match self {
+            AudioSource::Alsa(device) => format!("alsa:{device}"),
+            AudioSource::Wav(name) => format!("wav:{name}"),
+            AudioSource::Stdin => "stdin".into(),
+        }
+    }
+
+    /// Start reading the source on a blocking thread, the thread stops when
+    /// the receiver is dropped
+    pub fn start(&self) -> Result<mpsc::Receiver<Chunk>> {
+        let (sender, receiver) = mpsc::channel(4);
+        match self.clone() {
+            AudioSource::Alsa(device) => {
+                let mut child = Command::new("arecord")
+                    .args(["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-r"])
+                    .arg(SAMPLE_RATE.to_string())
+                    .args(["-D", &device])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("Unable to start arecord")?;
+                let stdout = child.stdout.take().context("arecord has no output")?;
+                std::thread::spawn(move || {
+                    if let Err(e) = read_pcm(stdout, |chunk| sender.blocking_send(chunk).is_ok()) {
+                        error!("Error capturing audio: {e:?}");
+                    }
+                    let _ = child.kill();
+                    let _ = child.wait();
+                });
+            },
+            AudioSource::Wav(name) => {
+                let path = PathBuf::from(AUDIO_DIR).join(name);
+                // decode here so a bad file is reported straight away
+                let (sample_rate, samples) = read_wav(&path)
+                    .with_context(|| format!("Unable to read {}", path.display()))?;
+                std::thread::spawn(move || loop_wav(sample_rate, &samples, &sender));
+            },
+            AudioSource::Stdin => {
+                let mut chunks = stdin_chunks();
+                std::thread::spawn(move || loop {
+                    match chunks.blocking_recv() {
+                        Ok(chunk) => if sender.blocking_send(chunk).is_err() {
+                            return;
+                        },
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("Skipped {skipped} chunks of audio from stdin");
+                        },
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    }
+                });
+            },
+        }
+        Ok(receiver)
+    }
+}
+
+/// List the spec strings of the available audio sources
+pub fn list_sources() -> Vec<String> {
+    let mut wavs: Vec<String> = std::fs::read_dir(AUDIO_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.to_lowercase().ends_with(".wav"))
+                .map(|name| AudioSource::Wav(name).spec())
+                .collect()
+        })
+        .unwrap_or_default();
+    wavs.sort();
+    let mut sources: Vec<String> = alsa_devices().into_iter().map(|d| AudioSource::Alsa(d).spec()).collect();
+    sources.push(AudioSource::Stdin.spec());
+    sources.append(&mut wavs);
+    sources
+}
+
+/// The ALSA devices that can be captured from, `default` and a `plughw`
+/// device for each capture device of the sound cards
+fn alsa_devices() -> Vec<String> {
+    let pcm = std::fs::read_to_string(ALSA_PCM).unwrap_or_default();
+    let mut devices = vec!["default".to_string()];
+    devices.extend(capture_devices(&pcm));
+    devices
+}
+
+/// Find the capture devices in the contents of `/proc/asound/pcm`, where
+/// each line looks like `00-01: id : name : playback 1 : capture 1`
+fn capture_devices(pcm: &str) -> Vec<String> {
+    pcm.lines()
+        .filter(|line| line.split(" : ").any(|part| part.trim().starts_with("capture")))
+        .filter_map(|line| {
+            let (card, device) = line.split(':').next()?.split_once('-')?;
+            let card: u32 = card.trim().parse().ok()?;
+            let device: u32 = device.trim().parse().ok()?;
+            Some(format!("plughw:{card},{device}"))
+        })
+        .collect()
+}
+
+/// The chunks read from standard input, by a thread started the first time
+/// they are asked for. Nothing more comes once standard input ends
+fn stdin_chunks() -> broadcast::Receiver<Chunk> {
+    static STDIN: OnceLock<broadcast::Sender<Chunk>> = OnceLock::new();
+    STDIN.get_or_init(|| {
+        let (sender, _) = broadcast::channel(4);
+        let chunks = sender.clone();
+        std::thread::spawn(move || {
+            // keep reading while nobody listens, so the writer isn't held up
+            if let Err(e) = read_pcm(std::io::stdin(), |chunk| {
+                let _ = chunks.send(chunk);
+                true
+            }) {
+                error!("Error reading audio from stdin: {e:?}");
+            }
+        });
+        sender
+    }).subscribe()
+}
+
+/// Read 16 bit little-endian mono PCM in chunks until the input ends or
+/// `send` returns false
+fn read_pcm<R: Read>(reader: R, mut send: impl FnMut(Chunk) -> bool) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut buf = vec![0u8; CHUNK_SIZE * 2];
+    loop {
+        reader.read_exact(&mut buf)?;
+        let samples = buf
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+            .collect();
+        if !send(Chunk { sample_rate: SAMPLE_RATE, samples }) {
+            return Ok(());
+        }
+    }
+}
+
+/// Decode a whole WAV file, mixing all channels down to mono
+fn read_wav(path: &PathBuf) -> Result<(u32, Vec<f32>)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        },
+    };
+    if samples.is_empty() {
+        bail!("Audio file {} is empty", path.display());
+    }
+    let mono = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok((spec.sample_rate, mono))
+}
+
+/// Hand out decoded samples on a loop in real time, until the receiver is
+/// dropped
+fn loop_wav(sample_rate: u32, samples: &[f32], sender: &mpsc::Sender<Chunk>) {
+    // hand out the chunks at the rate they would be captured
+    let chunk_time = Duration::from_secs_f64(CHUNK_SIZE as f64 / sample_rate as f64);
+    let mut next = Instant::now();
+    loop {
+        for chunk in samples.chunks(CHUNK_SIZE) {
+            next += chunk_time;
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            let chunk = Chunk { sample_rate, samples: chunk.to_vec() };
+            if sender.blocking_send(chunk).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_safe_sources() {
+        assert_eq!(AudioSource::parse("wav:song.wav").unwrap(), AudioSource::Wav("song.wav".into()));
+        assert_eq!(AudioSource::parse("alsa:default").unwrap(), AudioSource::Alsa("default".into()));
+        assert_eq!(AudioSource::parse("stdin").unwrap(), AudioSource::Stdin);
+        assert!(AudioSource::parse("wav:../../etc/passwd").is_err());
+        assert!(AudioSource::parse("wav:sub/song.wav").is_err());
+        assert!(AudioSource::parse("wav:.hidden.wav").is_err());
+        assert!(AudioSource::parse("wav:").is_err());
+        assert!(AudioSource::parse("alsa:/dev/something").is_err());
+    }
+
+    #[test]
+    fn finds_capture_devices() {
+        let pcm = "00-00: bcm2835 Headphones : bcm2835 Headphones : playback 8\n\
+                   01-00: USB Audio : USB Audio : playback 1 : capture 1\n";
+        assert_eq!(capture_devices(pcm), vec!["plughw:1,0".to_string()]);
+    }
+}
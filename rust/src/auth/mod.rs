@@ -0,0 +1,407 @@
+//! Optional authentication for the web-app
+//!
+//! The password hash and the API tokens are kept in `secrets/auth.json`, which
+//! is shared with the command line tools. Authentication is only enforced once
+//! a password or a token has been set up.
+use std::collections::HashMap;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use rand_core::{OsRng, RngCore};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// File with the password hash and tokens
+pub const AUTH_FILE: &str = "secrets/auth.json";
+/// How long a login session lasts
+const SESSION_LENGTH: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Wrong passwords allowed in a row before logins have to wait
+const FREE_LOGINS: u32 = 3;
+/// First wait after too many wrong passwords, doubled for each one after
+const LOGIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between logins
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(300);
+/// Most clients whose wrong passwords are remembered
+const MAX_LOGIN_CLIENTS: usize = 1024;
+
+/// What a user or token is allowed to do
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// Turn the lights on and off, and change modes and colors
+    Guest,
+    /// Everything, including scripts, recordings, tokens and shutdown
+    Admin,
+}
+
+/// A stored API token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token {
+    pub name: String,
+    pub role: Role,
+    /// sha256 of the token
+    hash: String,
+}
+
+/// Contents of the auth file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthFile {
+    password: Option<String>,
+    #[serde(default)]
+    tokens: Vec<Token>,
+}
+
+impl AuthFile {
+    /// Read the auth file, a missing file means no authentication
+    pub fn load() -> Result<Self> {
+        match std::fs::read_to_string(AUTH_FILE) {
+            Ok(text) => serde_json::from_str(&text).context("Failed to parse auth file"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AuthFile::default()),
+            Err(e) => Err(e).context("Failed to read auth file"),
+        }
+    }
+
+    /// Write the auth file, only readable by the owner
+    pub fn save(&self) -> Result<()> {
+        let path = PathBuf::from(AUTH_FILE);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .context("Failed to write auth file")?;
+        // the mode is only used for new files
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())
+            .context("Failed to write auth file")
+    }
+
+    /// Whether requests have to be authenticated
+    pub fn enabled(&self) -> bool {
+        self.password.is_some() || !self.tokens.is_empty()
+    }
+
+    /// Set the web-app password
+    pub fn set_password(&mut self, password: &str) -> Result<()> {
+        if password.is_empty() {
+            bail!("Password can not be empty");
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("Failed to hash password: {e}"))?;
+        self.password = Some(hash.to_string());
+        Ok(())
+    }
+
+    /// Check a password against the stored hash
+    pub fn check_password(&self, password: &str) -> bool {
+        let Some(hash) = &self.password else {
+            return false;
+        };
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(e) => {
+                error!("Stored password hash is invalid: {e}");
+                false
+            },
+        }
+    }
+
+    /// The stored tokens
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Create a new token, the token itself is only returned here
+    pub fn add_token(&mut self, name: &str, role: Role) -> Result<String> {
+        if name.is_empty() {
+            bail!("Token name can not be empty");
+        }
+        if self.tokens.iter().any(|t| t.name == name) {
+            bail!("A token named {name} already exists");
+        }
+        let token = random_hex(32);
+        self.tokens.push(Token { name: name.into(), role, hash: sha256_hex(&token) });
+        Ok(token)
+    }
+
+    /// Remove a token by name
+    pub fn remove_token(&mut self, name: &str) -> Result<()> {
+        let count = self.tokens.len();
+        self.tokens.retain(|t| t.name != name);
+        if self.tokens.len() == count {
+            bail!("No token named {name}");
+        }
+        Ok(())
+    }
+
+    /// The role for a bearer token
+    pub fn check_token(&self, token: &str) -> Option<Role> {
+        let hash = sha256_hex(token);
+        self.tokens.iter().find(|t| t.hash == hash).map(|t| t.role)
+    }
+}
+
+/// Why a login failed
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    WrongPassword,
+    /// there were too many wrong passwords, try again after this long
+    TooSoon(Duration),
+}
+
+/// Wrong passwords in a row from a client, which make its next login wait
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    /// logins aren't checked until then
+    until: Option<Instant>,
+}
+
+impl Failures {
+    /// How long until the next login can be checked, if it has to wait
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        self.until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.count += 1;
+        if let Some(extra) = self.count.checked_sub(FREE_LOGINS + 1) {
+            let wait = LOGIN_BACKOFF.saturating_mul(2u32.saturating_pow(extra)).min(MAX_LOGIN_BACKOFF);
+            self.until = Some(now + wait);
+        }
+    }
+}
+
+/// Wrong passwords from each client, so one client guessing passwords doesn't
+/// keep the others out
+#[derive(Default)]
+struct LoginFailures {
+    clients: HashMap<IpAddr, Failures>,
+}
+
+impl LoginFailures {
+    /// The key for a client, IPv6 clients usually have a whole /64 to pick
+    /// addresses from
+    fn key(client: IpAddr) -> IpAddr {
+        match client {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => v4.into(),
+                None => IpAddr::V6((u128::from(v6) & (!0u128 << 64)).into()),
+            },
+            v4 => v4,
+        }
+    }
+
+    /// How long until the client's next login can be checked, if it has to
+    /// wait
+    fn wait(&self, client: IpAddr, now: Instant) -> Option<Duration> {
+        self.clients.get(&Self::key(client))?.wait(now)
+    }
+
+    fn failed(&mut self, client: IpAddr, now: Instant) {
+        let key = Self::key(client);
+        if !self.clients.contains_key(&key) && self.clients.len() >= MAX_LOGIN_CLIENTS {
+            // make room, forgetting the clients that don't have to wait first
+            self.clients.retain(|_, failures| failures.wait(now).is_some());
+            if self.clients.len() >= MAX_LOGIN_CLIENTS {
+                let first = self.clients.iter().min_by_key(|(_, failures)| failures.until).map(|(ip, _)| *ip);
+                if let Some(first) = first {
+                    self.clients.remove(&first);
+                }
+            }
+        }
+        self.clients.entry(key).or_default().failed(now);
+    }
+
+    fn succeeded(&mut self, client: IpAddr) {
+        self.clients.remove(&Self::key(client));
+    }
+}
+
+/// Authentication state for the web-server
+///
+/// Reloads the auth file when it changes so the command line tools can be
+/// used while the server is running.
+pub struct Auth {
+    file: Mutex<(Option<SystemTime>, AuthFile)>,
+    sessions: Mutex<HashMap<String, (Role, Instant)>>,
+    failures: Mutex<LoginFailures>,
+}
+
+impl Auth {
+    pub fn load() -> Result<Self> {
+        Ok(Auth {
+            file: Mutex::new((modified(), AuthFile::load()?)),
+            sessions: Mutex::new(HashMap::new()),
+            failures: Mutex::new(LoginFailures::default()),
+        })
+    }
+
+    /// The current auth file contents
+    pub fn file(&self) -> AuthFile {
+        let mut file = self.file.lock().unwrap();
+        let changed = modified();
+        if changed != file.0 {
+            match AuthFile::load() {
+                Ok(new) => *file = (changed, new),
+                Err(e) => error!("Keeping old auth settings: {e:?}"),
+            }
+        }
+        file.1.clone()
+    }
+
+    /// Change and save the auth file
+    pub fn update<T>(&self, f: impl FnOnce(&mut AuthFile) -> Result<T>) -> Result<T> {
+        let mut auth = self.file();
+        let res = f(&mut auth)?;
+        auth.save()?;
+        *self.file.lock().unwrap() = (modified(), auth);
+        Ok(res)
+    }
+
+    /// Log in from `client` with the password and get a new session id.
+    /// Checking the password takes a while, so this is best run on a blocking
+    /// thread
+    pub fn login(&self, client: IpAddr, password: &str) -> Result<String, LoginError> {
+        if let Some(wait) = self.failures.lock().unwrap().wait(client, Instant::now()) {
+            return Err(LoginError::TooSoon(wait));
+        }
+        if !self.file().check_password(password) {
+            self.failures.lock().unwrap().failed(client, Instant::now());
+            return Err(LoginError::WrongPassword);
+        }
+        self.failures.lock().unwrap().succeeded(client);
+        let id = random_hex(32);
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(id.clone(), (Role::Admin, now + SESSION_LENGTH));
+        Ok(id)
+    }
+
+    /// End a session
+    pub fn logout(&self, session: &str) {
+        self.sessions.lock().unwrap().remove(session);
+    }
+
+    /// The role for a session id
+    pub fn session(&self, session: &str) -> Option<Role> {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(session) {
+            Some((role, expires)) if *expires > Instant::now() => Some(*role),
+            _ => None,
+        }
+    }
+}
+
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(AUTH_FILE).and_then(|m| m.modified()).ok()
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    hex(&buf)
+}
+
+fn sha256_hex(text: &str) -> String {
+    hex(&Sha256::digest(text.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn login_backoff() {
+        let start = Instant::now();
+        let mut failures = Failures::default();
+        for _ in 0..FREE_LOGINS {
+            failures.failed(start);
+            assert_eq!(failures.wait(start), None);
+        }
+        failures.failed(start);
+        assert_eq!(failures.wait(start), Some(LOGIN_BACKOFF));
+        failures.failed(start);
+        assert_eq!(failures.wait(start), Some(LOGIN_BACKOFF * 2));
+        assert_eq!(failures.wait(start + LOGIN_BACKOFF * 2), None);
+        for _ in 0..100 {
+            failures.failed(start);
+        }
+        assert_eq!(failures.wait(start), Some(MAX_LOGIN_BACKOFF));
+    }
+
+    #[test]
+    fn login_backoff_per_client() {
+        let now = Instant::now();
+        let mut failures = LoginFailures::default();
+        let guesser: IpAddr = "192.168.1.20".parse().unwrap();
+        let admin: IpAddr = "192.168.1.10".parse().unwrap();
+        for _ in 0..=FREE_LOGINS {
+            failures.failed(guesser, now);
+        }
+        assert!(failures.wait(guesser, now).is_some());
+        assert_eq!(failures.wait(admin, now), None);
+        // addresses in the same /64, and IPv4 as IPv6
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        for _ in 0..=FREE_LOGINS {
+            failures.failed(v6, now);
+        }
+        assert!(failures.wait("2001:db8::2".parse().unwrap(), now).is_some());
+        assert_eq!(failures.wait("2001:db8:0:1::1".parse().unwrap(), now), None);
+        assert!(failures.wait("::ffff:192.168.1.20".parse().unwrap(), now).is_some());
+        failures.succeeded(guesser);
+        assert_eq!(failures.wait(guesser, now), None);
+        // the clients remembered are limited
+        for i in 0..MAX_LOGIN_CLIENTS as u32 * 2 {
+            failures.failed(IpAddr::V4(i.into()), now);
+        }
+        assert!(failures.clients.len() <= MAX_LOGIN_CLIENTS);
+        // without losing the ones that have to wait
+        assert!(failures.wait(v6, now).is_some());
+    }
+
+    #[test]
+    fn passwords_and_tokens() {
+        let mut auth = AuthFile::default();
+        assert!(!auth.enabled());
+        auth.set_password("hunter2").unwrap();
+        assert!(auth.enabled());
+        assert!(auth.check_password("hunter2"));
+        assert!(!auth.check_password("hunter3"));
+
+        let token = auth.add_token("porch", Role::Guest).unwrap();
+        assert_eq!(auth.check_token(&token), Some(Role::Guest));
+        assert_eq!(auth.check_token("not-a-token"), None);
+        assert!(auth.add_token("porch", Role::Admin).is_err());
+        auth.remove_token("porch").unwrap();
+        assert_eq!(auth.check_token(&token), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Command line tools for managing the lights-app
+use std::io::BufRead;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::auth::{AuthFile, Role};
+use crate::mdns;
+
+/// Christmas lights controller
+///
+/// Runs the lights controller and web-server when no command is given.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Set the web-app password (read from stdin)
+    Password,
+    /// Manage the API tokens
+    Token {
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+    /// List the lights found on the local network
+    Discover {
+        /// seconds to wait for answers
+        #[arg(long, default_value = "2")]
+        wait: u64,
+        /// service to look for
+        #[arg(long, default_value = mdns::LIGHTS_SERVICE)]
+        service: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenAction {
+    /// Create a new token and print it
+    Add {
+        /// name to identify the token
+        name: String,
+        /// what the token is allowed to do
+        #[arg(long, value_enum, default_value = "guest")]
+        role: Role,
+    },
+    /// List the tokens
+    List,
+    /// Remove a token
+    Remove {
+        /// name of the token
+        name: String,
+    },
+}
+
+/// Run a command line tool
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Password => {
+            let mut auth = AuthFile::load()?;
+            eprintln!("New password:");
+            let mut password = String::new();
+            std::io::stdin().lock().read_line(&mut password).context("Failed to read password")?;
+            auth.set_password(password.trim_end_matches(['\r', '\n']))?;
+            auth.save()?;
+            eprintln!("Password changed");
+        },
+        Command::Token { action } => token(action)?,
+        Command::Discover { wait, service } => {
+            for found in mdns::discover(&service, Duration::from_secs(wait)).await? {
+                let addr = found.socket_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                let txt: Vec<String> = found.txt.iter().map(|(key, value)| format!("{key}={value}")).collect();
+                println!("{}\t{}\t{addr}\t{}", found.name, found.host, txt.join(" "));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Change the API tokens
+fn token(action: TokenAction) -> Result<()> {
+    let mut auth = AuthFile::load()?;
+    match action {
+        TokenAction::Add { name, role } => {
+            let token = auth.add_token(&name, role)?;
+            auth.save()?;
+            println!("{token}");
+        },
+        TokenAction::List => {
+            for token in auth.tokens() {
+                println!("{}\t{:?}", token.name, token.role);
+            }
+        },
+        TokenAction::Remove { name } => {
+            auth.remove_token(&name)?;
+            auth.save()?;
+        },
+    }
+    Ok(())
+}
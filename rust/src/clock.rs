@@ -0,0 +1,33 @@
+//! The animation clock the modes move their patterns by
+//!
+//! It counts seconds from when the app started, plus an offset. A follower
+//! (see `sync`) sets the offset so its clock matches the leader's, which keeps
+//! the animations on both in step.
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+/// When the app started
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// Microseconds added to the local clock
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// Seconds since the app started, without the offset
+pub fn local() -> f64 {
+    EPOCH.elapsed().as_secs_f64()
+}
+
+/// The animation time in seconds
+pub fn seconds() -> f64 {
+    local() + OFFSET.load(Ordering::Relaxed) as f64 / 1e6
+}
+
+/// Seconds added to the local clock
+pub fn offset() -> f64 {
+    OFFSET.load(Ordering::Relaxed) as f64 / 1e6
+}
+
+/// Move the animation clock to be `offset` seconds ahead of the local one
+pub fn set_offset(offset: f64) {
+    OFFSET.store((offset * 1e6).round() as i64, Ordering::Relaxed);
+}
@@ -0,0 +1,265 @@
+//! Settings for the lights-app, read from `config.json` in the working
+//! directory
+//!
+//! Every setting has a default, so the file only needs the ones that are
+//! changed, and a missing file means all the defaults.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::lights::{OutputConfig, StripConfig, StripKind};
+use crate::sync::SyncConfig;
+
+/// File with the settings
+pub const CONFIG_FILE: &str = "config.json";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    /// Serve plain HTTP only, for trusted networks
+    pub http_only: bool,
+    /// Port for plain HTTP (the redirect, or the web-app when `http_only`)
+    pub http_port: u16,
+    /// Port for HTTPS
+    pub https_port: u16,
+    pub tls: TlsConfig,
+    pub log: LogConfig,
+    pub lights: LightsConfig,
+    pub mdns: MdnsConfig,
+    /// Lead or follow other instances, left alone when missing
+    pub sync: Option<SyncConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            http_only: false,
+            http_port: 80,
+            https_port: 443,
+            tls: TlsConfig::default(),
+            log: LogConfig::default(),
+            lights: LightsConfig::default(),
+            mdns: MdnsConfig::default(),
+            sync: None,
+        }
+    }
+}
+
+/// The LED strips and how often they are updated
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LightsConfig {
+    /// number of LEDs on the left strip (GPIO 12), when `strips` is empty
+    pub left: usize,
+    /// number of LEDs on the right strip (GPIO 13), when `strips` is empty
+    pub right: usize,
+    /// the strips in the order they are wired
+    pub strips: Vec<StripConfig>,
+    /// pixel controllers on the network, shown as more strips
+    pub outputs: Vec<OutputConfig>,
+    /// names of the strips and outputs in the order their LEDs are numbered,
+    /// empty for the strips then the outputs in the order they are listed in
+    pub order: Vec<String>,
+    /// frames rendered per second
+    pub fps: u32,
+    /// JSON file with the position of every LED for the preview, a cone like
+    /// a tree when missing
+    pub map: Option<PathBuf>,
+    /// turn the lights off when nobody has changed them for this many hours
+    pub auto_off_hours: Option<f64>,
+}
+
+impl Default for LightsConfig {
+    fn default() -> Self {
+        LightsConfig {
+            left: 100,
+            right: 300,
+            strips: Vec::new(),
+            outputs: Vec::new(),
+            order: Vec::new(),
+            fps: 60,
+            map: None,
+            auto_off_hours: None,
+        }
+    }
+}
+
+impl LightsConfig {
+    /// The configured strips, or the left and right strips when there are
+    /// none (the left one is numbered from its far end)
+    pub fn strips(&self) -> Vec<StripConfig> {
+        if !self.strips.is_empty() {
+            return self.strips.clone();
+        }
+        let strip = |name: &str, pin, count, reverse| StripConfig {
+            name: name.into(),
+            pin,
+            count,
+            kind: StripKind::Ws2812,
+            reverse,
+            brightness: 255,
+        };
+        vec![strip("left", 12, self.left, true), strip("right", 13, self.right, false)]
+    }
+}
+
+/// Where the certificate lives and what a generated one is made for
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Host names and IP addresses put in a generated certificate
+    pub names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: PathBuf::from("secrets/lights.crt"),
+            key: PathBuf::from("secrets/lights.key"),
+            names: vec!["lights.local".into(), "localhost".into()],
+        }
+    }
+}
+
+/// How the app is advertised on the local network
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MdnsConfig {
+    pub enabled: bool,
+    /// Name the services are shown as, the host name when missing
+    pub name: Option<String>,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            enabled: true,
+            name: None,
+        }
+    }
+}
+
+/// Where log messages go and which ones are kept
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LogConfig {
+    /// Level for modules without their own filter
+    pub level: LevelFilter,
+    /// Levels by module path, e.g. `{"lights_app::webapp": "info"}`
+    pub modules: BTreeMap<String, LevelFilter>,
+    /// Write each message as a JSON object instead of plain text (the journal
+    /// always gets its own fields)
+    pub json: bool,
+    /// Where to write the messages, stderr is used when none of them work
+    pub sinks: Vec<LogSink>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Debug,
+            modules: BTreeMap::new(),
+            json: false,
+            sinks: vec![LogSink::Syslog],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LogSink {
+    Stderr,
+    /// The local syslog unix socket
+    Syslog,
+    /// The systemd journal's native protocol, which keeps the module as a field
+    Journald,
+    /// A file that is rotated once it gets too big
+    #[serde(rename_all = "camelCase")]
+    File {
+        path: PathBuf,
+        /// size in bytes before the file is rotated
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+        /// number of rotated files to keep
+        #[serde(default = "default_keep")]
+        keep: usize,
+    },
+}
+
+fn default_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+impl Config {
+    /// Read the config file, a missing file means the defaults
+    pub fn load() -> Result<Self> {
+        let config: Config = match std::fs::read_to_string(CONFIG_FILE) {
+            Ok(text) => serde_json::from_str(&text).context("Failed to parse config file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e).context("Failed to read config file"),
+        };
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Catch settings that parse but can't be used
+    fn check(&self) -> Result<()> {
+        if let Some(hours) = self.lights.auto_off_hours {
+            if hours <= 0.0 || Duration::try_from_secs_f64(hours * 3600.0).is_err() {
+                bail!("lights.autoOffHours has to be a positive number of hours, not {hours}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config: Config = serde_json::from_str(r#"{"httpOnly": true, "tls": {"names": ["tree.lan"]}}"#).unwrap();
+        assert!(config.http_only);
+        assert_eq!(config.http_port, 80);
+        assert_eq!(config.tls.names, vec!["tree.lan".to_string()]);
+        assert_eq!(config.tls.cert, PathBuf::from("secrets/lights.crt"));
+        assert_eq!(config.log.level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn checks_auto_off() {
+        let mut config = Config::default();
+        config.lights.auto_off_hours = Some(4.5);
+        assert!(config.check().is_ok());
+        for hours in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e300] {
+            config.lights.auto_off_hours = Some(hours);
+            assert!(config.check().is_err(), "{hours} hours");
+        }
+    }
+
+    #[test]
+    fn log_sinks() {
+        let log: LogConfig = serde_json::from_str(r#"{
+            "level": "info",
+            "modules": {"lights_app::webapp": "warn"},
+            "sinks": [{"type": "stderr"}, {"type": "file", "path": "logs/lights.log"}]
+        }"#).unwrap();
+        assert_eq!(log.level, LevelFilter::Info);
+        assert_eq!(log.modules["lights_app::webapp"], LevelFilter::Warn);
+        match &log.sinks[1] {
+            LogSink::File { max_size, keep, .. } => assert_eq!((*max_size, *keep), (1024 * 1024, 3)),
+            other => panic!("unexpected sink {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Events from the controller and the modes, for anyone who wants to follow
+//! along (see the `/events` endpoint)
+use std::sync::LazyLock;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::mode::Param;
+
+/// Events buffered for each listener before the slow ones start to miss some
+const CAPACITY: usize = 64;
+
+/// The events sent to every listener
+pub static EVENTS: LazyLock<Events> = LazyLock::new(Events::new);
+
+/// Something that happened to the lights
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    /// The controller received a command (frames from the modes aren't sent)
+    Command { command: &'static str },
+    /// A mode was started, or the running one stopped
+    ModeChanged { mode: Option<String> },
+    /// The parameters of the running mode were changed
+    ParamsUpdated { params: Vec<Param> },
+    /// The LED driver failed
+    DriverError { error: String },
+    /// A timer went off
+    ScheduleFired { schedule: &'static str },
+}
+
+/// Broadcasts events to whoever is listening
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Events { sender }
+    }
+
+    /// Send an event, it is dropped when nobody is listening
+    pub fn send(&self, event: Event) {
+        trace!("Event {event:?}");
+        let _ = self.sender.send(event);
+    }
+
+    /// Start listening for events
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn event_json() {
+        let event = Event::ModeChanged { mode: Some("solid".into()) };
+        assert_eq!(serde_json::to_value(event).unwrap(), json!({ "type": "modeChanged", "mode": "solid" }));
+        let event = Event::Command { command: "on" };
+        assert_eq!(serde_json::to_value(event).unwrap(), json!({ "type": "command", "command": "on" }));
+    }
+}
@@ -0,0 +1,361 @@
+//! Reader for the xLights FSEQ sequence files
+//!
+//! Supports version 1 files (uncompressed) and version 2 files that are
+//! uncompressed or compressed in zstd or zlib blocks, with or without sparse
+//! channel ranges. The whole file is kept in memory and one compression block
+//! is decompressed at a time.
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// Directory (relative to the working directory) that holds the sequences
+pub const SEQUENCES_DIR: &str = "sequences";
+
+/// Compression used for the frame data in a version 2 file
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Compression {
+    None,
+    Zstd,
+    Zlib,
+}
+
+/// A compressed block of consecutive frames
+#[derive(Clone, Debug)]
+struct Block {
+    first_frame: usize,
+    offset: usize,
+    length: usize,
+}
+
+/// A range of channels stored in a sparse file
+#[derive(Copy, Clone, Debug)]
+struct Range {
+    start: usize,
+    count: usize,
+}
+
+/// An FSEQ sequence loaded into memory
+pub struct Sequence {
+    data: Vec<u8>,
+    /// number of channels in the (full) channel space
+    channels: usize,
+    frames: usize,
+    step: Duration,
+    compression: Compression,
+    blocks: Vec<Block>,
+    ranges: Vec<Range>,
+    media: Option<String>,
+    /// index and decompressed contents of the last block used
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl Sequence {
+    /// Load a sequence file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read sequence {}", path.display()))?;
+        Sequence::from_bytes(data)
+    }
+
+    /// Parse a sequence from the contents of a file
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        if data.len() < 28 || !(&data[0..4] == b"PSEQ" || &data[0..4] == b"FSEQ") {
+            bail!("Not an FSEQ file");
+        }
+        let data_offset = u16_at(&data, 4) as usize;
+        let major = data[7];
+        let header_offset = u16_at(&data, 8) as usize;
+        let channels = u32_at(&data, 10) as usize;
+        let frames = u32_at(&data, 14) as usize;
+        let step = Duration::from_millis(data[18].max(1) as u64);
+        if data_offset > data.len() || header_offset > data_offset {
+            bail!("FSEQ header offsets are out of range");
+        }
+
+        let mut compression = Compression::None;
+        let mut blocks = Vec::new();
+        let mut ranges = Vec::new();
+        match major {
+            1 => {},
+            2 => {
+                if data.len() < 32 {
+                    bail!("FSEQ v2 header is truncated");
+                }
+                compression = match data[20] & 0x0f {
+                    0 => Compression::None,
+                    1 => Compression::Zstd,
+                    2 => Compression::Zlib,
+                    other => bail!("Unknown FSEQ compression type {other}"),
+                };
+                let block_count = (((data[20] & 0xf0) as usize) << 4) | data[21] as usize;
+                let range_count = data[22] as usize;
+                if 32 + block_count * 8 + range_count * 6 > data_offset {
+                    bail!("FSEQ block index runs into the channel data");
+                }
+                let mut pos = 32;
+                let mut offset = data_offset;
+                for _ in 0..block_count {
+                    let first_frame = u32_at(&data, pos) as usize;
+                    let length = u32_at(&data, pos + 4) as usize;
+                    pos += 8;
+                    // unused blocks at the end are written with a zero length
+                    if length > 0 && compression != Compression::None {
+                        blocks.push(Block { first_frame, offset, length });
+                    }
+                    offset += length;
+                }
+                for _ in 0..range_count {
+                    let start = u24_at(&data, pos) as usize;
+                    let count = u24_at(&data, pos + 3) as usize;
+                    pos += 6;
+                    ranges.push(Range { start, count });
+                }
+            },
+            other => bail!("Unsupported FSEQ version {other}"),
+        }
+
+        let media = variable_headers(&data[header_offset..data_offset])
+            .find(|(code, _)| code == b"mf")
+            .map(|(_, value)| String::from_utf8_lossy(value).trim_end_matches('\0').to_string());
+
+        Ok(Sequence {
+            data,
+            channels,
+            frames,
+            step,
+            compression,
+            blocks,
+            ranges,
+            media,
+            cache: None,
+        })
+    }
+
+    /// Number of frames in the sequence
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Time between frames
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    /// Length of the whole sequence
+    pub fn duration(&self) -> Duration {
+        self.step * self.frames as u32
+    }
+
+    /// Name of the media (audio) file the sequence was made for
+    pub fn media_file(&self) -> Option<&str> {
+        self.media.as_deref()
+    }
+
+    /// Number of channels stored for every frame
+    fn frame_size(&self) -> usize {
+        if self.ranges.is_empty() {
+            self.channels
+        } else {
+            self.ranges.iter().map(|r| r.count).sum()
+        }
+    }
+
+    /// Copy the channels `start..start + out.len()` of a frame into `out`,
+    /// channels that are not in the sequence are set to zero
+    pub fn read_channels(&mut self, frame: usize, start: usize, out: &mut [u8]) -> Result<()> {
+        if frame >= self.frames {
+            bail!("Frame {frame} is past the end of the sequence");
+        }
+        out.fill(0);
+        let ranges = if self.ranges.is_empty() {
+            vec![Range { start: 0, count: self.channels }]
+        } else {
+            self.ranges.clone()
+        };
+        let stored = self.frame_data(frame)?;
+        let mut pos = 0;
+        for range in ranges {
+            // overlap of this range with the requested channels
+            let lo = range.start.max(start);
+            let hi = (range.start + range.count).min(start + out.len());
+            if lo < hi {
+                let src = pos + lo - range.start;
+                let len = (hi - lo).min(stored.len().saturating_sub(src));
+                out[lo - start..lo - start + len].copy_from_slice(&stored[src..src + len]);
+            }
+            pos += range.count;
+        }
+        Ok(())
+    }
+
+    /// The stored channel data for a single frame
+    fn frame_data(&mut self, frame: usize) -> Result<&[u8]> {
+        let size = self.frame_size();
+        if self.compression == Compression::None {
+            let offset = u16_at(&self.data, 4) as usize + frame * size;
+            return self.data.get(offset..offset + size).context("FSEQ frame data is truncated");
+        }
+        let index = self.blocks
+            .iter()
+            .rposition(|block| block.first_frame <= frame)
+            .context("No FSEQ block contains the frame")?;
+        if self.cache.as_ref().map(|(i, _)| *i) != Some(index) {
+            let block = &self.blocks[index];
+            let raw = self.data
+                .get(block.offset..block.offset + block.length)
+                .context("FSEQ compressed block is truncated")?;
+            let decoded = match self.compression {
+                Compression::Zstd => zstd::decode_all(raw).context("Failed to decompress zstd block")?,
+                Compression::Zlib => {
+                    let mut buf = Vec::new();
+                    flate2::read::ZlibDecoder::new(raw)
+                        .read_to_end(&mut buf)
+                        .context("Failed to decompress zlib block")?;
+                    buf
+                },
+                Compression::None => unreachable!(),
+            };
+            self.cache = Some((index, decoded));
+        }
+        let first = self.blocks[index].first_frame;
+        let (_, decoded) = self.cache.as_ref().unwrap();
+        let offset = (frame - first) * size;
+        decoded.get(offset..offset + size).context("FSEQ block is missing frame data")
+    }
+}
+
+/// List the names of the sequence files in the sequences directory
+pub fn list_sequences() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(SEQUENCES_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.to_lowercase().ends_with(".fseq"))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Iterate over the (code, value) pairs of the variable headers
+fn variable_headers(mut buf: &[u8]) -> impl Iterator<Item = ([u8; 2], &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let length = u16_at(buf, 0) as usize;
+        if length < 4 || length > buf.len() {
+            return None;
+        }
+        let code = [buf[2], buf[3]];
+        let value = &buf[4..length];
+        buf = &buf[length..];
+        Some((code, value))
+    })
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u24_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], 0])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    /// Variable header with the media file name
+    fn media_header() -> Vec<u8> {
+        let name = b"song.mp3\0";
+        let mut buf = ((name.len() + 4) as u16).to_le_bytes().to_vec();
+        buf.extend_from_slice(b"mf");
+        buf.extend_from_slice(name);
+        buf
+    }
+
+    /// 4 frames of 6 channels where every channel is `frame * 10 + channel`
+    fn frames() -> Vec<u8> {
+        (0..4u8).flat_map(|f| (0..6u8).map(move |c| f * 10 + c)).collect()
+    }
+
+    fn v1() -> Vec<u8> {
+        let header = media_header();
+        let offset = 28 + header.len();
+        let mut buf = b"PSEQ".to_vec();
+        buf.extend_from_slice(&(offset as u16).to_le_bytes());
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&28u16.to_le_bytes());
+        buf.extend_from_slice(&6u32.to_le_bytes());
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&[50, 0]);
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(&frames());
+        buf
+    }
+
+    /// v2 file with two zlib blocks and a sparse range covering channels 2..6
+    fn v2_zlib_sparse() -> Vec<u8> {
+        let stored: Vec<u8> = frames().chunks(6).flat_map(|f| f[2..6].to_vec()).collect();
+        let blocks: Vec<Vec<u8>> = stored.chunks(8).map(|chunk| {
+            let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            enc.write_all(chunk).unwrap();
+            enc.finish().unwrap()
+        }).collect();
+        let header = media_header();
+        let header_offset = 32 + 2 * 8 + 6;
+        let offset = header_offset + header.len();
+        let mut buf = b"PSEQ".to_vec();
+        buf.extend_from_slice(&(offset as u16).to_le_bytes());
+        buf.extend_from_slice(&[0, 2]);
+        buf.extend_from_slice(&(header_offset as u16).to_le_bytes());
+        buf.extend_from_slice(&6u32.to_le_bytes());
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&[25, 0, 2, 2, 1, 0]);
+        buf.extend_from_slice(&[0; 8]);
+        for (i, block) in blocks.iter().enumerate() {
+            buf.extend_from_slice(&(i as u32 * 2).to_le_bytes());
+            buf.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        }
+        buf.extend_from_slice(&[2, 0, 0, 4, 0, 0]);
+        buf.extend_from_slice(&header);
+        for block in blocks {
+            buf.extend_from_slice(&block);
+        }
+        buf
+    }
+
+    #[test]
+    fn reads_v1() {
+        let mut seq = Sequence::from_bytes(v1()).unwrap();
+        assert_eq!(seq.frame_count(), 4);
+        assert_eq!(seq.duration(), Duration::from_millis(200));
+        assert_eq!(seq.media_file(), Some("song.mp3"));
+        let mut out = [0u8; 3];
+        seq.read_channels(2, 3, &mut out).unwrap();
+        assert_eq!(out, [23, 24, 25]);
+    }
+
+    #[test]
+    fn reads_v2_zlib_sparse() {
+        let mut seq = Sequence::from_bytes(v2_zlib_sparse()).unwrap();
+        assert_eq!(seq.step(), Duration::from_millis(25));
+        let mut out = [0u8; 6];
+        seq.read_channels(3, 0, &mut out).unwrap();
+        assert_eq!(out, [0, 0, 32, 33, 34, 35]);
+        seq.read_channels(0, 0, &mut out).unwrap();
+        assert_eq!(out, [0, 0, 2, 3, 4, 5]);
+        assert!(seq.read_channels(4, 0, &mut out).is_err());
+    }
+}
@@ -1,7 +1,10 @@
+use std::borrow::Cow;
+
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
 use serde::{Serialize, Deserialize, Deserializer};
 
 /// LED light color
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct LedColor {
     pub r: u8,
     pub g: u8,
@@ -49,3 +52,17 @@ impl<'de> Deserialize<'de> for LedColor {
         Ok(LedColor { r, g, b })
     }
 }
+
+impl JsonSchema for LedColor {
+    fn schema_name() -> Cow<'static, str> {
+        "LedColor".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "pattern": "^#[0-9a-fA-F]{6}$",
+            "examples": ["#ff8800"],
+        })
+    }
+}
@@ -1,10 +1,22 @@
-use std::iter::zip;
-use anyhow::Result;
-use tokio::sync::mpsc;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use tokio::sync::{mpsc, oneshot, watch};
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::Serialize;
 
-use super::{LedColor, LedDriver, DriverConfig};
+use crate::events::{Event, EVENTS};
+use crate::palette::{self, DEFAULT_PALETTE};
+use crate::recording::FrameWriter;
+use super::frames::{FrameStats, FrameTimer};
+use super::layers::{Layer, LayerInfo, Layers};
+use super::{LedColor, LedDriver, DriverConfig, Health, LightsError};
 
 /// Commands to send for the lights
 #[derive(Clone)]
@@ -23,112 +35,694 @@ pub enum LightsCommand {
     Set(Vec<LedColor>),
     /// Change the configuration for the driver
     ChangeConfig(DriverConfig),
+    /// Start recording the frames that are shown to a file
+    StartRecording(PathBuf),
+    /// Stop recording frames
+    StopRecording,
+    /// Get what the lights are showing, see `LightsRemote::state`
+    Get,
+    /// Fade the overall brightness to a new level
+    Brightness { level: u8, fade: Duration },
+    /// Add or replace a layer drawn on top of the colors that are set
+    SetLayer(Layer),
+    /// Remove a layer by name
+    ClearLayer(String),
+}
+
+impl LightsCommand {
+    /// Short name of the command, for events
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightsCommand::Stop => "stop",
+            LightsCommand::Off => "off",
+            LightsCommand::On => "on",
+            LightsCommand::Fill(_) => "fill",
+            LightsCommand::SetSingle(..) => "setSingle",
+            LightsCommand::Set(_) => "set",
+            LightsCommand::ChangeConfig(_) => "changeConfig",
+            LightsCommand::StartRecording(_) => "startRecording",
+            LightsCommand::StopRecording => "stopRecording",
+            LightsCommand::Get => "get",
+            LightsCommand::Brightness { .. } => "brightness",
+            LightsCommand::SetLayer(_) => "setLayer",
+            LightsCommand::ClearLayer(_) => "clearLayer",
+        }
+    }
+}
+
+/// What the lights controller is showing
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LightsState {
+    pub on: bool,
+    pub config: DriverConfig,
+    /// whether the frames are being recorded
+    pub recording: bool,
+    /// the color of every LED with the layers on top (as it is shown when
+    /// the lights are on)
+    pub leds: Vec<LedColor>,
+    pub layers: Vec<LayerInfo>,
+    pub frames: FrameStats,
+}
+
+/// Current drawn by one color of an LED at full brightness
+const MILLIAMPS_PER_COLOR: f64 = 20.0;
+/// Current drawn by an LED that is off
+const MILLIAMPS_IDLE: f64 = 1.0;
+/// Supply voltage of the strips
+const VOLTS: f64 = 5.0;
+
+impl LightsState {
+    /// Rough estimate of the power drawn by the LEDs, in watts
+    pub fn power_watts(&self) -> f64 {
+        let idle = self.leds.len() as f64 * MILLIAMPS_IDLE;
+        if !self.on {
+            return idle * VOLTS / 1000.0;
+        }
+        let levels: f64 = self.leds.iter()
+            .map(|c| (c.r as u32 + c.g as u32 + c.b as u32) as f64 / 255.0)
+            .sum();
+        let scale = self.config.brightness as f64 / 255.0;
+        (idle + levels * scale * MILLIAMPS_PER_COLOR) * VOLTS / 1000.0
+    }
+}
+
+/// The controller's answer to a command
+enum LightsReply {
+    Done,
+    State(LightsState),
+}
+
+/// A command along with the channel to answer it on
+pub struct LightsRequest {
+    command: LightsCommand,
+    reply: oneshot::Sender<Result<LightsReply, LightsError>>,
+}
+
+/// Answers to commands that changed the lights, held until the frame with
+/// the change has rendered so a driver failure reaches whoever sent them
+#[derive(Default)]
+struct Waiting {
+    replies: Vec<(oneshot::Sender<Result<LightsReply, LightsError>>, LightsReply)>,
+}
+
+impl Waiting {
+    fn hold(&mut self, reply: oneshot::Sender<Result<LightsReply, LightsError>>, answer: LightsReply) {
+        self.replies.push((reply, answer));
+    }
+
+    /// Answer everyone with how the frame rendered
+    fn rendered(&mut self, res: &Result<()>) {
+        for (reply, answer) in self.replies.drain(..) {
+            // the sender may have stopped waiting, which is fine
+            let _ = reply.send(match res {
+                Ok(()) => Ok(answer),
+                Err(e) => Err(LightsError::Driver(format!("{e:#}"))),
+            });
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct LightsRemote {
-    sender: mpsc::Sender<LightsCommand>,
+    sender: mpsc::Sender<LightsRequest>,
+    /// the colors shown on every frame
+    frames: watch::Receiver<Vec<LedColor>>,
+    /// number of LEDs, shared with the controller
+    count: Arc<AtomicUsize>,
+    health: Arc<Health>,
+}
+
+impl LightsRemote {
+    pub fn new(sender: mpsc::Sender<LightsRequest>, frames: watch::Receiver<Vec<LedColor>>, count: Arc<AtomicUsize>, health: Arc<Health>) -> Self {
+        LightsRemote{ sender, frames, count, health }
+    }
+
+    /// Number of LEDs the controller is driving
+    pub fn led_count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The colors the LEDs show, updated whenever a frame is rendered (all
+    /// black while the lights are off)
+    pub fn frames(&self) -> watch::Receiver<Vec<LedColor>> {
+        self.frames.clone()
+    }
+
+    /// Whether the controller is running and rendering
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Whether the controller answers a request in time, a stalled loop
+    /// still counts as running
+    pub async fn responding(&self) -> bool {
+        matches!(tokio::time::timeout(STALL_TIMEOUT, self.state()).await, Ok(Ok(_)))
+    }
+
+    /// Send a command and wait for the controller to carry it out, commands
+    /// that change the lights are answered once the frame with the change has
+    /// rendered (or failed to with `LightsError::Driver`)
+    pub async fn send(&self, command: LightsCommand) -> Result<(), LightsError> {
+        self.request(command).await.map(|_| ())
+    }
+
+    /// Number of commands waiting for the controller
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Get what the lights are showing
+    pub async fn state(&self) -> Result<LightsState, LightsError> {
+        match self.request(LightsCommand::Get).await? {
+            LightsReply::State(state) => Ok(state),
+            LightsReply::Done => Err(LightsError::Closed),
+        }
+    }
+
+    async fn request(&self, command: LightsCommand) -> Result<LightsReply, LightsError> {
+        let (reply, result) = oneshot::channel();
+        self.sender.send(LightsRequest { command, reply }).await
+            .map_err(|_| LightsError::Closed)?;
+        result.await.unwrap_or(Err(LightsError::Closed))
+    }
 }
 
+#[cfg(test)]
 impl LightsRemote {
-    pub fn new(sender: mpsc::Sender<LightsCommand>) -> Self {
-        LightsRemote{ sender }
+    /// A remote to a stand-in for the controller, for testing what sends it
+    /// commands. The lights are on and only the brightness changes, and every
+    /// command is passed on to the receiver
+    pub fn stand_in(count: usize) -> (Self, mpsc::UnboundedReceiver<LightsCommand>) {
+        let (sender, mut requests) = mpsc::channel::<LightsRequest>(16);
+        let (commands, received) = mpsc::unbounded_channel();
+        let (_, frames) = watch::channel(vec![[0, 0, 0].into(); count]);
+        tokio::spawn(async move {
+            let mut config = DriverConfig { strips: vec![], order: vec![], outputs: vec![], brightness: 255, fps: 60 };
+            while let Some(request) = requests.recv().await {
+                let answer = match &request.command {
+                    LightsCommand::Get => LightsReply::State(LightsState {
+                        on: true,
+                        config: config.clone(),
+                        recording: false,
+                        leds: vec![[0, 0, 0].into(); count],
+                        layers: vec![],
+                        frames: FrameStats::default(),
+                    }),
+                    LightsCommand::Brightness { level, .. } => {
+                        config.brightness = *level;
+                        LightsReply::Done
+                    },
+                    _ => LightsReply::Done,
+                };
+                let _ = commands.send(request.command);
+                let _ = request.reply.send(Ok(answer));
+            }
+        });
+        (LightsRemote::new(sender, frames, Arc::new(AtomicUsize::new(count)), Arc::default()), received)
     }
+}
+
+/// A recording in progress
+struct Recorder {
+    writer: FrameWriter<BufWriter<File>>,
+    start: Instant,
+}
 
-    pub async fn send(&self, cmd: LightsCommand) -> Result<()> {
-        Ok(self.sender.send(cmd).await?)
+impl Recorder {
+    fn new(path: &PathBuf, count: usize) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create recording {}", path.display()))?;
+        let writer = FrameWriter::new(BufWriter::new(file), count)?;
+        Ok(Recorder { writer, start: Instant::now() })
+    }
+}
+
+/// Longest the controller may take to answer before it counts as stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Driver errors in a row before the driver is set up again
+const MAX_DRIVER_ERRORS: usize = 5;
+/// Time to wait before restarting a failed controller
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// A brightness change in progress
+struct Fade {
+    from: u8,
+    to: u8,
+    start: Instant,
+    length: Duration,
+}
+
+impl Fade {
+    /// The brightness at `now`, and whether the fade is finished
+    fn level(&self, now: Instant) -> (u8, bool) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.length {
+            return (self.to, true);
+        }
+        let t = elapsed.as_secs_f32() / self.length.as_secs_f32();
+        let level = self.from as f32 + (self.to as f32 - self.from as f32) * t;
+        (level.round() as u8, false)
     }
 }
 
 pub struct LightsController {
     config: DriverConfig,
-    receiver: mpsc::Receiver<LightsCommand>,
+    receiver: mpsc::Receiver<LightsRequest>,
+    /// the colors that were set, below the layers
     state: Vec<LedColor>,
+    layers: Layers,
+    /// the colors last sent to the driver
+    shown: Vec<LedColor>,
+    /// the frames for anyone watching the lights from afar
+    preview: watch::Sender<Vec<LedColor>>,
+    on: bool,
+    recorder: Option<Recorder>,
+    fade: Option<Fade>,
+    /// the driver has changes that haven't been rendered yet
+    dirty: bool,
+    frames: FrameTimer,
+    health: Arc<Health>,
+    /// number of LEDs, shared with the remotes
+    count: Arc<AtomicUsize>,
 }
 
 impl LightsController {
-    pub fn new(config: DriverConfig, receiver: mpsc::Receiver<LightsCommand>) -> Self {
-        let mut default_colors: Vec<LedColor> = Vec::with_capacity(config.left + config.right);
-        let red: LedColor = [128, 0, 0].into();
-        let green: LedColor = [0, 128, 0].into();
-        let white: LedColor = [96, 96, 64].into();
-        for i in 0..(config.left + config.right) {
-            if i%3 == 0 {
-                default_colors.push(red);
-            } else if i%3 == 1 {
-                default_colors.push(green);
-            } else if i%3 == 2 {
-                default_colors.push(white);
+    pub fn new(
+        config: DriverConfig,
+        receiver: mpsc::Receiver<LightsRequest>,
+        preview: watch::Sender<Vec<LedColor>>,
+        health: Arc<Health>,
+        count: Arc<AtomicUsize>,
+    ) -> Self {
+        // the stop colors of the default palette, one after the other
+        let default_colors = palette::get(DEFAULT_PALETTE)
+            .map(|palette| palette.pattern(config.led_count(), palette.stops.len()))
+            .unwrap_or_else(|| vec![[128, 0, 0].into(); config.led_count()]);
+        LightsController {
+            frames: FrameTimer::new(config.fps),
+            config,
+            receiver,
+            shown: default_colors.clone(),
+            preview,
+            state: default_colors,
+            layers: Layers::default(),
+            on: true,
+            recorder: None,
+            fade: None,
+            dirty: false,
+            health,
+            count,
+        }
+    }
+
+    /// Run the controller until it is stopped, setting up the driver again
+    /// whenever it fails
+    pub async fn supervise(&mut self) {
+        loop {
+            match self.start().await {
+                Ok(()) => return,
+                Err(e) => {
+                    error!("Error with lights controller, restarting in {RESTART_DELAY:?}: {e:?}");
+                    EVENTS.send(Event::DriverError { error: format!("{e:#}") });
+                    self.health.restarted();
+                    tokio::time::sleep(RESTART_DELAY).await;
+                },
             }
         }
-        // for i in 0..(config.left + config.right) {
-        //     if i < config.left {
-        //         default_colors.push(red);
-        //     } else {
-        //         default_colors.push(green);
-        //     }
-        // }
-        LightsController { config, receiver, state: default_colors }
     }
 
     /// Consume the controller and start a loop to control the lights based on MPSC messages
+    ///
+    /// Errors from carrying out a command are sent back to whoever sent it,
+    /// the loop only ends when the driver can't be set up or keeps failing.
     pub async fn start(&mut self) -> Result<()> {
+        let res = self.run().await;
+        self.health.set_running(false);
+        self.health.set_driver_ok(false);
+        res
+    }
+
+    async fn run(&mut self) -> Result<()> {
         debug!("Starting lights controller");
-        let mut driver = LedDriver::new(self.config)?;
-        for (state_led, led) in zip(self.state.iter(), driver.iter()) {
-            *led = (*state_led).into()
-        }
-        driver.controller.render()?;
-        while let Some(cmd) = self.receiver.recv().await {
-            match cmd {
-                LightsCommand::Off => {
-                    trace!("Turning lights off");
-                    driver.clear()?;
-                },
-                LightsCommand::On => {
-                    trace!("Turingin lights on");
-                    for (state_led, led) in zip(self.state.iter(), driver.iter()) {
-                        *led = (*state_led).into()
+        let mut driver = LedDriver::new(&self.config)?;
+        self.health.set_running(true);
+        self.show(&mut driver);
+        match driver.render() {
+            Ok(()) => self.health.set_driver_ok(true),
+            Err(e) => error!("Failed to show the initial lights: {e:?}"),
+        }
+        let mut driver_errors = 0;
+        let mut frame_timer = render_timer(self.frames.frame_time());
+        let mut waiting = Waiting::default();
+        loop {
+            tokio::select! {
+                request = self.receiver.recv() => {
+                    let Some(LightsRequest { command, reply }) = request else {
+                        break
+                    };
+                    let stop = matches!(command, LightsCommand::Stop);
+                    // frames come in too fast to be worth reporting
+                    if !matches!(command, LightsCommand::Set(_) | LightsCommand::SetLayer(_) | LightsCommand::Get) {
+                        EVENTS.send(Event::Command { command: command.name() });
                     }
-                    driver.controller.render()?;
-                },
-                LightsCommand::Fill(color) => {
-                    trace!("Setting all lights to color: (r:{}, g:{}, b:{})", color.r, color.g, color.b);
-                    for state_led in self.state.iter_mut() {
-                        *state_led = color;
+                    let res = self.handle(&mut driver, command);
+                    match res {
+                        // changes are answered once they have been rendered
+                        Ok(answer) if self.dirty && !stop => waiting.hold(reply, answer),
+                        res => {
+                            if let Err(e) = &res {
+                                warn!("Lights command failed: {e}");
+                            }
+                            // the sender may have stopped waiting, which is fine
+                            let _ = reply.send(res);
+                        },
                     }
-                    driver.fill(color)?;
-                },
-                LightsCommand::SetSingle(index, color ) => {
-                    trace!("Setting light number {} to color: (r:{}, g:{}, b:{})", index, color.r, color.g, color.b);
-                    self.state[index] = color;
-                    driver[index] = color.into();
-                    driver.controller.render()?;
-                },
-                LightsCommand::Set(colors) => {
-                    trace!("Setting lights to received colors");
-                    for (state_led, (color, led)) in zip(self.state.iter_mut(), zip(colors, driver.iter())) {
-                        *state_led = color;
-                        *led = color.into();
+                    if stop {
+                        break
+                    }
+                    if frame_timer.period() != self.frames.frame_time() {
+                        frame_timer = render_timer(self.frames.frame_time());
                     }
-                    driver.controller.render()?;
                 },
-                LightsCommand::ChangeConfig(config) => {
-                    trace!("Making new config");
-                    driver.clear()?;
-                    driver = LedDriver::new(config)?;
-                    for (state_led, led) in zip(self.state.iter(), driver.iter()) {
-                        *led = (*state_led).into()
+                // updates are only rendered once per frame
+                _ = frame_timer.tick(), if self.dirty || self.fade.is_some() || self.layers.has_timeouts() || driver.pending() => {
+                    if self.layers.expire(Instant::now()) {
+                        self.dirty = true;
+                    }
+                    // a network output may still be waiting for its rate limit
+                    if !self.dirty && self.fade.is_none() && !driver.pending() {
+                        continue
+                    }
+                    self.fade_step(&mut driver);
+                    if self.dirty {
+                        self.show(&mut driver);
+                    }
+                    let start = Instant::now();
+                    let res = driver.render();
+                    waiting.rendered(&res);
+                    match res {
+                        Ok(()) => {
+                            self.health.set_driver_ok(true);
+                            driver_errors = 0;
+                        },
+                        Err(e) => {
+                            warn!("Failed to render frame: {e:?}");
+                            EVENTS.send(Event::DriverError { error: format!("{e:#}") });
+                            self.health.set_driver_ok(false);
+                            driver_errors += 1;
+                        },
+                    }
+                    self.frames.rendered(start.elapsed(), Instant::now());
+                    self.dirty = false;
+                    self.record(self.on);
+                    if driver_errors >= MAX_DRIVER_ERRORS {
+                        bail!("LED driver failed {driver_errors} times in a row");
                     }
-                    driver.controller.render()?;
-                }
-                LightsCommand::Stop => {
-                    debug!("Stopping lights controller");
-                    driver.clear()?;
-                    break
                 },
             }
         }
         Ok(())
     }
+
+    /// Move the brightness along the fade in progress
+    fn fade_step(&mut self, driver: &mut LedDriver) {
+        let Some(fade) = &self.fade else {
+            return;
+        };
+        let (level, done) = fade.level(Instant::now());
+        if done {
+            self.fade = None;
+        }
+        if level != self.config.brightness {
+            self.config.brightness = level;
+            driver.set_brightness(level);
+        }
+    }
+
+    /// Compose the layers and put the result in the driver
+    fn show(&mut self, driver: &mut LedDriver) {
+        self.shown = self.layers.compose(&self.state);
+        if self.on {
+            driver.set_all(&self.shown);
+        } else {
+            driver.blank();
+        }
+        self.publish_preview();
+    }
+
+    /// Publish the shown colors to the preview, without allocating or waking
+    /// up the watchers when nothing changed
+    fn publish_preview(&mut self) {
+        let (shown, on) = (&self.shown, self.on);
+        self.preview.send_if_modified(|preview| copy_preview(preview, shown, on));
+    }
+
+    /// Mark the colors as changed, to be shown on the next frame
+    fn changed(&mut self) {
+        self.dirty = true;
+        self.frames.update();
+    }
+
+    /// Carry out a single command
+    fn handle(&mut self, driver: &mut LedDriver, cmd: LightsCommand) -> Result<LightsReply, LightsError> {
+        check(&cmd, self.state.len())?;
+        // everything but turning the lights off (or asking) shows the colors
+        self.on = match cmd {
+            LightsCommand::Off | LightsCommand::Stop => false,
+            LightsCommand::Get
+            | LightsCommand::StartRecording(_)
+            | LightsCommand::StopRecording
+            | LightsCommand::Brightness { .. }
+            | LightsCommand::SetLayer(_)
+            | LightsCommand::ClearLayer(_) => self.on,
+            _ => true,
+        };
+        match cmd {
+            LightsCommand::Off => {
+                trace!("Turning lights off");
+                self.changed();
+            },
+            LightsCommand::On => {
+                trace!("Turingin lights on");
+                self.changed();
+            },
+            LightsCommand::Fill(color) => {
+                trace!("Setting all lights to color: (r:{}, g:{}, b:{})", color.r, color.g, color.b);
+                for state_led in self.state.iter_mut() {
+                    *state_led = color;
+                }
+                self.changed();
+            },
+            LightsCommand::SetSingle(index, color ) => {
+                trace!("Setting light number {} to color: (r:{}, g:{}, b:{})", index, color.r, color.g, color.b);
+                self.state[index] = color;
+                self.changed();
+            },
+            LightsCommand::Set(colors) => {
+                trace!("Setting lights to received colors");
+                self.state = colors;
+                self.changed();
+            },
+            LightsCommand::ChangeConfig(config) => {
+                trace!("Making new config");
+                driver.clear()?;
+                *driver = LedDriver::new(&config)?;
+                let count = config.led_count();
+                if count != self.state.len() {
+                    debug!("Changing from {} to {count} LEDs", self.state.len());
+                    // a recording can't change its LED count
+                    self.stop_recording();
+                    self.state.resize(count, [0, 0, 0].into());
+                    self.shown.resize(count, [0, 0, 0].into());
+                    self.layers.resize(count);
+                    self.count.store(count, Ordering::Relaxed);
+                }
+                self.fade = None;
+                self.frames = FrameTimer::new(config.fps);
+                self.config = config;
+                self.changed();
+            }
+            LightsCommand::StartRecording(path) => {
+                debug!("Recording frames to {}", path.display());
+                self.stop_recording();
+                let recorder = Recorder::new(&path, self.state.len())
+                    .map_err(|e| LightsError::Recording(format!("{e:#}")))?;
+                self.recorder = Some(recorder);
+                self.record(self.on);
+            },
+            LightsCommand::StopRecording => {
+                debug!("Stopping frame recording");
+                self.stop_recording();
+            },
+            LightsCommand::Stop => {
+                debug!("Stopping lights controller");
+                self.record(false);
+                self.stop_recording();
+                driver.clear()?;
+            },
+            LightsCommand::Brightness { level, fade } => {
+                debug!("Fading brightness to {level} over {fade:?}");
+                self.fade = Some(Fade {
+                    from: self.config.brightness,
+                    to: level,
+                    start: Instant::now(),
+                    length: fade,
+                });
+            },
+            LightsCommand::SetLayer(layer) => {
+                trace!("Setting layer {}", layer.name);
+                self.layers.set(layer, Instant::now());
+                self.changed();
+            },
+            LightsCommand::ClearLayer(name) => {
+                trace!("Clearing layer {name}");
+                if !self.layers.remove(&name) {
+                    return Err(LightsError::NoLayer(name));
+                }
+                self.changed();
+            },
+            LightsCommand::Get => {
+                return Ok(LightsReply::State(LightsState {
+                    on: self.on,
+                    config: self.config.clone(),
+                    recording: self.recorder.is_some(),
+                    leds: self.layers.compose(&self.state),
+                    layers: self.layers.info(Instant::now()),
+                    frames: self.frames.stats(Instant::now()),
+                }));
+            },
+        }
+        Ok(LightsReply::Done)
+    }
+
+    /// Add the frame that was just rendered to the recording (if there is one)
+    fn record(&mut self, on: bool) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            let timestamp = recorder.start.elapsed();
+            let res = if on {
+                recorder.writer.write(timestamp, &self.shown)
+            } else {
+                recorder.writer.write_off(timestamp)
+            };
+            if let Err(e) = res {
+                error!("Failed to record frame, stopping recording: {e:?}");
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Finish the recording (if there is one)
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.writer.finish() {
+                error!("Failed to finish recording: {e:?}");
+            }
+        }
+    }
+}
+
+/// Timer for rendering frames, frames that are missed are skipped
+fn render_timer(frame_time: Duration) -> tokio::time::Interval {
+    let mut timer = tokio::time::interval(frame_time);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    timer
+}
+
+/// Check that a command fits the number of LEDs before carrying it out
+fn check(cmd: &LightsCommand, count: usize) -> Result<(), LightsError> {
+    match cmd {
+        LightsCommand::SetSingle(index, _) if *index >= count =>
+            Err(LightsError::IndexOutOfRange { index: *index, count }),
+        LightsCommand::Set(colors) if colors.len() != count =>
+            Err(LightsError::WrongLength { expected: count, got: colors.len() }),
+        LightsCommand::SetLayer(layer) if layer.colors.len() != count =>
+            Err(LightsError::WrongLength { expected: count, got: layer.colors.len() }),
+        _ => Ok(()),
+    }
+}
+
+/// Copy the colors that are shown (all black while off) into the preview
+/// buffer, returning whether it changed
+fn copy_preview(preview: &mut Vec<LedColor>, shown: &[LedColor], on: bool) -> bool {
+    let black: LedColor = [0, 0, 0].into();
+    let same = preview.len() == shown.len() && if on {
+        preview.as_slice() == shown
+    } else {
+        preview.iter().all(|c| *c == black)
+    };
+    if same {
+        return false;
+    }
+    preview.clear();
+    if on {
+        preview.extend_from_slice(shown);
+    } else {
+        preview.resize(shown.len(), black);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_indices_and_lengths() {
+        let red: LedColor = [255, 0, 0].into();
+        assert_eq!(check(&LightsCommand::SetSingle(9, red), 10), Ok(()));
+        assert_eq!(
+            check(&LightsCommand::SetSingle(10, red), 10),
+            Err(LightsError::IndexOutOfRange { index: 10, count: 10 }),
+        );
+        assert_eq!(check(&LightsCommand::Set(vec![red; 10]), 10), Ok(()));
+        assert_eq!(
+            check(&LightsCommand::Set(vec![red; 4]), 10),
+            Err(LightsError::WrongLength { expected: 10, got: 4 }),
+        );
+    }
+
+    #[test]
+    fn answers_after_rendering() {
+        let mut waiting = Waiting::default();
+        let (first, mut first_answer) = oneshot::channel();
+        let (second, mut second_answer) = oneshot::channel();
+        waiting.hold(first, LightsReply::Done);
+        assert!(first_answer.try_recv().is_err());
+        waiting.rendered(&Ok(()));
+        assert!(matches!(first_answer.try_recv(), Ok(Ok(LightsReply::Done))));
+
+        waiting.hold(second, LightsReply::Done);
+        waiting.rendered(&Err(anyhow::anyhow!("DMA failed")));
+        assert_eq!(second_answer.try_recv().unwrap().err(), Some(LightsError::Driver("DMA failed".into())));
+    }
+
+    #[test]
+    fn fades_between_levels() {
+        let start = Instant::now();
+        let fade = Fade { from: 200, to: 100, start, length: Duration::from_millis(500) };
+        assert_eq!(fade.level(start), (200, false));
+        assert_eq!(fade.level(start + Duration::from_millis(250)), (150, false));
+        assert_eq!(fade.level(start + Duration::from_millis(600)), (100, true));
+        let instant = Fade { from: 0, to: 255, start, length: Duration::ZERO };
+        assert_eq!(instant.level(start), (255, true));
+    }
+
+    #[test]
+    fn copies_preview_only_on_change() {
+        let red: LedColor = [255, 0, 0].into();
+        let black: LedColor = [0, 0, 0].into();
+        let mut preview = Vec::new();
+        assert!(copy_preview(&mut preview, &[red; 3], true));
+        assert_eq!(preview, vec![red; 3]);
+        assert!(!copy_preview(&mut preview, &[red; 3], true));
+        assert!(copy_preview(&mut preview, &[red; 3], false));
+        assert_eq!(preview, vec![black; 3]);
+        assert!(!copy_preview(&mut preview, &[red; 3], false));
+        assert!(copy_preview(&mut preview, &[black; 4], true));
+        assert_eq!(preview, vec![black; 4]);
+    }
 }
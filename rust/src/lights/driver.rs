@@ -1,8 +1,11 @@
 use std::ops::{Index, IndexMut};
+use std::time::Instant;
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use rs_ws281x::{
     ChannelBuilder,
     Controller,
@@ -10,54 +13,255 @@ use rs_ws281x::{
     StripType,
 };
 
+use crate::metrics::METRICS;
 use super::LedColor;
+use super::network::{NetworkOutput, OutputConfig};
+
+/// The kind of LEDs on a strip, and the order they take their colors in
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum StripKind {
+    #[default]
+    Ws2812,
+    Ws2811Rgb,
+    Ws2811Rbg,
+    Ws2811Grb,
+    Ws2811Gbr,
+    Ws2811Brg,
+    Ws2811Bgr,
+    Sk6812,
+    Sk6812W,
+    Sk6812Rgbw,
+    Sk6812Rbgw,
+    Sk6812Gbrw,
+    Sk6812Grbw,
+    Sk6812Brgw,
+    Sk6812Bgrw,
+}
+
+impl From<StripKind> for StripType {
+    fn from(kind: StripKind) -> Self {
+        match kind {
+            StripKind::Ws2812 => StripType::Ws2812,
+            StripKind::Ws2811Rgb => StripType::Ws2811Rgb,
+            StripKind::Ws2811Rbg => StripType::Ws2811Rbg,
+            StripKind::Ws2811Grb => StripType::Ws2811Grb,
+            StripKind::Ws2811Gbr => StripType::Ws2811Gbr,
+            StripKind::Ws2811Brg => StripType::Ws2811Brg,
+            StripKind::Ws2811Bgr => StripType::Ws2811Bgr,
+            StripKind::Sk6812 => StripType::Sk6812,
+            StripKind::Sk6812W => StripType::Sk6812W,
+            StripKind::Sk6812Rgbw => StripType::Sk6812Rgbw,
+            StripKind::Sk6812Rbgw => StripType::Sk6812Rbgw,
+            StripKind::Sk6812Gbrw => StripType::Sk6812Gbrw,
+            StripKind::Sk6812Grbw => StripType::Sk6812Grbw,
+            StripKind::Sk6812Brgw => StripType::Sk6812Brgw,
+            StripKind::Sk6812Bgrw => StripType::Sk6812Bgrw,
+        }
+    }
+}
+
+/// A strip of LEDs on a GPIO pin
+///
+/// Strips on the same pin are chained one after the other on the wire, in
+/// the order they are listed.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StripConfig {
+    pub name: String,
+    /// GPIO number (not the header pin)
+    pub pin: u8,
+    pub count: usize,
+    #[serde(default)]
+    pub kind: StripKind,
+    /// the first LED in the lights is the one furthest down the wire
+    #[serde(default)]
+    pub reverse: bool,
+    /// scales the colors of this strip, to even out strips that are brighter
+    #[serde(default = "full_brightness")]
+    pub brightness: u8,
+}
+
+fn full_brightness() -> u8 {
+    255
+}
 
 /// Configuration for the lights
-#[derive(Copy, Clone)]
+#[derive(Clone, Debug, Serialize, JsonSchema)]
 pub struct DriverConfig {
-    /// number of lights in the left strip (GPIO 12 - Pin 32)
-    pub left: usize,
-    /// number of lights in the right strip (GPIO 13 - Pin 33)
-    pub right: usize,
-    /// Not - maybe max overall brightness?
+    /// the strips in the order they are wired
+    pub strips: Vec<StripConfig>,
+    /// names of the strips in the order their LEDs are numbered, empty for
+    /// the wiring order
+    pub order: Vec<String>,
+    /// pixel controllers on the network
+    pub outputs: Vec<OutputConfig>,
+    /// overall brightness
     pub brightness: u8,
+    /// frames rendered per second, updates in between are merged
+    pub fps: u32,
+}
+
+impl DriverConfig {
+    /// The number of LEDs on all the strips and outputs
+    pub fn led_count(&self) -> usize {
+        self.strips.iter().map(|strip| strip.count).sum::<usize>()
+            + self.outputs.iter().map(|output| output.count).sum::<usize>()
+    }
+}
+
+/// The driver channel for a GPIO pin, the PWM1 pins use the second channel
+/// and everything else (PWM0, PCM and SPI) the first
+fn channel_for(pin: u8) -> Result<usize> {
+    match pin {
+        10 | 12 | 18 | 21 | 31 | 40 | 52 => Ok(0),
+        13 | 19 | 41 | 45 | 53 => Ok(1),
+        _ => bail!("GPIO {pin} can't drive LEDs"),
+    }
+}
+
+/// What the LEDs of a strip are sent to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Target {
+    /// a driver channel
+    Channel(usize),
+    /// a network output
+    Output(usize),
+}
+
+/// Where an LED is in the driver
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Led {
+    target: Target,
+    index: usize,
+    /// brightness of the strip it is on
+    brightness: u8,
+}
+
+/// A strip or output as it is placed in the lights
+struct Segment<'a> {
+    name: &'a str,
+    count: usize,
+    reverse: bool,
+    brightness: u8,
+    target: Target,
+    /// where the segment starts on its target
+    start: usize,
+}
+
+/// How the strips are set up on the channels
+struct Layout {
+    /// (pin, count, kind) for each channel that is used
+    channels: [Option<(u8, usize, StripKind)>; 2],
+    /// where each LED of the lights is, in order
+    leds: Vec<Led>,
+}
+
+impl Layout {
+    fn new(config: &DriverConfig) -> Result<Self> {
+        let mut channels: [Option<(u8, usize, StripKind)>; 2] = [None, None];
+        let mut segments = Vec::with_capacity(config.strips.len() + config.outputs.len());
+        for strip in config.strips.iter() {
+            let channel = channel_for(strip.pin)?;
+            let (pin, count, kind) = channels[channel].get_or_insert((strip.pin, 0, strip.kind));
+            if *pin != strip.pin {
+                bail!("GPIO {} and {} can't both be used, they share a driver channel", pin, strip.pin);
+            }
+            if *kind != strip.kind {
+                bail!("Strips chained on GPIO {pin} must be the same kind");
+            }
+            segments.push(Segment {
+                name: &strip.name,
+                count: strip.count,
+                reverse: strip.reverse,
+                brightness: strip.brightness,
+                target: Target::Channel(channel),
+                start: *count,
+            });
+            *count += strip.count;
+        }
+        for (i, output) in config.outputs.iter().enumerate() {
+            segments.push(Segment {
+                name: &output.name,
+                count: output.count,
+                reverse: output.reverse,
+                brightness: 255,
+                target: Target::Output(i),
+                start: 0,
+            });
+        }
+
+        let order: Vec<usize> = if config.order.is_empty() {
+            (0..segments.len()).collect()
+        } else {
+            let order = config.order.iter()
+                .map(|name| segments.iter().position(|segment| segment.name == name)
+                    .with_context(|| format!("No strip or output named {name}")))
+                .collect::<Result<Vec<_>>>()?;
+            if order.len() != segments.len() || (0..order.len()).any(|i| !order.contains(&i)) {
+                bail!("The strip order has to list every strip and output once");
+            }
+            order
+        };
+
+        let mut leds = Vec::with_capacity(config.led_count());
+        for i in order {
+            let Segment { count, reverse, brightness, target, start, .. } = segments[i];
+            for n in 0..count {
+                let index = if reverse { start + count - 1 - n } else { start + n };
+                leds.push(Led { target, index, brightness });
+            }
+        }
+        Ok(Layout { channels, leds })
+    }
 }
 
-/// Driver object for the ws281x lights
+/// Driver object for the ws281x lights and the network outputs
 pub struct LedDriver {
-    pub sizes: (usize, usize),
+    leds: Vec<Led>,
+    channels: Vec<usize>,
     pub controller: Controller,
+    /// the network outputs with the LEDs sent to them
+    outputs: Vec<(NetworkOutput, Vec<[u8; 4]>)>,
+    /// overall brightness, the network outputs are scaled by it when sent
+    brightness: u8,
 }
 
 impl LedDriver {
     /// new driver
-    pub fn new(config: DriverConfig) -> Result<Self> {
-        debug!("Creating new LED driver with ({},{}) leds on (left,right) pins", config.left, config.right);
-        let controller = ControllerBuilder::new()
-        .channel(
-            0,
-            ChannelBuilder::new()
-            .pin(12) // GPIO 12 = header pin 32
-            .count(config.left as i32)
-            .strip_type(StripType::Ws2812)
-            .brightness(config.brightness)
-            .build(),
-        )
-        .channel(
-            1,
-            ChannelBuilder::new()
-            .pin(13) // GPIO = header pin 33
-            .count(config.right as i32)
-            .strip_type(StripType::Ws2812)
-            .brightness(config.brightness)
-            .build(),
-        )
-        .build()
+    pub fn new(config: &DriverConfig) -> Result<Self> {
+        let layout = Layout::new(config)?;
+        let mut builder = ControllerBuilder::new();
+        let mut channels = Vec::new();
+        for (channel, setup) in layout.channels.iter().enumerate() {
+            let Some((pin, count, kind)) = setup else {
+                continue
+            };
+            debug!("Creating LED driver channel {channel} with {count} LEDs on GPIO {pin}");
+            builder.channel(
+                channel,
+                ChannelBuilder::new()
+                .pin(*pin as i32)
+                .count(*count as i32)
+                .strip_type((*kind).into())
+                .brightness(config.brightness)
+                .build(),
+            );
+            channels.push(channel);
+        }
+        let controller = builder.build()
         .context("Failed setting up Controller")?;
 
+        let outputs = config.outputs.iter()
+            .map(|output| (NetworkOutput::new(output.clone()), vec![[0; 4]; output.count]))
+            .collect();
+
         Ok(LedDriver {
-            sizes: (config.left, config.right),
+            leds: layout.leds,
+            channels,
             controller,
+            outputs,
+            brightness: config.brightness,
         })
     }
 
@@ -66,21 +270,69 @@ impl LedDriver {
         LedIterator{ lc: self, index: 0 }
     }
 
-    /// Turn all the lights off
-    pub fn clear(&mut self) -> Result<()> {
-        for led in self.iter() {
-            *led = [0,0,0,0];
+    /// Show the current colors on the lights
+    pub fn render(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.send_outputs(start);
+        match self.controller.render() {
+            Ok(()) => {
+                METRICS.frame_rendered(start.elapsed());
+                Ok(())
+            },
+            Err(e) => {
+                METRICS.frames_dropped(1);
+                Err(e.into())
+            },
+        }
+    }
+
+    /// Send the colors to the network outputs, a controller that can't be
+    /// reached doesn't stop the rest of the lights
+    fn send_outputs(&mut self, now: Instant) {
+        let scale = |c: u8| (c as u16 * self.brightness as u16 / 255) as u8;
+        for (output, leds) in self.outputs.iter_mut() {
+            // the LEDs are stored the way the ws281x driver wants them
+            let rgb: Vec<u8> = leds.iter()
+                .flat_map(|[b, r, g, _]| [scale(*r), scale(*g), scale(*b)])
+                .collect();
+            output.show(&rgb, now);
+        }
+    }
+
+    /// A network output has a frame waiting for its rate limit
+    pub fn pending(&self) -> bool {
+        self.outputs.iter().any(|(output, _)| output.pending())
+    }
+
+    /// Change the brightness of all the strips, shown on the next render
+    pub fn set_brightness(&mut self, level: u8) {
+        for channel in self.channels.iter() {
+            self.controller.set_brightness(*channel, level);
+        }
+        self.brightness = level;
+    }
+
+    /// Copy colors into the LEDs, scaled by the brightness of their strip,
+    /// shown on the next render
+    pub fn set_all(&mut self, colors: &[LedColor]) {
+        for (index, color) in colors.iter().enumerate().take(self.leds.len()) {
+            let brightness = self.leds[index].brightness;
+            let scale = |c: u8| (c as u16 * brightness as u16 / 255) as u8;
+            self[index] = LedColor { r: scale(color.r), g: scale(color.g), b: scale(color.b) }.into();
         }
-        Ok(self.controller.render()?)
     }
 
-    /// Fill all the lights with a solid color
-    pub fn fill<A: Copy + Into<LedColor>>(&mut self, color: A) -> Result<()> {
-        let color: [u8; 4] = color.into().into();
+    /// Turn all the LEDs off, shown on the next render
+    pub fn blank(&mut self) {
         for led in self.iter() {
-            *led = color;
+            *led = [0,0,0,0];
         }
-        Ok(self.controller.render()?)
+    }
+
+    /// Turn all the lights off
+    pub fn clear(&mut self) -> Result<()> {
+        self.blank();
+        self.render()
     }
 }
 
@@ -89,11 +341,10 @@ impl Index<usize> for LedDriver {
 
     /// Get the reference to a single LED
     fn index(&self, index: usize) -> &Self::Output {
-        let mid = self.sizes.0;
-        if index < mid {
-            &self.controller.leds(0)[mid-1-index]
-        } else {
-            &self.controller.leds(1)[index-mid]
+        let led = self.leds[index];
+        match led.target {
+            Target::Channel(channel) => &self.controller.leds(channel)[led.index],
+            Target::Output(output) => &self.outputs[output].1[led.index],
         }
     }
 }
@@ -102,11 +353,10 @@ impl IndexMut<usize> for LedDriver {
 
     /// Get a mutable reference to a single LED
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        let mid = self.sizes.0;
-        if index < mid {
-            &mut self.controller.leds_mut(0)[mid-1-index-1]
-        } else {
-            &mut self.controller.leds_mut(1)[index-mid-1]
+        let led = self.leds[index];
+        match led.target {
+            Target::Channel(channel) => &mut self.controller.leds_mut(channel)[led.index],
+            Target::Output(output) => &mut self.outputs[output].1[led.index],
         }
     }
 }
@@ -121,22 +371,67 @@ impl<'a> Iterator for LedIterator<'a> {
     type Item = &'a mut [u8; 4];
 
     fn next(&mut self) -> Option<Self::Item> {
-        let i = self.index;
+        let led = *self.lc.leds.get(self.index)?;
         self.index += 1;
-        let mid = self.lc.sizes.0;
-        let last = mid + self.lc.sizes.1;
-        if i < mid {
-            let ptr = self.lc.controller.leds_mut(0).as_mut_ptr();
-            unsafe {
-                Some(&mut *ptr.add(mid-1-i))
-            }
-        } else if i < last {
-            let ptr = self.lc.controller.leds_mut(1).as_mut_ptr();
-            unsafe {
-                Some(&mut *ptr.add(i-mid))
-            }
-        } else {
-            None
+        let ptr = match led.target {
+            Target::Channel(channel) => self.lc.controller.leds_mut(channel).as_mut_ptr(),
+            Target::Output(output) => self.lc.outputs[output].1.as_mut_ptr(),
+        };
+        // every LED is listed once in the layout, so these never alias
+        unsafe {
+            Some(&mut *ptr.add(led.index))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lights::network::Protocol;
+
+    fn strip(name: &str, pin: u8, count: usize, reverse: bool) -> StripConfig {
+        StripConfig { name: name.into(), pin, count, kind: StripKind::Ws2812, reverse, brightness: 255 }
+    }
+
+    #[test]
+    fn layouts() {
+        let mut config = DriverConfig {
+            strips: vec![strip("tree", 12, 3, true), strip("star", 12, 2, false), strip("garland", 13, 2, false)],
+            order: vec!["star".into(), "tree".into(), "garland".into()],
+            outputs: Vec::new(),
+            brightness: 255,
+            fps: 60,
+        };
+        let layout = Layout::new(&config).unwrap();
+        assert_eq!(layout.channels[0].map(|(_, count, _)| count), Some(5));
+        assert_eq!(layout.channels[1].map(|(_, count, _)| count), Some(2));
+        let leds: Vec<(Target, usize)> = layout.leds.iter().map(|led| (led.target, led.index)).collect();
+        let (a, b) = (Target::Channel(0), Target::Channel(1));
+        assert_eq!(leds, vec![(a, 3), (a, 4), (a, 2), (a, 1), (a, 0), (b, 0), (b, 1)]);
+
+        // network outputs are segments like the strips
+        config.outputs.push(OutputConfig {
+            name: "yard".into(),
+            protocol: Protocol::Ddp,
+            address: "127.0.0.1".into(),
+            count: 2,
+            universe: 1,
+            offset: 0,
+            reverse: true,
+            fps: None,
+        });
+        config.order.insert(0, "yard".into());
+        let layout = Layout::new(&config).unwrap();
+        assert_eq!(layout.leds[0].target, Target::Output(0));
+        assert_eq!(layout.leds[0].index, 1);
+        assert_eq!(layout.leds.len(), 9);
+        config.outputs.clear();
+        config.order.remove(0);
+
+        config.order = vec!["star".into()];
+        assert!(Layout::new(&config).is_err());
+        config.order.clear();
+        config.strips.push(strip("porch", 18, 10, false));
+        assert!(Layout::new(&config).is_err());
+    }
+}
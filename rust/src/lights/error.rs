@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// Why the lights controller couldn't carry out a command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LightsError {
+    /// The LED index is past the end of the strips
+    IndexOutOfRange { index: usize, count: usize },
+    /// The number of colors doesn't match the number of LEDs
+    WrongLength { expected: usize, got: usize },
+    /// The LED driver failed to render or set up
+    Driver(String),
+    /// The recording couldn't be started
+    Recording(String),
+    /// There is no layer with the name
+    NoLayer(String),
+    /// The controller isn't running anymore
+    Closed,
+}
+
+impl fmt::Display for LightsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LightsError::IndexOutOfRange { index, count } =>
+                write!(f, "LED index {index} is out of range for {count} LEDs"),
+            LightsError::WrongLength { expected, got } =>
+                write!(f, "Got {got} colors for {expected} LEDs"),
+            LightsError::Driver(e) => write!(f, "LED driver error: {e}"),
+            LightsError::Recording(e) => write!(f, "Recording error: {e}"),
+            LightsError::NoLayer(name) => write!(f, "No layer named {name}"),
+            LightsError::Closed => write!(f, "Lights controller is not running"),
+        }
+    }
+}
+
+impl std::error::Error for LightsError {}
+
+impl From<anyhow::Error> for LightsError {
+    fn from(e: anyhow::Error) -> Self {
+        LightsError::Driver(format!("{e:#}"))
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// How long the frame statistics are collected for before they are updated
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Frame timing over the last second
+#[derive(Clone, Copy, Debug, Default, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameStats {
+    pub target_fps: u32,
+    /// frames actually rendered per second
+    pub fps: f64,
+    pub render_avg_ms: f64,
+    pub render_max_ms: f64,
+    /// frames that took longer to render than the frame time (since start)
+    pub late_frames: u64,
+    /// updates that were merged into another frame instead of rendered on
+    /// their own (since start)
+    pub coalesced: u64,
+}
+
+/// Collects the render times into `FrameStats`
+pub struct FrameTimer {
+    frame_time: Duration,
+    window_start: Instant,
+    frames: u32,
+    render_total: Duration,
+    render_max: Duration,
+    /// updates since the last frame
+    pending: u64,
+    stats: FrameStats,
+}
+
+impl FrameTimer {
+    pub fn new(fps: u32) -> Self {
+        let fps = fps.max(1);
+        FrameTimer {
+            frame_time: Duration::from_secs(1) / fps,
+            window_start: Instant::now(),
+            frames: 0,
+            render_total: Duration::ZERO,
+            render_max: Duration::ZERO,
+            pending: 0,
+            stats: FrameStats { target_fps: fps, ..Default::default() },
+        }
+    }
+
+    /// Time between frames
+    pub fn frame_time(&self) -> Duration {
+        self.frame_time
+    }
+
+    /// Count an update waiting for the next frame
+    pub fn update(&mut self) {
+        self.pending += 1;
+    }
+
+    /// Count a rendered frame that took `took`, finishing the window at `now`
+    pub fn rendered(&mut self, took: Duration, now: Instant) {
+        self.frames += 1;
+        self.render_total += took;
+        self.render_max = self.render_max.max(took);
+        if took > self.frame_time {
+            self.stats.late_frames += 1;
+        }
+        self.stats.coalesced += self.pending.saturating_sub(1);
+        self.pending = 0;
+
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            self.stats.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.stats.render_avg_ms = self.render_total.as_secs_f64() * 1000.0 / self.frames as f64;
+            self.stats.render_max_ms = self.render_max.as_secs_f64() * 1000.0;
+            self.window_start = now;
+            self.frames = 0;
+            self.render_total = Duration::ZERO;
+            self.render_max = Duration::ZERO;
+        }
+    }
+
+    /// The stats at `now`, when no frame has finished the window for a while
+    /// the frame rate is what was rendered since it started, so it drops to
+    /// zero while the lights are idle
+    pub fn stats(&self, now: Instant) -> FrameStats {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return self.stats;
+        }
+        FrameStats { fps: self.frames as f64 / elapsed.as_secs_f64(), ..self.stats }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collects_stats() {
+        let mut timer = FrameTimer::new(50);
+        assert_eq!(timer.frame_time(), Duration::from_millis(20));
+        let start = timer.window_start;
+        for i in 1..=50 {
+            timer.update();
+            timer.update();
+            let took = Duration::from_millis(if i == 10 { 30 } else { 5 });
+            timer.rendered(took, start + Duration::from_millis(20 * i));
+        }
+        let end = start + Duration::from_millis(1000);
+        let stats = timer.stats(end);
+        assert!((stats.fps - 50.0).abs() < 0.01);
+        assert_eq!(stats.render_max_ms, 30.0);
+        assert_eq!(stats.late_frames, 1);
+        assert_eq!(stats.coalesced, 50);
+
+        // nothing rendered since
+        timer.rendered(Duration::from_millis(5), end + Duration::from_millis(500));
+        assert!((timer.stats(end + Duration::from_secs(2)).fps - 0.5).abs() < 0.01);
+        assert!(timer.stats(end + Duration::from_secs(60)).fps < 0.02);
+        assert_eq!(timer.stats(end + Duration::from_secs(60)).render_max_ms, 30.0);
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Whether the lights controller is running and the driver is working,
+/// shared between the controller and everything holding a remote
+#[derive(Default)]
+pub struct Health {
+    running: AtomicBool,
+    driver_ok: AtomicBool,
+    restarts: AtomicU64,
+}
+
+/// A snapshot of the controller health
+#[derive(Clone, Copy, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    /// the controller loop is running
+    pub running: bool,
+    /// the last frame was rendered without errors
+    pub driver_ok: bool,
+    /// times the controller was restarted after failing
+    pub restarts: u64,
+}
+
+impl Health {
+    pub fn status(&self) -> HealthStatus {
+        HealthStatus {
+            running: self.running.load(Ordering::Relaxed),
+            driver_ok: self.driver_ok.load(Ordering::Relaxed),
+            restarts: self.restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn set_running(&self, running: bool) {
+        self.running.store(running, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_driver_ok(&self, ok: bool) {
+        self.driver_ok.store(ok, Ordering::Relaxed);
+    }
+
+    pub(super) fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+}
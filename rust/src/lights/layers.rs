@@ -0,0 +1,188 @@
+//! Layers composed on top of the base colors before they are shown
+//!
+//! The base is what the running mode (or a plain `Set`) shows. Layers are
+//! named, drawn in order of priority on top of it, and can remove themselves
+//! after a timeout, so a short flash doesn't destroy the animation under it.
+use std::time::{Duration, Instant};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::LedColor;
+
+/// How a layer is combined with the colors below it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Blend {
+    /// the layer covers what is below it
+    #[default]
+    Replace,
+    /// the colors are added together
+    Add,
+    /// the colors below are scaled by the layer, e.g. to dim parts of a strip
+    Multiply,
+    /// like replace, but black LEDs in the layer let the colors below show
+    Alpha,
+}
+
+/// A layer of colors to show on top of the base colors
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub name: String,
+    /// higher priorities are drawn on top
+    pub priority: i32,
+    pub blend: Blend,
+    /// how much of the blended result is used, 255 is fully
+    pub opacity: u8,
+    pub colors: Vec<LedColor>,
+    /// remove the layer after this long
+    pub timeout: Option<Duration>,
+}
+
+/// A layer as reported in the lights state (without the colors)
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerInfo {
+    pub name: String,
+    pub priority: i32,
+    pub blend: Blend,
+    pub opacity: u8,
+    /// time left before the layer is removed
+    pub remaining_ms: Option<u64>,
+}
+
+/// The layers in the order they are drawn
+#[derive(Default)]
+pub struct Layers {
+    layers: Vec<(Layer, Option<Instant>)>,
+}
+
+impl Layers {
+    /// Add a layer, replacing any layer with the same name
+    pub fn set(&mut self, layer: Layer, now: Instant) {
+        self.remove(&layer.name);
+        let expires = layer.timeout.map(|timeout| now + timeout);
+        // after the layers with the same priority, so the newest is on top
+        let at = self.layers.partition_point(|(l, _)| l.priority <= layer.priority);
+        self.layers.insert(at, (layer, expires));
+    }
+
+    /// Drop the layers that don't cover `count` LEDs, after the strips change
+    pub fn resize(&mut self, count: usize) {
+        self.layers.retain(|(layer, _)| layer.colors.len() == count);
+    }
+
+    /// Remove a layer, returns whether there was one
+    pub fn remove(&mut self, name: &str) -> bool {
+        let count = self.layers.len();
+        self.layers.retain(|(layer, _)| layer.name != name);
+        self.layers.len() != count
+    }
+
+    /// Remove the layers that have timed out, returns whether any were
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let count = self.layers.len();
+        self.layers.retain(|(_, expires)| expires.is_none_or(|expires| expires > now));
+        self.layers.len() != count
+    }
+
+    /// Whether any layer is waiting to time out
+    pub fn has_timeouts(&self) -> bool {
+        self.layers.iter().any(|(_, expires)| expires.is_some())
+    }
+
+    pub fn info(&self, now: Instant) -> Vec<LayerInfo> {
+        self.layers.iter()
+            .map(|(layer, expires)| LayerInfo {
+                name: layer.name.clone(),
+                priority: layer.priority,
+                blend: layer.blend,
+                opacity: layer.opacity,
+                remaining_ms: expires.map(|e| e.saturating_duration_since(now).as_millis() as u64),
+            })
+            .collect()
+    }
+
+    /// The base colors with every layer drawn on top
+    pub fn compose(&self, base: &[LedColor]) -> Vec<LedColor> {
+        let mut out = base.to_vec();
+        for (layer, _) in self.layers.iter() {
+            for (below, above) in out.iter_mut().zip(layer.colors.iter()) {
+                *below = blend(*below, *above, layer.blend, layer.opacity);
+            }
+        }
+        out
+    }
+}
+
+/// Combine a color with the one below it
+fn blend(below: LedColor, above: LedColor, mode: Blend, opacity: u8) -> LedColor {
+    let channel = |b: u8, a: u8| -> u8 {
+        match mode {
+            Blend::Replace | Blend::Alpha => a,
+            Blend::Add => b.saturating_add(a),
+            Blend::Multiply => ((b as u16 * a as u16 + 127) / 255) as u8,
+        }
+    };
+    if mode == Blend::Alpha && above == (LedColor { r: 0, g: 0, b: 0 }) {
+        return below;
+    }
+    let mix = |b: u8, a: u8| -> u8 {
+        let blended = channel(b, a) as u16;
+        ((b as u16 * (255 - opacity as u16) + blended * opacity as u16 + 127) / 255) as u8
+    };
+    LedColor { r: mix(below.r, above.r), g: mix(below.g, above.g), b: mix(below.b, above.b) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn layer(name: &str, priority: i32, blend: Blend, color: [u8; 3]) -> Layer {
+        Layer { name: name.into(), priority, blend, opacity: 255, colors: vec![color.into(); 2], timeout: None }
+    }
+
+    #[test]
+    fn blend_modes() {
+        let below: LedColor = [200, 100, 0].into();
+        let above: LedColor = [100, 255, 0].into();
+        assert_eq!(blend(below, above, Blend::Replace, 255), above);
+        assert_eq!(blend(below, above, Blend::Add, 255), [255, 255, 0].into());
+        assert_eq!(blend(below, above, Blend::Multiply, 255), [78, 100, 0].into());
+        assert_eq!(blend(below, [0, 0, 0].into(), Blend::Alpha, 255), below);
+        assert_eq!(blend(below, above, Blend::Replace, 128), [150, 178, 0].into());
+        assert_eq!(blend(below, above, Blend::Replace, 0), below);
+    }
+
+    #[test]
+    fn priorities_and_timeouts() {
+        let now = Instant::now();
+        let mut layers = Layers::default();
+        layers.set(layer("flash", 10, Blend::Replace, [255, 255, 255]), now);
+        layers.set(layer("dim", 5, Blend::Multiply, [128, 128, 128]), now);
+        let base = vec![[255, 0, 0].into(); 2];
+        assert_eq!(layers.compose(&base)[0], [255, 255, 255].into());
+
+        let mut flash = layer("flash", 10, Blend::Replace, [255, 255, 255]);
+        flash.timeout = Some(Duration::from_secs(1));
+        layers.set(flash, now);
+        assert!(layers.has_timeouts());
+        assert!(!layers.expire(now + Duration::from_millis(500)));
+        assert!(layers.expire(now + Duration::from_secs(2)));
+        assert_eq!(layers.compose(&base)[0], [128, 0, 0].into());
+        assert!(layers.remove("dim"));
+        assert!(!layers.remove("dim"));
+    }
+
+    #[test]
+    fn resizing_drops_layers() {
+        let now = Instant::now();
+        let mut layers = Layers::default();
+        layers.set(layer("flash", 10, Blend::Replace, [255, 255, 255]), now);
+        layers.resize(2);
+        assert_eq!(layers.info(now).len(), 1);
+        layers.resize(3);
+        assert!(layers.info(now).is_empty());
+        assert_eq!(layers.compose(&[[1, 2, 3].into(); 3]), vec![[1, 2, 3].into(); 3]);
+    }
+}
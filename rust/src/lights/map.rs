@@ -0,0 +1,67 @@
+//! Where each LED is, for drawing the lights in the preview
+use std::f64::consts::TAU;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// LEDs per turn around the tree
+const LEDS_PER_TURN: f64 = 40.0;
+
+/// The position of every LED, in the order they are numbered
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PixelMap {
+    /// `[x, y, z]` with y going up, in any unit
+    pub positions: Vec<[f64; 3]>,
+}
+
+impl PixelMap {
+    /// LEDs wound around a cone from the bottom up, like lights on a tree
+    pub fn tree(count: usize) -> Self {
+        let last = count.saturating_sub(1).max(1) as f64;
+        let positions = (0..count)
+            .map(|i| {
+                let height = i as f64 / last;
+                let radius = 0.4 * (1.0 - height) + 0.02;
+                let angle = i as f64 / LEDS_PER_TURN * TAU;
+                [radius * angle.cos(), height, radius * angle.sin()]
+            })
+            .collect();
+        PixelMap { positions }
+    }
+
+    /// Read a JSON list of `[x, y, z]` positions (or `[x, y]` for flat
+    /// lights), one for each of the `count` LEDs
+    pub fn load(path: &Path, count: usize) -> Result<Self> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let points: Vec<Vec<f64>> = serde_json::from_str(&file)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        if points.len() != count {
+            bail!("{} has {} positions for {count} LEDs", path.display(), points.len());
+        }
+        let positions = points.iter()
+            .map(|point| match point[..] {
+                [x, y] => Ok([x, y, 0.0]),
+                [x, y, z] => Ok([x, y, z]),
+                _ => bail!("Positions need 2 or 3 numbers"),
+            })
+            .collect::<Result<_>>()?;
+        Ok(PixelMap { positions })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tree_goes_up() {
+        let map = PixelMap::tree(100);
+        assert_eq!(map.positions.len(), 100);
+        assert_eq!(map.positions[0][1], 0.0);
+        assert_eq!(map.positions[99][1], 1.0);
+        assert!(map.positions.iter().all(|[x, _, z]| x.hypot(*z) <= 0.43));
+    }
+}
@@ -1,16 +1,36 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 mod color;
 pub use color::LedColor;
 
 mod driver;
 use driver::LedDriver;
-pub use driver::DriverConfig;
+pub use driver::{DriverConfig, StripConfig, StripKind};
+
+mod error;
+pub use error::LightsError;
+
+mod health;
+pub use health::{Health, HealthStatus};
+
+mod frames;
+
+mod map;
+pub use map::PixelMap;
+
+mod network;
+pub use network::OutputConfig;
+
+mod layers;
+pub use layers::{Blend, Layer, LayerInfo};
 
 mod controller;
-pub use controller::{LightsCommand, LightsRemote, LightsController};
+pub use controller::{LightsCommand, LightsRemote, LightsController, LightsState};
 
 /// Create a remote and controller task
 /// 
@@ -19,12 +39,16 @@ pub use controller::{LightsCommand, LightsRemote, LightsController};
 /// can only be run with tokio::task::spawn_local
 /// 
 /// The remote is a wrapper around an MPSC sender for sending commands to the
-/// controller and is cloneable and safe to use in multiple threads.
+/// controller and is cloneable and safe to use in multiple threads. Every
+/// command is answered by the controller once it has been carried out.
 pub fn new_lights(config: DriverConfig) -> (LightsRemote, LightsController) {
     trace!("Creating the lights remote and controller");
     let (sender, receiver) = mpsc::channel(10);
-    let remote = LightsRemote::new(sender);
-    let controller = LightsController::new(config, receiver);
+    let (preview, frames) = watch::channel(Vec::new());
+    let health = Arc::new(Health::default());
+    let count = Arc::new(AtomicUsize::new(config.led_count()));
+    let remote = LightsRemote::new(sender, frames, count.clone(), health.clone());
+    let controller = LightsController::new(config, receiver, preview, health, count);
     (remote, controller)
 }
 
@@ -0,0 +1,460 @@
+//! Sending frames to pixel controllers on the network (WLED, ESP32 and the
+//! like) over E1.31, Art-Net or DDP
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Channels used in each DMX universe, a whole number of RGB pixels
+const CHANNELS_PER_UNIVERSE: usize = 510;
+/// Most pixel data bytes in one DDP packet
+const DDP_MAX_DATA: usize = 1440;
+/// Identifies this app as an E1.31 source
+const E131_CID: [u8; 16] = *b"lights-app-e131!";
+/// Name sent with E1.31 packets
+const SOURCE_NAME: &str = "lights";
+/// Time before looking up an address that couldn't be found again
+const RESOLVE_RETRY: Duration = Duration::from_secs(10);
+
+/// The protocol an output speaks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Protocol {
+    /// streaming ACN (sACN), one packet per universe
+    E131,
+    /// Art-Net DMX, one packet per universe
+    ArtNet,
+    /// Distributed Display Protocol, which isn't split into universes
+    Ddp,
+}
+
+impl Protocol {
+    fn default_port(self) -> u16 {
+        match self {
+            Protocol::E131 => 5568,
+            Protocol::ArtNet => 6454,
+            Protocol::Ddp => 4048,
+        }
+    }
+
+    /// The sequence number sent after `sequence`
+    ///
+    /// E1.31 receivers drop packets that look older than the last one, so it
+    /// uses the whole byte. Zero means "no sequence" for Art-Net and DDP, and
+    /// DDP only has 4 bits.
+    fn next_sequence(self, sequence: u8) -> u8 {
+        match self {
+            Protocol::E131 => sequence.wrapping_add(1),
+            Protocol::ArtNet => sequence % 255 + 1,
+            Protocol::Ddp => sequence % 15 + 1,
+        }
+    }
+}
+
+/// A pixel controller on the network, shown as a strip of the lights
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputConfig {
+    pub name: String,
+    pub protocol: Protocol,
+    /// host name or IP address, with an optional port
+    pub address: String,
+    pub count: usize,
+    /// first universe, for E1.31 and Art-Net
+    #[serde(default = "first_universe")]
+    pub universe: u16,
+    /// channel the first LED starts at (in the first universe for E1.31 and
+    /// Art-Net)
+    #[serde(default)]
+    pub offset: usize,
+    /// the first LED in the lights is the last one on the controller
+    #[serde(default)]
+    pub reverse: bool,
+    /// most frames sent per second, no limit when missing
+    pub fps: Option<u32>,
+}
+
+fn first_universe() -> u16 {
+    1
+}
+
+/// Where the frames of an output go
+enum Destination {
+    /// the address is being looked up in the background
+    Resolving(mpsc::Receiver<Result<SocketAddr>>),
+    /// the address couldn't be found, look it up again after this
+    Retry(Instant),
+    Ready(UdpSocket),
+}
+
+/// Look up the address on another thread, name lookups can take a while (or
+/// fail) when the network is still coming up
+fn resolve(config: &OutputConfig) -> Destination {
+    let (sender, receiver) = mpsc::channel();
+    let (address, port) = (config.address.clone(), config.protocol.default_port());
+    std::thread::spawn(move || {
+        let res = address.to_socket_addrs()
+            .or_else(|_| (address.as_str(), port).to_socket_addrs())
+            .with_context(|| format!("Unable to resolve {address}"))
+            .and_then(|mut addrs| addrs.next().with_context(|| format!("No address for {address}")));
+        let _ = sender.send(res);
+    });
+    Destination::Resolving(receiver)
+}
+
+/// A socket sending to `addr`
+fn connect(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Sends the colors of one output
+pub struct NetworkOutput {
+    config: OutputConfig,
+    destination: Destination,
+    /// shortest time between frames
+    interval: Duration,
+    last_sent: Option<Instant>,
+    /// a frame was held back by the rate limit
+    pending: bool,
+    sequence: u8,
+    /// the last frame couldn't be sent
+    failing: bool,
+}
+
+impl NetworkOutput {
+    /// An output that starts sending once its address has been found, an
+    /// address that can't be found doesn't stop the rest of the lights
+    pub fn new(config: OutputConfig) -> Self {
+        // addresses don't need looking up
+        let addr = config.address.parse::<SocketAddr>().ok()
+            .or_else(|| config.address.parse::<IpAddr>().ok()
+                .map(|ip| SocketAddr::new(ip, config.protocol.default_port())));
+        let destination = match addr.map(connect) {
+            Some(Ok(socket)) => Destination::Ready(socket),
+            Some(Err(e)) => {
+                warn!("Unable to send to {}: {e:#}", config.name);
+                Destination::Retry(Instant::now() + RESOLVE_RETRY)
+            },
+            None => resolve(&config),
+        };
+        let interval = config.fps
+            .filter(|fps| *fps > 0)
+            .map(|fps| Duration::from_secs(1) / fps)
+            .unwrap_or_default();
+        NetworkOutput {
+            config,
+            destination,
+            interval,
+            last_sent: None,
+            pending: false,
+            sequence: 0,
+            failing: false,
+        }
+    }
+
+    /// The socket to send with, once the address has been found
+    fn socket(&mut self, now: Instant) -> Result<Option<&UdpSocket>> {
+        match &self.destination {
+            Destination::Resolving(receiver) => match receiver.try_recv() {
+                Ok(Ok(addr)) => {
+                    debug!("Sending {} LEDs to {addr} over {:?}", self.config.count, self.config.protocol);
+                    match connect(addr) {
+                        Ok(socket) => self.destination = Destination::Ready(socket),
+                        Err(e) => {
+                            self.destination = Destination::Retry(now + RESOLVE_RETRY);
+                            return Err(e);
+                        },
+                    }
+                },
+                Ok(Err(e)) => {
+                    self.destination = Destination::Retry(now + RESOLVE_RETRY);
+                    return Err(e);
+                },
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    self.destination = Destination::Retry(now + RESOLVE_RETRY);
+                    return Err(anyhow!("Lost the lookup of {}", self.config.address));
+                },
+            },
+            Destination::Retry(at) if now >= *at => {
+                self.destination = resolve(&self.config);
+                return Ok(None);
+            },
+            Destination::Retry(_) => return Err(anyhow!("No address for {}", self.config.address)),
+            Destination::Ready(_) => {},
+        }
+        match &self.destination {
+            Destination::Ready(socket) => Ok(Some(socket)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Send a frame, logging when the controller stops (or starts) taking
+    /// them rather than for every frame
+    pub fn show(&mut self, rgb: &[u8], now: Instant) {
+        match self.send(rgb, now) {
+            Ok(()) if self.failing => {
+                info!("Sending to {} again", self.config.name);
+                self.failing = false;
+            },
+            Ok(()) => {},
+            Err(e) if !self.failing => {
+                warn!("Unable to send to {}: {e:#}", self.config.name);
+                self.failing = true;
+            },
+            Err(_) => {},
+        }
+    }
+
+    /// A frame is waiting for the rate limit or for the address to be found
+    pub fn pending(&self) -> bool {
+        self.pending || !matches!(self.destination, Destination::Ready(_))
+    }
+
+    /// Send the RGB bytes of the LEDs, unless the last frame was too recent
+    /// or the address hasn't been found yet
+    pub fn send(&mut self, rgb: &[u8], now: Instant) -> Result<()> {
+        if self.socket(now)?.is_none() {
+            return Ok(());
+        }
+        if self.last_sent.is_some_and(|last| now.duration_since(last) < self.interval) {
+            self.pending = true;
+            return Ok(());
+        }
+        self.pending = false;
+        self.last_sent = Some(now);
+        self.sequence = self.config.protocol.next_sequence(self.sequence);
+        let packets = match self.config.protocol {
+            Protocol::E131 => e131_packets(self.config.universe, self.config.offset, rgb, self.sequence),
+            Protocol::ArtNet => artnet_packets(self.config.universe, self.config.offset, rgb, self.sequence),
+            Protocol::Ddp => ddp_packets(self.config.offset, rgb, self.sequence),
+        };
+        let Destination::Ready(socket) = &self.destination else {
+            return Ok(());
+        };
+        for packet in packets {
+            socket.send(&packet)
+                .with_context(|| format!("Failed to send to {}", self.config.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// The data for each universe, with the offset applied to the first
+fn universes(first: u16, offset: usize, rgb: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut data = vec![0u8; offset];
+    data.extend_from_slice(rgb);
+    data.chunks(CHANNELS_PER_UNIVERSE)
+        .enumerate()
+        .map(|(i, chunk)| (first.wrapping_add(i as u16), chunk.to_vec()))
+        .collect()
+}
+
+/// E1.31 data packets, see ANSI E1.31-2018
+fn e131_packets(first: u16, offset: usize, rgb: &[u8], sequence: u8) -> Vec<Vec<u8>> {
+    universes(first, offset, rgb).into_iter()
+        .map(|(universe, data)| {
+            let len = 126 + data.len();
+            let flags_len = |from: usize| (0x7000 | (len - from) as u16).to_be_bytes();
+            let mut packet = Vec::with_capacity(len);
+            // root layer
+            packet.extend_from_slice(&0x0010u16.to_be_bytes());
+            packet.extend_from_slice(&0u16.to_be_bytes());
+            packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+            packet.extend_from_slice(&flags_len(16));
+            packet.extend_from_slice(&4u32.to_be_bytes());
+            packet.extend_from_slice(&E131_CID);
+            // framing layer
+            packet.extend_from_slice(&flags_len(38));
+            packet.extend_from_slice(&2u32.to_be_bytes());
+            let mut name = [0u8; 64];
+            name[..SOURCE_NAME.len()].copy_from_slice(SOURCE_NAME.as_bytes());
+            packet.extend_from_slice(&name);
+            packet.push(100); // priority
+            packet.extend_from_slice(&0u16.to_be_bytes()); // no sync
+            packet.push(sequence);
+            packet.push(0); // options
+            packet.extend_from_slice(&universe.to_be_bytes());
+            // DMP layer
+            packet.extend_from_slice(&flags_len(115));
+            packet.push(0x02);
+            packet.push(0xa1);
+            packet.extend_from_slice(&0u16.to_be_bytes());
+            packet.extend_from_slice(&1u16.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+            packet.push(0); // start code
+            packet.extend_from_slice(&data);
+            packet
+        })
+        .collect()
+}
+
+/// Art-Net ArtDmx packets
+fn artnet_packets(first: u16, offset: usize, rgb: &[u8], sequence: u8) -> Vec<Vec<u8>> {
+    universes(first, offset, rgb).into_iter()
+        .map(|(universe, mut data)| {
+            // the length has to be even
+            if data.len() % 2 == 1 {
+                data.push(0);
+            }
+            let mut packet = Vec::with_capacity(18 + data.len());
+            packet.extend_from_slice(b"Art-Net\0");
+            packet.extend_from_slice(&0x5000u16.to_le_bytes());
+            packet.extend_from_slice(&14u16.to_be_bytes());
+            packet.push(sequence);
+            packet.push(0); // physical port
+            packet.extend_from_slice(&(universe & 0x7fff).to_le_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&data);
+            packet
+        })
+        .collect()
+}
+
+/// DDP packets, the last one tells the controller to show the frame
+fn ddp_packets(offset: usize, rgb: &[u8], sequence: u8) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = rgb.chunks(DDP_MAX_DATA).collect();
+    chunks.iter()
+        .enumerate()
+        .map(|(i, data)| {
+            let push = if i + 1 == chunks.len() { 0x01 } else { 0x00 };
+            let mut packet = Vec::with_capacity(10 + data.len());
+            packet.push(0x40 | push); // version 1
+            packet.push(sequence & 0x0f);
+            packet.push(0x0b); // RGB, 8 bits each
+            packet.push(0x01); // default output
+            packet.extend_from_slice(&((offset + i * DDP_MAX_DATA) as u32).to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+            packet
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn output(protocol: Protocol, count: usize, listener: &UdpSocket) -> NetworkOutput {
+        NetworkOutput::new(OutputConfig {
+            name: "test".into(),
+            protocol,
+            address: listener.local_addr().unwrap().to_string(),
+            count,
+            universe: 1,
+            offset: 0,
+            reverse: false,
+            fps: Some(10),
+        })
+    }
+
+    #[test]
+    fn e131() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut out = output(Protocol::E131, 200, &listener);
+        let rgb: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        out.send(&rgb, Instant::now()).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(len, 126 + 510);
+        assert_eq!(&buf[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(u16::from_be_bytes([buf[113], buf[114]]), 1);
+        assert_eq!(u16::from_be_bytes([buf[123], buf[124]]), 511);
+        assert_eq!(&buf[126..129], &[0, 1, 2]);
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(len, 126 + 90);
+        assert_eq!(u16::from_be_bytes([buf[113], buf[114]]), 2);
+        assert_eq!(buf[126], 510u16 as u8);
+    }
+
+    #[test]
+    fn e131_sequence() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut out = output(Protocol::E131, 1, &listener);
+        let start = Instant::now();
+        let mut buf = [0u8; 1024];
+        let mut last = None;
+        for i in 0..300u32 {
+            out.send(&[1, 2, 3], start + Duration::from_millis(100) * i).unwrap();
+            listener.recv(&mut buf).unwrap();
+            // the sequence byte of the framing layer
+            let sequence = buf[111];
+            if let Some(last) = last {
+                assert_eq!(sequence, u8::wrapping_add(last, 1));
+            }
+            last = Some(sequence);
+        }
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(Protocol::E131.next_sequence(255), 0);
+        assert_eq!(Protocol::ArtNet.next_sequence(255), 1);
+        assert_eq!(Protocol::ArtNet.next_sequence(15), 16);
+        assert_eq!(Protocol::Ddp.next_sequence(15), 1);
+    }
+
+    #[test]
+    fn artnet_and_rate_limit() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut out = output(Protocol::ArtNet, 1, &listener);
+        let now = Instant::now();
+        out.send(&[1, 2, 3], now).unwrap();
+        out.send(&[4, 5, 6], now + Duration::from_millis(10)).unwrap();
+        assert!(out.pending());
+
+        let mut buf = [0u8; 1024];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..8], b"Art-Net\0");
+        assert_eq!(u16::from_be_bytes([buf[16], buf[17]]), 4);
+        assert_eq!(&buf[18..len], &[1, 2, 3, 0]);
+
+        out.send(&[4, 5, 6], now + Duration::from_millis(100)).unwrap();
+        assert!(!out.pending());
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[18..len], &[4, 5, 6, 0]);
+    }
+
+    #[test]
+    fn unknown_host() {
+        let mut out = NetworkOutput::new(OutputConfig {
+            name: "missing".into(),
+            protocol: Protocol::Ddp,
+            address: "no-such-host.invalid".into(),
+            count: 1,
+            universe: 1,
+            offset: 0,
+            reverse: false,
+            fps: None,
+        });
+        // the lookup happens in the background, and failing to find the
+        // address only fails the sends
+        let start = Instant::now();
+        let mut res = Ok(());
+        while res.is_ok() && start.elapsed() < Duration::from_secs(10) {
+            res = out.send(&[1, 2, 3], Instant::now());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(res.is_err());
+        assert!(out.pending());
+    }
+
+    #[test]
+    fn ddp() {
+        let packets = ddp_packets(3, &vec![7u8; 1500], 1);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][0], 0x40);
+        assert_eq!(packets[1][0], 0x41);
+        assert_eq!(u32::from_be_bytes(packets[1][4..8].try_into().unwrap()), 3 + 1440);
+        assert_eq!(u16::from_be_bytes([packets[1][8], packets[1][9]]), 60);
+    }
+}
@@ -1,42 +1,173 @@
-use log::LevelFilter;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
 
 mod mylog;
 
+mod config;
+use config::Config;
+
+mod saved;
+use saved::Saved;
+
+mod palette;
+
+mod metrics;
+
+mod events;
+
+mod clock;
+
+mod sync;
+
+mod mdns;
+use mdns::Advertisement;
+
+mod systemd;
+
 mod lights;
-use lights::{DriverConfig, new_lights};
+use lights::{DriverConfig, PixelMap, new_lights};
 
 mod webapp;
 
 mod mode;
+use mode::ModeRunner;
+
+mod alert;
+use alert::Alerts;
+
+mod sleep;
+use sleep::SleepTimer;
+
+mod recording;
+
+mod fseq;
+
+mod audio;
+
+mod script;
+
+mod auth;
+use auth::Auth;
+
+mod cli;
+use cli::Cli;
 
 #[tokio::main]
 async fn main() {
+    // run a command line tool instead of the server if one was given
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        if let Err(e) = cli::run(command).await {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // load the settings, a broken config file should be fixed rather than
+    // silently replaced with the defaults
+    let config = Config::load();
+
     // initilize logging
-    if let Err(e) = mylog::init_log(LevelFilter::Debug) {
+    let log_config = config.as_ref().map(|config| config.log.clone()).unwrap_or_default();
+    if let Err(e) = mylog::init_log(&log_config) {
         eprintln!("Unable to initialize logging: {e:?}");
     }
 
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Unable to load config: {e:?}");
+            return;
+        }
+    };
+
+    // load the login and token settings before anything else, refusing to
+    // start without them is better than starting with no authentication
+    let auth = match Auth::load() {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => {
+            error!("Unable to load authentication settings: {e:?}");
+            return;
+        }
+    };
+
     // create lights object as part of our state
-    let config = DriverConfig {left: 100, right: 300, brightness: 255};
-    let (lights_remote, mut lights_controller) = new_lights(config);
+    let brightness = Saved::load().brightness.unwrap_or(255);
+    let driver_config = DriverConfig {
+        strips: config.lights.strips(),
+        outputs: config.lights.outputs.clone(),
+        order: config.lights.order.clone(),
+        brightness,
+        fps: config.lights.fps,
+    };
+    let (lights_remote, mut lights_controller) = new_lights(driver_config);
 
     // create handle for the axum server
     let app_handle = axum_server::Handle::new();
-    // create the signal handler
-    let signal = webapp::shutdown_signal(app_handle.clone(), lights_remote.clone());
-    // start the redirect server
-    let _redirect_task = tokio::spawn(webapp::redirect_http_to_https(signal));
+    let redirect_handle = axum_server::Handle::new();
+    // the signal handler runs on its own, so it still shuts down the app when
+    // the redirect server fails
+    tokio::spawn(webapp::shutdown_signal(vec![app_handle.clone(), redirect_handle.clone()], lights_remote.clone()));
+    if !config.http_only {
+        // start the redirect server
+        let redirect = webapp::redirect_http_to_https(redirect_handle, config.http_port, config.https_port);
+        let _redirect_task = tokio::spawn(async move {
+            if let Err(e) = redirect.await {
+                error!("Error with redirect service: {e:?}");
+            }
+        });
+    }
+    let systemd_remote = lights_remote.clone();
+    let sleep_timer = Arc::new(SleepTimer::new(lights_remote.clone()));
+    if let Some(hours) = config.lights.auto_off_hours {
+        let after = Duration::from_secs_f64(hours * 3600.0);
+        tokio::spawn(sleep::auto_off(sleep_timer.clone(), after));
+    }
+    // where the LEDs are for the preview, a made up tree will do
+    let map = match &config.lights.map {
+        Some(path) => PixelMap::load(path, lights_remote.led_count()).unwrap_or_else(|e| {
+            warn!("Unable to load the LED positions: {e:#}");
+            PixelMap::tree(lights_remote.led_count())
+        }),
+        None => PixelMap::tree(lights_remote.led_count()),
+    };
+    let modes = ModeRunner::shared(lights_remote.clone());
+    if let Some(sync) = config.sync.clone() {
+        tokio::spawn(sync::run(sync, modes.clone(), lights_remote.clone()));
+    }
+    if config.mdns.enabled {
+        let mode_names = modes.lock().await.available();
+        match Advertisement::new(&config, lights_remote.led_count(), &mode_names) {
+            Ok(advertisement) => {
+                tokio::spawn(mdns::advertise(advertisement));
+            },
+            Err(e) => warn!("Unable to advertise the lights: {e:#}"),
+        }
+    }
     // Start the server
-    let _webapp_task = tokio::spawn(async move { webapp::start(app_handle.clone(), &lights_remote).await });
+    let app_state = webapp::AppState {
+        modes,
+        alerts: Arc::new(Alerts::new(lights_remote.clone())),
+        sleep: sleep_timer,
+        remote: lights_remote,
+        auth,
+        http_only: config.http_only,
+        map: Arc::new(map),
+    };
+    let _webapp_task = tokio::spawn(async move { webapp::start(app_handle.clone(), app_state, config).await });
+    // let systemd know when we are up, and that we stay up
+    let _systemd_task = tokio::spawn(systemd::run(systemd_remote));
 
     // start the lights task in the main loop this handles the LED driver, which
     // is a bare pointer and can't be moved (easily... by me... cause I'm not
-    // good with handling pointers in rust)
-    if let Err(e) = lights_controller.start().await {
-        error!("Error with lights controller: {e:?}");
-    }
+    // good with handling pointers in rust). The driver is set up again if it
+    // fails.
+    lights_controller.supervise().await;
  
 }
 
@@ -0,0 +1,609 @@
+//! Telling the network about the lights over multicast DNS (DNS-SD), and
+//! finding other lights the same way
+//!
+//! The responder answers questions for the web-app (`_http._tcp` and
+//! `_https._tcp`) and for this app (`_lightsapp._tcp`, with the LED count,
+//! version, modes and sync port in the TXT record). `_wled._tcp` isn't
+//! advertised, WLED apps would expect its JSON API. It shares port 5353 with
+//! any other responder on the machine (like avahi), and doesn't probe for name
+//! conflicts.
+//!
+//! The host name (`<hostname>.local`) belongs to the system's responder, so
+//! the SRV records point at it but questions for its address are left to
+//! that responder. The address is only sent along with the SRV records, as a
+//! shared record that doesn't flush what other responders said about it.
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::config::Config;
+use crate::sync::SyncConfig;
+
+/// The service this app advertises itself as
+pub const LIGHTS_SERVICE: &str = "_lightsapp._tcp";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// Name asked for to list every service type
+const SERVICES: &str = "_services._dns-sd._udp.local";
+/// Time to live for the host and SRV records
+const HOST_TTL: u32 = 120;
+/// Time to live for the other records
+const OTHER_TTL: u32 = 4500;
+/// Longest time to live in answers to one-shot (legacy) queries
+const LEGACY_TTL: u32 = 10;
+/// Time between checks that this host's address hasn't changed
+const ADDR_CHECK_TIME: Duration = Duration::from_secs(30);
+/// Wait before starting the responder again after it fails
+const RETRY_TIME: Duration = Duration::from_secs(10);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of records only this host answers for
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// The data of a resource record
+#[derive(Clone, Debug, PartialEq)]
+enum Data {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Txt(Vec<String>),
+    Srv { port: u16, target: String },
+    /// a type that isn't used here
+    Other(u16),
+}
+
+impl Data {
+    fn record_type(&self) -> u16 {
+        match self {
+            Data::A(_) => TYPE_A,
+            Data::Aaaa(_) => TYPE_AAAA,
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Txt(_) => TYPE_TXT,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Other(record_type) => *record_type,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: Data,
+}
+
+impl Record {
+    fn new(name: &str, ttl: u32, data: Data) -> Self {
+        Record { name: name.to_string(), ttl, data }
+    }
+
+    /// Only this responder has records for the name
+    fn unique(&self) -> bool {
+        !matches!(self.data, Data::Ptr(_) | Data::A(_) | Data::Aaaa(_))
+    }
+}
+
+/// A DNS message, with the answer, authority and additional records together
+#[derive(Debug, Default)]
+struct Packet {
+    id: u16,
+    response: bool,
+    /// (name, type)
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn write_record(out: &mut Vec<u8>, record: &Record, cache_flush: bool) {
+    write_name(out, &record.name);
+    out.extend_from_slice(&record.data.record_type().to_be_bytes());
+    let class = if cache_flush && record.unique() { CLASS_IN | CACHE_FLUSH } else { CLASS_IN };
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+    let mut data = Vec::new();
+    match &record.data {
+        Data::A(addr) => data.extend_from_slice(&addr.octets()),
+        Data::Aaaa(addr) => data.extend_from_slice(&addr.octets()),
+        Data::Ptr(name) => write_name(&mut data, name),
+        Data::Txt(strings) if strings.is_empty() => data.push(0),
+        Data::Txt(strings) => for string in strings {
+            let string = &string.as_bytes()[..string.len().min(255)];
+            data.push(string.len() as u8);
+            data.extend_from_slice(string);
+        },
+        Data::Srv { port, target } => {
+            data.extend_from_slice(&[0, 0, 0, 0]); // priority and weight
+            data.extend_from_slice(&port.to_be_bytes());
+            write_name(&mut data, target);
+        },
+        Data::Other(_) => {},
+    }
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// Encode a query for `name`
+fn query(name: &str, record_type: u16) -> Vec<u8> {
+    let mut out = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut out, name);
+    out.extend_from_slice(&record_type.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out
+}
+
+/// Encode a response, one-shot queries get their id and questions back and
+/// no cache flush bits
+fn response(legacy: Option<&Packet>, answers: &[Record], additional: &[Record]) -> Vec<u8> {
+    let questions = legacy.map(|query| query.questions.as_slice()).unwrap_or_default();
+    let mut out = Vec::with_capacity(512);
+    out.extend_from_slice(&legacy.map(|query| query.id).unwrap_or_default().to_be_bytes());
+    out.extend_from_slice(&0x8400u16.to_be_bytes()); // response, authoritative
+    for count in [questions.len(), answers.len(), 0, additional.len()] {
+        out.extend_from_slice(&(count as u16).to_be_bytes());
+    }
+    for (name, record_type) in questions {
+        write_name(&mut out, name);
+        out.extend_from_slice(&record_type.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for record in answers.iter().chain(additional) {
+        write_record(&mut out, record, legacy.is_none());
+    }
+    out
+}
+
+/// Reads a DNS message
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len).context("Message is cut short")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    /// A name, following the pointers of compressed names
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        for _ in 0..128 {
+            let len = *self.buf.get(pos).context("Message is cut short")? as usize;
+            if len & 0xc0 == 0xc0 {
+                let low = *self.buf.get(pos + 1).context("Message is cut short")? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                    jumped = true;
+                }
+                pos = (len & 0x3f) << 8 | low;
+                continue;
+            }
+            if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            }
+            let label = self.buf.get(pos + 1..pos + 1 + len).context("Message is cut short")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+        bail!("Name has too many labels or pointers")
+    }
+
+    fn record(&mut self) -> Result<Record> {
+        let name = self.name()?;
+        let record_type = self.u16()?;
+        let _class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        let data = match record_type {
+            TYPE_A => Data::A(<[u8; 4]>::try_from(self.bytes(len)?)?.into()),
+            TYPE_AAAA => Data::Aaaa(<[u8; 16]>::try_from(self.bytes(len)?)?.into()),
+            TYPE_PTR => Data::Ptr(self.name()?),
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.pos < end {
+                    let len = self.bytes(1)?[0] as usize;
+                    let string = self.bytes(len)?;
+                    if !string.is_empty() {
+                        strings.push(String::from_utf8_lossy(string).into_owned());
+                    }
+                }
+                Data::Txt(strings)
+            },
+            TYPE_SRV => {
+                self.bytes(4)?;
+                let port = self.u16()?;
+                Data::Srv { port, target: self.name()? }
+            },
+            other => Data::Other(other),
+        };
+        self.pos = end;
+        Ok(Record { name, ttl, data })
+    }
+}
+
+fn parse(buf: &[u8]) -> Result<Packet> {
+    let mut reader = Reader { buf, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+    let mut packet = Packet { id, response: flags & 0x8000 != 0, ..Default::default() };
+    for _ in 0..counts[0] {
+        let name = reader.name()?;
+        let record_type = reader.u16()?;
+        let _class = reader.u16()?;
+        packet.questions.push((name, record_type));
+    }
+    for _ in 0..counts[1] as usize + counts[2] as usize + counts[3] as usize {
+        packet.records.push(reader.record()?);
+    }
+    Ok(packet)
+}
+
+/// A service this host offers
+#[derive(Clone, Debug)]
+struct Service {
+    /// like `_http._tcp`
+    kind: String,
+    port: u16,
+    txt: Vec<String>,
+}
+
+/// The records this host answers for
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    /// the instance name the services are shown as
+    name: String,
+    /// like `lights.local`
+    host: String,
+    /// this host's address, looked up when the responder starts
+    addr: Ipv4Addr,
+    services: Vec<Service>,
+}
+
+impl Advertisement {
+    /// Advertise the web-app and lights with `config`, named after the host
+    /// unless the config has a name
+    pub fn new(config: &Config, led_count: usize, modes: &[String]) -> Result<Self> {
+        let hostname = hostname::get()?.to_string_lossy().into_owned();
+        let name = config.mdns.name.clone().unwrap_or_else(|| hostname.clone());
+        let (proto, port) = if config.http_only { ("http", config.http_port) } else { ("https", config.https_port) };
+        let mut txt = vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("leds={led_count}"),
+            format!("modes={}", modes.join(",")),
+            format!("proto={proto}"),
+        ];
+        if let Some(SyncConfig::Leader { port, .. }) = config.sync {
+            txt.push(format!("sync={port}"));
+        }
+        let service = |kind: &str, port, txt: Vec<String>| Service { kind: kind.into(), port, txt };
+        let mut services = vec![service("_http._tcp", config.http_port, vec!["path=/".into()])];
+        if !config.http_only {
+            services.push(service("_https._tcp", config.https_port, vec!["path=/".into()]));
+        }
+        services.push(service(LIGHTS_SERVICE, port, txt));
+        let host = format!("{hostname}.local");
+        Ok(Advertisement { name, host, addr: Ipv4Addr::UNSPECIFIED, services })
+    }
+
+    fn instance(&self, service: &Service) -> String {
+        format!("{}.{}.local", self.name, service.kind)
+    }
+
+    fn host_record(&self) -> Record {
+        Record::new(&self.host, HOST_TTL, Data::A(self.addr))
+    }
+
+    fn srv_record(&self, service: &Service) -> Record {
+        let srv = Data::Srv { port: service.port, target: self.host.clone() };
+        Record::new(&self.instance(service), HOST_TTL, srv)
+    }
+
+    fn txt_record(&self, service: &Service) -> Record {
+        Record::new(&self.instance(service), OTHER_TTL, Data::Txt(service.txt.clone()))
+    }
+
+    /// The answers and additional records for a question
+    fn answer(&self, name: &str, record_type: u16) -> (Vec<Record>, Vec<Record>) {
+        let wants = |t| record_type == t || record_type == TYPE_ANY;
+        let (mut answers, mut additional) = (Vec::new(), Vec::new());
+        if name.eq_ignore_ascii_case(SERVICES) && wants(TYPE_PTR) {
+            for service in self.services.iter() {
+                answers.push(Record::new(SERVICES, OTHER_TTL, Data::Ptr(format!("{}.local", service.kind))));
+            }
+        }
+        for service in self.services.iter() {
+            if name.eq_ignore_ascii_case(&format!("{}.local", service.kind)) && wants(TYPE_PTR) {
+                answers.push(Record::new(name, OTHER_TTL, Data::Ptr(self.instance(service))));
+                additional.extend([self.srv_record(service), self.txt_record(service), self.host_record()]);
+            }
+            if name.eq_ignore_ascii_case(&self.instance(service)) {
+                if wants(TYPE_SRV) {
+                    answers.push(self.srv_record(service));
+                    additional.push(self.host_record());
+                }
+                if wants(TYPE_TXT) {
+                    answers.push(self.txt_record(service));
+                }
+            }
+        }
+        (answers, additional)
+    }
+
+    /// Every record, sent when the responder starts
+    fn all(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for service in self.services.iter() {
+            records.push(Record::new(SERVICES, OTHER_TTL, Data::Ptr(format!("{}.local", service.kind))));
+            records.push(Record::new(&format!("{}.local", service.kind), OTHER_TTL, Data::Ptr(self.instance(service))));
+            records.push(self.srv_record(service));
+            records.push(self.txt_record(service));
+        }
+        records
+    }
+}
+
+/// The address other hosts reach this one at, from the route to the mDNS group
+fn local_addr() -> Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((MDNS_GROUP, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(addr) if !addr.is_unspecified() => Ok(addr),
+        addr => bail!("No usable address ({addr}) for mDNS"),
+    }
+}
+
+/// Join the mDNS group on port 5353, alongside any other responder
+fn group_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())
+        .context("Unable to bind the mDNS port")?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Announce the records and answer questions for them, starting again when
+/// the network goes away
+pub async fn advertise(mut advertisement: Advertisement) {
+    loop {
+        if let Err(e) = respond(&mut advertisement).await {
+            warn!("mDNS responder stopped, starting again in {RETRY_TIME:?}: {e:#}");
+        }
+        tokio::time::sleep(RETRY_TIME).await;
+    }
+}
+
+async fn respond(ad: &mut Advertisement) -> Result<()> {
+    ad.addr = local_addr()?;
+    let socket = group_socket()?;
+    let group = SocketAddrV4::new(MDNS_GROUP, MDNS_PORT);
+    info!("Advertising {} at {} ({})", ad.name, ad.host, ad.addr);
+    // announced twice, a second apart
+    for i in 0..2 {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        socket.send_to(&response(None, &ad.all(), &[]), group).await?;
+    }
+
+    let mut buf = vec![0u8; 9000];
+    let mut check = tokio::time::interval(ADDR_CHECK_TIME);
+    check.reset();
+    loop {
+        let (len, from) = tokio::select! {
+            res = socket.recv_from(&mut buf) => res?,
+            _ = check.tick() => {
+                let addr = local_addr()?;
+                if addr != ad.addr {
+                    info!("Address for mDNS changed from {} to {addr}", ad.addr);
+                    ad.addr = addr;
+                }
+                continue;
+            },
+        };
+        let packet = match parse(&buf[..len]) {
+            Ok(packet) if !packet.response => packet,
+            Ok(_) => continue,
+            Err(e) => {
+                trace!("Bad mDNS message from {from}: {e:#}");
+                continue;
+            },
+        };
+        let (mut answers, mut additional) = (Vec::new(), Vec::new());
+        for (name, record_type) in packet.questions.iter() {
+            let (more, extra) = ad.answer(name, *record_type);
+            answers.extend(more);
+            additional.extend(extra);
+        }
+        if answers.is_empty() {
+            continue;
+        }
+        additional.retain(|record| !answers.contains(record));
+        additional.dedup();
+        // one-shot queries come from other ports and want a direct answer
+        let res = if from.port() == MDNS_PORT {
+            socket.send_to(&response(None, &answers, &additional), group).await
+        } else {
+            for record in answers.iter_mut().chain(additional.iter_mut()) {
+                record.ttl = record.ttl.min(LEGACY_TTL);
+            }
+            socket.send_to(&response(Some(&packet), &answers, &additional), from).await
+        };
+        if let Err(e) = res {
+            debug!("Unable to answer mDNS question from {from}: {e}");
+        }
+    }
+}
+
+/// An instance of a service found on the network
+#[derive(Clone, Debug)]
+pub struct Found {
+    /// the instance name
+    pub name: String,
+    pub host: String,
+    pub addr: Option<IpAddr>,
+    pub port: u16,
+    /// the keys and values of the TXT record
+    pub txt: BTreeMap<String, String>,
+}
+
+impl Found {
+    /// The address and port to connect to, if the host's address was given
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.addr.map(|addr| SocketAddr::new(addr, self.port))
+    }
+}
+
+/// Put the instances of `service` together from the records that came back
+fn found(service: &str, records: &[Record]) -> Vec<Found> {
+    let service = format!("{service}.local");
+    let mut instances: Vec<Found> = Vec::new();
+    for record in records.iter().filter(|record| record.name.eq_ignore_ascii_case(&service)) {
+        let Data::Ptr(instance) = &record.data else { continue };
+        if instances.iter().any(|found| found.name == *instance) {
+            continue;
+        }
+        let Some((host, port)) = records.iter().find_map(|record| match &record.data {
+            Data::Srv { port, target } if record.name.eq_ignore_ascii_case(instance) => Some((target.clone(), *port)),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let addr = records.iter().find_map(|record| match record.data {
+            Data::A(addr) if record.name.eq_ignore_ascii_case(&host) => Some(IpAddr::V4(addr)),
+            Data::Aaaa(addr) if record.name.eq_ignore_ascii_case(&host) => Some(IpAddr::V6(addr)),
+            _ => None,
+        });
+        let txt = records.iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(instance))
+            .filter_map(|record| match &record.data {
+                Data::Txt(strings) => Some(strings),
+                _ => None,
+            })
+            .flatten()
+            .map(|string| match string.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (string.clone(), String::new()),
+            })
+            .collect();
+        instances.push(Found { name: instance.clone(), host, addr, port, txt });
+    }
+    instances
+}
+
+/// Ask the network for the instances of a service (like `_lightsapp._tcp`),
+/// waiting `wait` for the answers
+pub async fn discover(service: &str, wait: Duration) -> Result<Vec<Found>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.send_to(&query(&format!("{service}.local"), TYPE_PTR), (MDNS_GROUP, MDNS_PORT)).await?;
+    let mut records = Vec::new();
+    let mut buf = vec![0u8; 9000];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = res?;
+        match parse(&buf[..len]) {
+            Ok(packet) if packet.response => records.extend(packet.records),
+            Ok(_) => {},
+            Err(e) => debug!("Bad mDNS answer from {from}: {e:#}"),
+        }
+    }
+    Ok(found(service, &records))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn advertisement() -> Advertisement {
+        Advertisement {
+            name: "tree".into(),
+            host: "tree.local".into(),
+            addr: Ipv4Addr::new(192, 168, 1, 20),
+            services: vec![Service {
+                kind: LIGHTS_SERVICE.into(),
+                port: 443,
+                txt: vec!["leds=400".into(), "sync=7117".into()],
+            }],
+        }
+    }
+
+    #[test]
+    fn answers() {
+        let ad = advertisement();
+        let (answers, additional) = ad.answer("_lightsapp._tcp.local", TYPE_PTR);
+        assert_eq!(answers, vec![Record::new("_lightsapp._tcp.local", OTHER_TTL, Data::Ptr("tree._lightsapp._tcp.local".into()))]);
+        assert_eq!(additional.len(), 3);
+        // the host name is left to the system's responder
+        assert!(ad.answer("TREE.local", TYPE_A).0.is_empty());
+        assert!(!ad.all().contains(&ad.host_record()));
+        assert!(ad.answer("_http._tcp.local", TYPE_PTR).0.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let ad = advertisement();
+        let query = parse(&query("_lightsapp._tcp.local", TYPE_PTR)).unwrap();
+        assert!(!query.response);
+        let (answers, additional) = ad.answer(&query.questions[0].0, query.questions[0].1);
+        let packet = parse(&response(Some(&query), &answers, &additional)).unwrap();
+        assert!(packet.response);
+        assert_eq!(packet.questions, query.questions);
+        // only the service records flush caches
+        assert!(!ad.host_record().unique());
+        assert!(ad.srv_record(&ad.services[0]).unique());
+
+        let found = found(LIGHTS_SERVICE, &packet.records);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].socket_addr(), Some("192.168.1.20:443".parse().unwrap()));
+        assert_eq!(found[0].txt.get("sync").map(String::as_str), Some("7117"));
+    }
+
+    #[test]
+    fn compressed_names() {
+        // "local" then "tree" pointing back at it
+        let buf = [5, b'l', b'o', b'c', b'a', b'l', 0, 4, b't', b'r', b'e', b'e', 0xc0, 0];
+        let mut reader = Reader { buf: &buf, pos: 7 };
+        assert_eq!(reader.name().unwrap(), "tree.local");
+        assert_eq!(reader.pos, buf.len());
+        // a pointer to itself never ends
+        let mut reader = Reader { buf: &[0xc0, 0], pos: 0 };
+        assert!(reader.name().is_err());
+    }
+}
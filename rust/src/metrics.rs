@@ -0,0 +1,158 @@
+//! Counters and gauges for the `/metrics` endpoint, in the Prometheus text
+//! format
+//!
+//! The counters are global so the controller, the modes and the web-app can
+//! all count without passing anything around. Gauges that can be read at any
+//! time (queue depth, active mode, power) are filled in when the metrics are
+//! rendered.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the render duration histogram buckets
+const RENDER_BUCKETS: [f64; 8] = [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25];
+
+/// The counters shared by the whole app
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    start: Instant,
+    frames_rendered: AtomicU64,
+    frames_dropped: AtomicU64,
+    render_buckets: [AtomicU64; RENDER_BUCKETS.len()],
+    /// total render time in microseconds
+    render_micros: AtomicU64,
+    /// request counts by method and status
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            start: Instant::now(),
+            frames_rendered: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            render_buckets: Default::default(),
+            render_micros: AtomicU64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count a frame sent to the LEDs and how long it took
+    pub fn frame_rendered(&self, took: Duration) {
+        self.frames_rendered.fetch_add(1, Ordering::Relaxed);
+        self.render_micros.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        let seconds = took.as_secs_f64();
+        if let Some(bucket) = RENDER_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.render_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count frames that were never shown, because rendering failed or a
+    /// mode fell behind
+    pub fn frames_dropped(&self, count: u64) {
+        self.frames_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count a web request
+    pub fn request(&self, method: &str, status: u16) {
+        *self.requests.lock().unwrap().entry((method.to_string(), status)).or_default() += 1;
+    }
+
+    /// Render the counters and the given gauges in the Prometheus text format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let frames = self.frames_rendered.load(Ordering::Relaxed);
+
+        header(&mut out, "lights_uptime_seconds", "gauge", "Time since the app started");
+        let _ = writeln!(out, "lights_uptime_seconds {}", self.start.elapsed().as_secs_f64());
+
+        header(&mut out, "lights_frames_rendered_total", "counter", "Frames sent to the LEDs");
+        let _ = writeln!(out, "lights_frames_rendered_total {frames}");
+
+        header(&mut out, "lights_frames_dropped_total", "counter", "Frames that were never shown");
+        let _ = writeln!(out, "lights_frames_dropped_total {}", self.frames_dropped.load(Ordering::Relaxed));
+
+        header(&mut out, "lights_render_duration_seconds", "histogram", "Time taken to render a frame");
+        let mut cumulative = 0;
+        for (le, bucket) in RENDER_BUCKETS.iter().zip(self.render_buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "lights_render_duration_seconds_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "lights_render_duration_seconds_bucket{{le=\"+Inf\"}} {frames}");
+        let sum = self.render_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "lights_render_duration_seconds_sum {sum}");
+        let _ = writeln!(out, "lights_render_duration_seconds_count {frames}");
+
+        header(&mut out, "lights_command_queue_depth", "gauge", "Commands waiting for the lights controller");
+        let _ = writeln!(out, "lights_command_queue_depth {}", gauges.queue_depth);
+
+        header(&mut out, "lights_mode_active", "gauge", "Which lights mode is running");
+        for (mode, active) in &gauges.modes {
+            let _ = writeln!(out, "lights_mode_active{{mode=\"{mode}\"}} {}", *active as u8);
+        }
+
+        if let Some(fps) = gauges.fps {
+            header(&mut out, "lights_frames_per_second", "gauge", "Frames rendered per second over the last second");
+            let _ = writeln!(out, "lights_frames_per_second {fps}");
+        }
+
+        if let Some(power_watts) = gauges.power_watts {
+            header(&mut out, "lights_power_watts", "gauge", "Estimated power drawn by the LEDs");
+            let _ = writeln!(out, "lights_power_watts {power_watts}");
+        }
+
+        header(&mut out, "lights_http_requests_total", "counter", "Web requests by method and status");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "lights_http_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Values that are read when the metrics are rendered
+#[derive(Default)]
+pub struct Gauges {
+    pub queue_depth: usize,
+    /// every mode and whether it is the one running
+    pub modes: Vec<(String, bool)>,
+    /// left out when the lights controller doesn't answer
+    pub power_watts: Option<f64>,
+    /// left out when the lights controller doesn't answer
+    pub fps: Option<f64>,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_histogram_and_labels() {
+        let metrics = Metrics::new();
+        metrics.frame_rendered(Duration::from_micros(1500));
+        metrics.frame_rendered(Duration::from_millis(15));
+        metrics.request("GET", 200);
+        let gauges = Gauges { modes: vec![("solid".into(), true)], ..Default::default() };
+        let text = metrics.render(&gauges);
+        assert!(text.contains("lights_frames_rendered_total 2\n"));
+        assert!(text.contains("lights_render_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("lights_render_duration_seconds_bucket{le=\"0.002\"} 1\n"));
+        assert!(text.contains("lights_render_duration_seconds_bucket{le=\"0.02\"} 2\n"));
+        assert!(text.contains("lights_mode_active{mode=\"solid\"} 1\n"));
+        assert!(text.contains("lights_http_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        // the controller didn't answer
+        assert!(!text.contains("lights_power_watts"));
+
+        let gauges = Gauges { fps: Some(30.0), power_watts: Some(12.5), ..Default::default() };
+        let text = metrics.render(&gauges);
+        assert!(text.contains("lights_frames_per_second 30\n"));
+        assert!(text.contains("lights_power_watts 12.5\n"));
+    }
+}
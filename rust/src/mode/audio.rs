@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::audio::{self, Analysis, Analyzer, AudioSource, CHUNK_SIZE, SAMPLE_RATE};
+use crate::lights::{LedColor, LightsCommand, LightsError, LightsRemote};
+
+use super::{LightsMode, Param, Value, Meta};
+
+/// How the audio is drawn onto the lights
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Effect {
+    /// Light up the strip from the start in proportion to the volume
+    VuMeter,
+    /// Split the strip into bars, one per frequency band
+    Spectrum,
+    /// Flash all the lights on every beat
+    Beat,
+}
+
+/// Effect settings that can change while the audio is playing
+#[derive(Copy, Clone)]
+struct Settings {
+    color: LedColor,
+    /// gain in tenths
+    sensitivity: isize,
+    /// lowest and highest frequency in Hz
+    band: (isize, isize),
+    /// percent of the previous level kept every frame
+    decay: isize,
+    /// number of bars for the spectrum
+    bars: isize,
+}
+
+/// Audio-reactive lights mode
+pub struct AudioMode {
+    remote: LightsRemote,
+    effect: Effect,
+    source: String,
+    settings: Settings,
+    updates: Option<watch::Sender<Settings>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl AudioMode {
+    /// Create an audio mode that draws the given effect
+    pub fn with_effect(remote: LightsRemote, effect: Effect) -> Self {
+        AudioMode {
+            remote,
+            effect,
+            source: AudioSource::Alsa("default".into()).spec(),
+            settings: Settings {
+                color: LedColor { r: 0, g: 96, b: 128 },
+                sensitivity: 20,
+                band: (40, 8000),
+                decay: 85,
+                bars: 8,
+            },
+            updates: None,
+            task: None,
+        }
+    }
+}
+
+impl LightsMode for AudioMode {
+    fn new(remote: LightsRemote) -> Self {
+        AudioMode::with_effect(remote, Effect::VuMeter)
+    }
+
+    fn params(&self) -> Result<Vec<Param>> {
+        let mut params = vec![
+            Param {
+                name: "source".into(),
+                value: Value::Select(self.source.clone()),
+                meta: Some(Meta::Select { options: audio::list_sources() }),
+            },
+            Param {
+                name: "color".into(),
+                value: Value::Color(self.settings.color),
+                meta: Some(Meta::Color),
+            },
+            Param {
+                name: "sensitivity".into(),
+                value: Value::Range(self.settings.sensitivity),
+                meta: Some(Meta::Range { min: 1, max: 100 }),
+            },
+            Param {
+                name: "low".into(),
+                value: Value::Range(self.settings.band.0),
+                meta: Some(Meta::Range { min: 20, max: 20000 }),
+            },
+            Param {
+                name: "high".into(),
+                value: Value::Range(self.settings.band.1),
+                meta: Some(Meta::Range { min: 20, max: 20000 }),
+            },
+            Param {
+                name: "decay".into(),
+                value: Value::Range(self.settings.decay),
+                meta: Some(Meta::Range { min: 0, max: 99 }),
+            },
+        ];
+        if self.effect == Effect::Spectrum {
+            params.push(Param {
+                name: "bars".into(),
+                value: Value::Range(self.settings.bars),
+                meta: Some(Meta::Range { min: 2, max: 32 }),
+            });
+        }
+        Ok(params)
+    }
+
+    fn start(&mut self) -> Result<Vec<Param>> {
+        self.stop()?;
+        let chunks = AudioSource::parse(&self.source)?.start()?;
+        let (updates, settings) = watch::channel(self.settings);
+        self.updates = Some(updates);
+        self.task = Some(tokio::spawn(react(self.remote.clone(), self.effect, chunks, settings)));
+        self.params()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.updates = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        let mut restart = false;
+        for param in params {
+            match (param.name.as_str(), param.value) {
+                ("source", Value::Select(spec)) => {
+                    AudioSource::parse(&spec)?;
+                    restart = self.task.is_some() && spec != self.source;
+                    self.source = spec;
+                },
+                ("color", Value::Color(color)) => self.settings.color = color,
+                ("sensitivity", Value::Range(value)) => self.settings.sensitivity = value.clamp(1, 100),
+                ("low", Value::Range(value)) => self.settings.band.0 = value.clamp(20, 20000),
+                ("high", Value::Range(value)) => self.settings.band.1 = value.clamp(20, 20000),
+                ("decay", Value::Range(value)) => self.settings.decay = value.clamp(0, 99),
+                ("bars", Value::Range(value)) => self.settings.bars = value.clamp(2, 32),
+                (name, _) => return Err(anyhow!("Unknown parameter {name}")),
+            }
+        }
+        if self.settings.band.1 <= self.settings.band.0 {
+            self.settings.band.1 = self.settings.band.0 + 1;
+        }
+        if restart {
+            self.start()?;
+        } else if let Some(updates) = &self.updates {
+            let _ = updates.send(self.settings);
+        }
+        Ok(())
+    }
+}
+
+/// Scale a color by a level between 0 and 1
+fn scale(color: LedColor, level: f32) -> LedColor {
+    let level = level.clamp(0.0, 1.0);
+    LedColor {
+        r: (color.r as f32 * level) as u8,
+        g: (color.g as f32 * level) as u8,
+        b: (color.b as f32 * level) as u8,
+    }
+}
+
+/// Analyze the audio chunks and draw the effect for each one
+async fn react(
+    remote: LightsRemote,
+    effect: Effect,
+    mut chunks: tokio::sync::mpsc::Receiver<audio::Chunk>,
+    updates: watch::Receiver<Settings>,
+) {
+    let count = remote.led_count();
+    let off: LedColor = [0, 0, 0].into();
+    let mut analyzer = Analyzer::new(SAMPLE_RATE, CHUNK_SIZE);
+    // smoothed levels for each bar (a single bar for the vu-meter and beat)
+    let mut levels: Vec<f32> = Vec::new();
+    while let Some(chunk) = chunks.recv().await {
+        let settings = *updates.borrow();
+        if analyzer.sample_rate() != chunk.sample_rate {
+            analyzer = Analyzer::new(chunk.sample_rate, CHUNK_SIZE);
+        }
+        let (low, high) = (settings.band.0 as f32, settings.band.1 as f32);
+        analyzer.set_band(low, high);
+        let analysis: Analysis = analyzer.process(&chunk.samples);
+        let gain = settings.sensitivity as f32 / 10.0;
+        let decay = settings.decay as f32 / 100.0;
+
+        let targets: Vec<f32> = match effect {
+            Effect::VuMeter => vec![analysis.level * gain],
+            Effect::Spectrum => analyzer.bands(&analysis, low, high, settings.bars as usize)
+                .into_iter()
+                .map(|band| band * gain)
+                .collect(),
+            Effect::Beat => vec![if analysis.onset { 1.0 } else { 0.0 }],
+        };
+        levels.resize(targets.len(), 0.0);
+        // jump up straight away but fall off slowly
+        for (level, target) in levels.iter_mut().zip(targets) {
+            *level = target.min(1.0).max(*level * decay);
+        }
+
+        let colors: Vec<LedColor> = match effect {
+            Effect::VuMeter => {
+                let lit = (levels[0] * count as f32) as usize;
+                (0..count)
+                    .map(|i| if i < lit { settings.color } else { off })
+                    .collect()
+            },
+            Effect::Spectrum => {
+                let width = count.div_ceil(levels.len());
+                (0..count)
+                    .map(|i| {
+                        let level = levels[i / width];
+                        if i % width < (level * width as f32) as usize {
+                            scale(settings.color, level.max(0.2))
+                        } else {
+                            off
+                        }
+                    })
+                    .collect()
+            },
+            Effect::Beat => vec![scale(settings.color, levels[0]); count],
+        };
+        if let Err(LightsError::Closed) = remote.send(LightsCommand::Set(colors)).await {
+            return;
+        }
+    }
+    debug!("Audio source ended");
+}
@@ -7,9 +7,22 @@ use crate::lights::LightsRemote;
 
 mod solid;
 
+mod palette;
+
+mod playback;
+
+mod sequence;
+
+mod audio;
+
+mod script;
+
+mod runtime;
+pub use runtime::{ModeRunner, SharedModes};
+
 pub trait LightsMode {
     //
-    fn new(remote: LightsRemote) -> impl LightsMode;
+    fn new(remote: LightsRemote) -> Self where Self: Sized;
 
     // start the lights mode
     fn start(&mut self) -> Result<Vec<Param>>;
@@ -22,4 +35,9 @@ pub trait LightsMode {
 
     // update the parameters for the lights mode
     fn update(&mut self, params: Vec<Param>) -> Result<()>;
-}
\ No newline at end of file
+
+    // the last error from the running lights mode (if any)
+    fn error(&self) -> Option<String> {
+        None
+    }
+}
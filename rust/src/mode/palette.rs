@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::clock;
+use crate::lights::{LightsCommand, LightsError, LightsRemote};
+use crate::palette::{self, DEFAULT_PALETTE};
+
+use super::{LightsMode, Param, Value, Meta};
+
+/// Time between frames while the pattern is moving
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// Settings that can change while the pattern is showing
+#[derive(Clone)]
+struct Settings {
+    palette: String,
+    /// LEDs the palette is spread over before it repeats
+    period: isize,
+    /// LEDs moved per second, negative goes backwards
+    speed: isize,
+}
+
+/// Show a palette as a repeating pattern, optionally moving along the lights
+pub struct PaletteMode {
+    remote: LightsRemote,
+    settings: Settings,
+    updates: Option<watch::Sender<Settings>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LightsMode for PaletteMode {
+    fn new(remote: LightsRemote) -> Self {
+        PaletteMode {
+            remote,
+            settings: Settings { palette: DEFAULT_PALETTE.into(), period: 50, speed: 0 },
+            updates: None,
+            task: None,
+        }
+    }
+
+    fn params(&self) -> Result<Vec<Param>> {
+        Ok(vec![
+            Param {
+                name: "palette".into(),
+                value: Value::Select(self.settings.palette.clone()),
+                meta: Some(Meta::Select { options: palette::names() }),
+            },
+            Param {
+                name: "period".into(),
+                value: Value::Range(self.settings.period),
+                meta: Some(Meta::Range { min: 1, max: self.remote.led_count().max(1) as isize }),
+            },
+            Param {
+                name: "speed".into(),
+                value: Value::Range(self.settings.speed),
+                meta: Some(Meta::Range { min: -100, max: 100 }),
+            },
+        ])
+    }
+
+    fn start(&mut self) -> Result<Vec<Param>> {
+        self.stop()?;
+        let (updates, settings) = watch::channel(self.settings.clone());
+        self.updates = Some(updates);
+        self.task = Some(tokio::spawn(show(self.remote.clone(), settings)));
+        self.params()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.updates = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        for param in params {
+            match (param.name.as_str(), param.value) {
+                ("palette", Value::Select(name)) => {
+                    if palette::get(&name).is_none() {
+                        return Err(anyhow!("Unknown palette {name}"));
+                    }
+                    self.settings.palette = name;
+                },
+                ("period", Value::Range(period)) => self.settings.period = period.max(1),
+                ("speed", Value::Range(speed)) => self.settings.speed = speed.clamp(-100, 100),
+                (name, _) => return Err(anyhow!("Unknown parameter {name}")),
+            }
+        }
+        if let Some(updates) = &self.updates {
+            let _ = updates.send(self.settings.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Send the pattern to the controller, moving it every frame when it has a
+/// speed. The pattern is only worked out again when the settings change
+async fn show(remote: LightsRemote, mut settings: watch::Receiver<Settings>) {
+    let count = remote.led_count();
+    'settings: loop {
+        let current = settings.borrow_and_update().clone();
+        // palettes can be removed while they are showing
+        let Some(palette) = palette::get(&current.palette) else {
+            warn!("Palette {} is gone", current.palette);
+            return;
+        };
+        let period = current.period as usize;
+        let pattern = palette.pattern(period, period);
+        loop {
+            // moved by the animation clock so synced lights stay in step
+            let shift = (clock::seconds() * current.speed as f64) as isize;
+            let colors = (0..count)
+                .map(|i| pattern[(i as isize - shift).rem_euclid(period as isize) as usize])
+                .collect();
+            if let Err(LightsError::Closed) = remote.send(LightsCommand::Set(colors)).await {
+                return;
+            }
+            if current.speed == 0 {
+                if settings.changed().await.is_err() {
+                    return;
+                }
+                continue 'settings;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(FRAME_TIME) => {},
+                res = settings.changed() => match res {
+                    Ok(()) => continue 'settings,
+                    Err(_) => return,
+                },
+            }
+        }
+    }
+}
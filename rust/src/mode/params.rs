@@ -1,9 +1,10 @@
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
 use crate::lights::LedColor;
 
 /// An input or adjustable parameter for a lights mode
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Param{
     /// The parameter name
@@ -16,7 +17,7 @@ pub struct Param{
 }
 
 /// The parameter value that returned by the web-app front-end
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase", tag="type", content="value")]
 pub enum Value{
     /// A sliding on-off toggle
@@ -27,10 +28,12 @@ pub enum Value{
     Range(isize),
     /// A color selector
     Color(LedColor),
+    /// A drop-down that selects one of a list of options
+    Select(String),
 }
 
 /// The parameters metadata used by the front-end to render the widget
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase", untagged)]
 pub enum Meta {
     /// A sliding on-off toggle
@@ -41,6 +44,8 @@ pub enum Meta {
     Range{min: isize, max: isize},
     /// A color selector
     Color,
+    /// A drop-down that selects one of a list of options
+    Select{options: Vec<String>},
 }
 
 #[cfg(test)]
@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+
+use crate::lights::{LightsCommand, LightsError, LightsRemote};
+use crate::recording::{self, FrameReader, RECORDINGS_DIR};
+
+use super::{LightsMode, Param, Value, Meta};
+
+/// Playback settings that can change while a recording is playing
+#[derive(Copy, Clone)]
+struct Settings {
+    looping: bool,
+    /// playback speed in percent
+    speed: isize,
+}
+
+/// Play back a recording made by the lights controller at its original timing
+pub struct PlaybackMode {
+    remote: LightsRemote,
+    recording: String,
+    settings: Settings,
+    updates: Option<watch::Sender<Settings>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LightsMode for PlaybackMode {
+    fn new(remote: LightsRemote) -> Self {
+        let recording = recording::list_recordings().into_iter().next().unwrap_or_default();
+        PlaybackMode {
+            remote,
+            recording,
+            settings: Settings { looping: true, speed: 100 },
+            updates: None,
+            task: None,
+        }
+    }
+
+    fn params(&self) -> Result<Vec<Param>> {
+        Ok(vec![
+            Param {
+                name: "recording".into(),
+                value: Value::Select(self.recording.clone()),
+                meta: Some(Meta::Select { options: recording::list_recordings() }),
+            },
+            Param {
+                name: "loop".into(),
+                value: Value::Toggle(self.settings.looping),
+                meta: Some(Meta::Toggle { on: "Loop".into(), off: "Once".into() }),
+            },
+            Param {
+                name: "speed".into(),
+                value: Value::Range(self.settings.speed),
+                meta: Some(Meta::Range { min: 10, max: 400 }),
+            },
+        ])
+    }
+
+    fn start(&mut self) -> Result<Vec<Param>> {
+        self.stop()?;
+        if self.recording.is_empty() {
+            return Err(anyhow!("No recording selected"));
+        }
+        let path = PathBuf::from(RECORDINGS_DIR).join(&self.recording);
+        let bytes = std::fs::read(&path)?;
+        // check the header before handing off to the task
+        FrameReader::new(bytes.as_slice())?;
+        let (updates, settings) = watch::channel(self.settings);
+        self.updates = Some(updates);
+        self.task = Some(tokio::spawn(play(self.remote.clone(), bytes, settings)));
+        self.params()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.updates = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        let mut restart = false;
+        for param in params {
+            match (param.name.as_str(), param.value) {
+                ("recording", Value::Select(name)) => {
+                    if !recording::list_recordings().contains(&name) {
+                        return Err(anyhow!("Unknown recording {name}"));
+                    }
+                    restart = self.task.is_some() && name != self.recording;
+                    self.recording = name;
+                },
+                ("loop", Value::Toggle(looping)) => self.settings.looping = looping,
+                ("speed", Value::Range(speed)) => self.settings.speed = speed.clamp(10, 400),
+                (name, _) => return Err(anyhow!("Unknown parameter {name}")),
+            }
+        }
+        if restart {
+            self.start()?;
+        } else if let Some(updates) = &self.updates {
+            let _ = updates.send(self.settings);
+        }
+        Ok(())
+    }
+}
+
+/// Send the recorded frames to the controller at the recorded times
+async fn play(remote: LightsRemote, bytes: Vec<u8>, mut settings: watch::Receiver<Settings>) {
+    loop {
+        let reader = match FrameReader::new(bytes.as_slice()) {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!("Unable to read recording: {e:?}");
+                return;
+            },
+        };
+        // position in the recording and the wall-clock time it was reached,
+        // re-anchored whenever the speed changes
+        let mut anchor = (Duration::ZERO, Instant::now());
+        let mut speed = settings.borrow().speed;
+        // whether the recording turned the lights off
+        let mut off = false;
+        for frame in reader {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Error reading recording: {e:?}");
+                    return;
+                },
+            };
+            loop {
+                let offset = frame.timestamp.saturating_sub(anchor.0) * 100 / speed as u32;
+                tokio::select! {
+                    _ = tokio::time::sleep_until(anchor.1 + offset) => break,
+                    res = settings.changed() => {
+                        if res.is_err() {
+                            return;
+                        }
+                        let now = Instant::now();
+                        let played = now.saturating_duration_since(anchor.1) * speed as u32 / 100;
+                        anchor = (anchor.0 + played, now);
+                        speed = settings.borrow().speed;
+                    },
+                }
+            }
+            if !frame.on {
+                if !off {
+                    if let Err(LightsError::Closed) = remote.send(LightsCommand::Off).await {
+                        return;
+                    }
+                    off = true;
+                }
+                continue;
+            }
+            if off {
+                if let Err(LightsError::Closed) = remote.send(LightsCommand::On).await {
+                    return;
+                }
+                off = false;
+            }
+            // recordings made with a different number of LEDs are cut or padded
+            let mut colors = frame.colors;
+            colors.resize(remote.led_count(), [0, 0, 0].into());
+            if let Err(LightsError::Closed) = remote.send(LightsCommand::Set(colors)).await {
+                return;
+            }
+        }
+        if !settings.borrow().looping {
+            trace!("Recording finished");
+            return;
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::sync::Mutex;
+
+use crate::events::{Event, EVENTS};
+use crate::lights::LightsRemote;
+
+use super::{LightsMode, Param};
+use super::solid::SolidMode;
+use super::palette::PaletteMode;
+use super::playback::PlaybackMode;
+use super::sequence::SequenceMode;
+use super::audio::{AudioMode, Effect};
+use super::script::ScriptMode;
+
+/// Mode runner that is shared between the web-app handlers
+pub type SharedModes = Arc<Mutex<ModeRunner>>;
+
+/// Names of the modes that can be selected
+const MODES: &[&str] = &["solid", "palette", "playback", "sequence", "vu-meter", "spectrum", "beat", "script"];
+
+/// Create a new lights mode by name
+fn make_mode(name: &str, remote: LightsRemote) -> Option<Box<dyn LightsMode + Send>> {
+    match name {
+        "solid" => Some(Box::new(SolidMode::new(remote))),
+        "palette" => Some(Box::new(PaletteMode::new(remote))),
+        "playback" => Some(Box::new(PlaybackMode::new(remote))),
+        "sequence" => Some(Box::new(SequenceMode::new(remote))),
+        "vu-meter" => Some(Box::new(AudioMode::with_effect(remote, Effect::VuMeter))),
+        "spectrum" => Some(Box::new(AudioMode::with_effect(remote, Effect::Spectrum))),
+        "beat" => Some(Box::new(AudioMode::with_effect(remote, Effect::Beat))),
+        "script" => Some(Box::new(ScriptMode::new(remote))),
+        _ => None,
+    }
+}
+
+/// Owns the active lights mode and switches between modes
+pub struct ModeRunner {
+    remote: LightsRemote,
+    active: Option<(String, Box<dyn LightsMode + Send>)>,
+}
+
+impl ModeRunner {
+    pub fn new(remote: LightsRemote) -> Self {
+        ModeRunner { remote, active: None }
+    }
+
+    /// Create a mode runner that can be shared between tasks
+    pub fn shared(remote: LightsRemote) -> SharedModes {
+        Arc::new(Mutex::new(ModeRunner::new(remote)))
+    }
+
+    /// The names of all the available modes
+    pub fn available(&self) -> Vec<String> {
+        MODES.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// The name of the running mode
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    /// Stop the running mode and start a new one
+    pub fn select(&mut self, name: &str) -> Result<Vec<Param>> {
+        let mut mode = make_mode(name, self.remote.clone())
+            .ok_or_else(|| anyhow!("Unknown mode {name}"))?;
+        self.stop()?;
+        debug!("Starting lights mode {name}");
+        let params = mode.start()?;
+        self.active = Some((name.to_string(), mode));
+        EVENTS.send(Event::ModeChanged { mode: Some(name.to_string()) });
+        Ok(params)
+    }
+
+    /// The parameters of the running mode
+    pub fn params(&self) -> Result<Vec<Param>> {
+        match &self.active {
+            Some((_, mode)) => mode.params(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Update the parameters of the running mode
+    pub fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        match &mut self.active {
+            Some((_, mode)) => {
+                mode.update(params.clone())?;
+                EVENTS.send(Event::ParamsUpdated { params });
+                Ok(())
+            },
+            None => Err(anyhow!("No lights mode is running")),
+        }
+    }
+
+    /// The last error from the running mode
+    pub fn error(&self) -> Option<String> {
+        self.active.as_ref().and_then(|(_, mode)| mode.error())
+    }
+
+    /// Stop the running mode
+    pub fn stop(&mut self) -> Result<()> {
+        if let Some((name, mut mode)) = self.active.take() {
+            debug!("Stopping lights mode {name}");
+            mode.stop()?;
+            EVENTS.send(Event::ModeChanged { mode: None });
+        }
+        Ok(())
+    }
+}
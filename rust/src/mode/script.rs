@@ -0,0 +1,146 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::task::JoinHandle;
+
+use crate::clock;
+use crate::lights::{LedColor, LightsCommand, LightsError, LightsRemote};
+use crate::script::{self, Script};
+
+use super::{LightsMode, Param, Value, Meta};
+
+/// Time between frames computed by the script
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// Run a user-defined effect script
+pub struct ScriptMode {
+    remote: LightsRemote,
+    name: String,
+    script: Option<Arc<Mutex<Script>>>,
+    error: Arc<Mutex<Option<String>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ScriptMode {
+    /// Load the selected script, keeping the error for the front-end
+    fn load(&mut self) -> Result<()> {
+        *self.error.lock().unwrap() = None;
+        let name = self.name.clone();
+        match off_async(move || Script::load(&name)) {
+            Ok(script) => {
+                self.script = Some(Arc::new(Mutex::new(script)));
+                Ok(())
+            },
+            Err(e) => {
+                self.script = None;
+                *self.error.lock().unwrap() = Some(format!("{e:#}"));
+                Err(e)
+            },
+        }
+    }
+}
+
+impl LightsMode for ScriptMode {
+    fn new(remote: LightsRemote) -> Self {
+        let name = script::list_scripts().into_iter().next().unwrap_or_default();
+        ScriptMode { remote, name, script: None, error: Arc::new(Mutex::new(None)), task: None }
+    }
+
+    fn params(&self) -> Result<Vec<Param>> {
+        let mut params = vec![Param {
+            name: "script".into(),
+            value: Value::Select(self.name.clone()),
+            meta: Some(Meta::Select { options: script::list_scripts() }),
+        }];
+        if let Some(script) = &self.script {
+            params.append(&mut off_async(|| script.lock().unwrap().params())?);
+        }
+        Ok(params)
+    }
+
+    fn start(&mut self) -> Result<Vec<Param>> {
+        self.stop()?;
+        if self.name.is_empty() {
+            return Err(anyhow!("No script selected"));
+        }
+        self.load()?;
+        if let Some(script) = &self.script {
+            let task = run(self.remote.clone(), script.clone(), self.error.clone());
+            self.task = Some(tokio::spawn(task));
+        }
+        self.params()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        for param in params {
+            match (param.name.as_str(), &param.value) {
+                ("script", Value::Select(name)) => {
+                    if !script::list_scripts().contains(name) {
+                        return Err(anyhow!("Unknown script {name}"));
+                    }
+                    self.name = name.clone();
+                    if self.task.is_some() {
+                        self.start()?;
+                    } else {
+                        self.load()?;
+                    }
+                },
+                _ => match &self.script {
+                    Some(script) => off_async(|| script.lock().unwrap().set(&param))?,
+                    None => return Err(anyhow!("Unknown parameter {}", param.name)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+/// Run a call into the script engine (or one waiting for a frame to finish)
+/// from the mode methods, which aren't async. The runtime hands the other tasks
+/// of this thread to a new one meanwhile, so the web server isn't held up
+fn off_async<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Compute and send frames from the script until it fails, the script runs on
+/// the blocking threads so a slow one can't hold up the web server
+async fn run(remote: LightsRemote, script: Arc<Mutex<Script>>, error: Arc<Mutex<Option<String>>>) {
+    let mut pixels: Vec<LedColor> = vec![[0, 0, 0].into(); remote.led_count()];
+    let mut interval = tokio::time::interval(FRAME_TIME);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let t = clock::seconds();
+        let script = script.clone();
+        let res = tokio::task::spawn_blocking(move || script.lock().unwrap().frame(t, &pixels)).await;
+        match res.map_err(anyhow::Error::from).and_then(|res| res) {
+            Ok(frame) => pixels = frame,
+            Err(e) => {
+                error!("Script stopped: {e:#}");
+                *error.lock().unwrap() = Some(format!("{e:#}"));
+                return;
+            },
+        }
+        if let Err(LightsError::Closed) = remote.send(LightsCommand::Set(pixels.clone())).await {
+            return;
+        }
+    }
+}
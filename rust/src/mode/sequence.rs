@@ -0,0 +1,327 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::{
+    io::AsyncReadExt,
+    process::{Child, ChildStderr, Command},
+    sync::watch,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::fseq::{self, Sequence, SEQUENCES_DIR};
+use crate::lights::{LedColor, LightsCommand, LightsError, LightsRemote};
+use crate::metrics::METRICS;
+
+use super::{LightsMode, Param, Value, Meta};
+
+/// Command used to play the audio for a sequence
+const AUDIO_PLAYER: &str = "ffplay";
+/// How far the frames can be off from the audio before they are moved
+const MAX_DRIFT: Duration = Duration::from_millis(40);
+
+/// Playback settings that can change while a sequence is playing
+#[derive(Copy, Clone)]
+struct Settings {
+    looping: bool,
+    /// first channel (zero based) mapped onto the first LED
+    offset: usize,
+    audio: bool,
+    /// bumped for every seek so the task knows to jump
+    seek: (u64, Duration),
+}
+
+/// Play an xLights FSEQ sequence onto the lights
+pub struct SequenceMode {
+    remote: LightsRemote,
+    sequence: String,
+    settings: Settings,
+    /// length of the selected sequence
+    duration: Duration,
+    updates: Option<watch::Sender<Settings>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LightsMode for SequenceMode {
+    fn new(remote: LightsRemote) -> Self {
+        let sequence = fseq::list_sequences().into_iter().next().unwrap_or_default();
+        SequenceMode {
+            remote,
+            sequence,
+            settings: Settings {
+                looping: true,
+                offset: 0,
+                audio: false,
+                seek: (0, Duration::ZERO),
+            },
+            duration: Duration::ZERO,
+            updates: None,
+            task: None,
+        }
+    }
+
+    fn params(&self) -> Result<Vec<Param>> {
+        Ok(vec![
+            Param {
+                name: "sequence".into(),
+                value: Value::Select(self.sequence.clone()),
+                meta: Some(Meta::Select { options: fseq::list_sequences() }),
+            },
+            Param {
+                name: "loop".into(),
+                value: Value::Toggle(self.settings.looping),
+                meta: Some(Meta::Toggle { on: "Loop".into(), off: "Once".into() }),
+            },
+            Param {
+                name: "audio".into(),
+                value: Value::Toggle(self.settings.audio),
+                meta: Some(Meta::Toggle { on: "Audio".into(), off: "Silent".into() }),
+            },
+            Param {
+                name: "offset".into(),
+                value: Value::Range(self.settings.offset as isize),
+                meta: Some(Meta::Range { min: 0, max: 65535 }),
+            },
+            Param {
+                name: "position".into(),
+                value: Value::Range(self.settings.seek.1.as_secs() as isize),
+                meta: Some(Meta::Range { min: 0, max: self.duration.as_secs() as isize }),
+            },
+        ])
+    }
+
+    fn start(&mut self) -> Result<Vec<Param>> {
+        self.stop()?;
+        if self.sequence.is_empty() {
+            return Err(anyhow!("No sequence selected"));
+        }
+        let sequence = Sequence::open(PathBuf::from(SEQUENCES_DIR).join(&self.sequence))?;
+        self.duration = sequence.duration();
+        let (updates, settings) = watch::channel(self.settings);
+        self.updates = Some(updates);
+        self.task = Some(tokio::spawn(play(self.remote.clone(), sequence, settings)));
+        self.params()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.updates = None;
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        let mut restart = false;
+        for param in params {
+            match (param.name.as_str(), param.value) {
+                ("sequence", Value::Select(name)) => {
+                    if !fseq::list_sequences().contains(&name) {
+                        return Err(anyhow!("Unknown sequence {name}"));
+                    }
+                    restart = self.task.is_some() && name != self.sequence;
+                    self.sequence = name;
+                    self.settings.seek = (self.settings.seek.0 + 1, Duration::ZERO);
+                },
+                ("loop", Value::Toggle(looping)) => self.settings.looping = looping,
+                ("audio", Value::Toggle(audio)) => self.settings.audio = audio,
+                ("offset", Value::Range(offset)) => self.settings.offset = offset.max(0) as usize,
+                ("position", Value::Range(secs)) => {
+                    let position = Duration::from_secs(secs.max(0) as u64).min(self.duration);
+                    self.settings.seek = (self.settings.seek.0 + 1, position);
+                },
+                (name, _) => return Err(anyhow!("Unknown parameter {name}")),
+            }
+        }
+        if restart {
+            self.start()?;
+        } else if let Some(updates) = &self.updates {
+            let _ = updates.send(self.settings);
+        }
+        Ok(())
+    }
+}
+
+/// The player for the audio of a sequence, killed when dropped
+struct AudioPlayer {
+    _child: Child,
+    /// the latest position the player reported and when it did
+    position: watch::Receiver<Option<(Instant, Duration)>>,
+}
+
+impl AudioPlayer {
+    /// The wall-clock time of frame zero according to the player, when it
+    /// has reported a new position since the last call
+    fn origin(&mut self) -> Option<Instant> {
+        if !self.position.has_changed().unwrap_or(false) {
+            return None;
+        }
+        let (at, position) = (*self.position.borrow_and_update())?;
+        at.checked_sub(position)
+    }
+}
+
+/// Start playing the audio for the sequence from a position
+fn play_audio(sequence: &Sequence, position: Duration) -> Option<AudioPlayer> {
+    // the media file is usually an absolute path on the machine that made the
+    // sequence, so look for a file with the same name next to the sequences
+    let name = sequence.media_file()?.rsplit(['/', '\\']).next()?;
+    let path = PathBuf::from(SEQUENCES_DIR).join(name);
+    if !path.is_file() {
+        warn!("Audio file {} for sequence not found", path.display());
+        return None;
+    }
+    // -stats prints the player's clock to stderr even with logging off
+    let res = Command::new(AUDIO_PLAYER)
+        .args(["-nodisp", "-autoexit", "-loglevel", "quiet", "-stats", "-ss"])
+        .arg(format!("{:.3}", position.as_secs_f64()))
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    match res {
+        Ok(mut child) => {
+            let (sender, position) = watch::channel(None);
+            if let Some(stderr) = child.stderr.take() {
+                tokio::spawn(follow_player(stderr, sender));
+            }
+            Some(AudioPlayer { _child: child, position })
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error!("Unable to play the sequence audio, {AUDIO_PLAYER} isn't installed (it comes with ffmpeg)");
+            None
+        },
+        Err(e) => {
+            error!("Unable to start audio player: {e:?}");
+            None
+        },
+    }
+}
+
+/// Read the status lines of the player until it exits, passing on the
+/// position in each one
+async fn follow_player(mut stderr: ChildStderr, sender: watch::Sender<Option<(Instant, Duration)>>) {
+    let mut buf = [0u8; 512];
+    let mut line = String::new();
+    loop {
+        let read = match stderr.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        for c in String::from_utf8_lossy(&buf[..read]).chars() {
+            if c == '\r' || c == '\n' {
+                if let Some(position) = player_position(&line) {
+                    sender.send_replace(Some((Instant::now(), position)));
+                }
+                line.clear();
+            } else {
+                line.push(c);
+            }
+        }
+    }
+}
+
+/// Get the position from one of the player's status lines, which start with
+/// the clock in seconds, like `  12.34 M-A:  0.000 fd=   0 aq=   22KB ...`
+fn player_position(line: &str) -> Option<Duration> {
+    let secs: f64 = line.split_whitespace().next()?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// The wall-clock time playback would have started to be at `position` now
+fn started_at(position: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_sub(position).unwrap_or(now)
+}
+
+/// Send the sequence frames to the controller at the sequence frame rate
+///
+/// While the audio plays the frames follow the position the player reports,
+/// moving on (or waiting) whenever they get more than `MAX_DRIFT` away from it
+async fn play(remote: LightsRemote, mut sequence: Sequence, mut settings: watch::Receiver<Settings>) {
+    let step = sequence.step();
+    let frames = sequence.frame_count();
+    let mut channels = vec![0u8; remote.led_count() * 3];
+    let mut current = *settings.borrow();
+    // the wall-clock time of frame zero, shifted on every seek
+    let mut origin = started_at(current.seek.1);
+    let mut audio = if current.audio { play_audio(&sequence, current.seek.1) } else { None };
+    let mut frame = (current.seek.1.as_millis() / step.as_millis()) as usize;
+    loop {
+        if frame >= frames {
+            if !current.looping {
+                trace!("Sequence finished");
+                return;
+            }
+            frame = 0;
+            origin = Instant::now();
+            if current.audio {
+                audio = play_audio(&sequence, Duration::ZERO);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(origin + step * frame as u32) => {
+                if let Err(e) = sequence.read_channels(frame, current.offset, &mut channels) {
+                    error!("Error reading sequence: {e:?}");
+                    return;
+                }
+                let colors: Vec<LedColor> = channels
+                    .chunks_exact(3)
+                    .map(|rgb| [rgb[0], rgb[1], rgb[2]].into())
+                    .collect();
+                if let Err(LightsError::Closed) = remote.send(LightsCommand::Set(colors)).await {
+                    return;
+                }
+                if let Some(target) = audio.as_mut().and_then(AudioPlayer::origin) {
+                    let drift = if target > origin { target - origin } else { origin - target };
+                    if drift > MAX_DRIFT {
+                        debug!("Moving the sequence by {drift:?} to follow the audio");
+                        origin = target;
+                    }
+                }
+                // skip any frames we fell behind on to stay in time
+                let elapsed = Instant::now().saturating_duration_since(origin);
+                let next = (frame + 1).max((elapsed.as_millis() / step.as_millis()) as usize);
+                METRICS.frames_dropped((next - frame - 1) as u64);
+                frame = next;
+            },
+            res = settings.changed() => {
+                if res.is_err() {
+                    return;
+                }
+                let new = *settings.borrow();
+                let seeked = new.seek.0 != current.seek.0;
+                if seeked {
+                    origin = started_at(new.seek.1);
+                    frame = (new.seek.1.as_millis() / step.as_millis()) as usize;
+                }
+                if new.audio && (seeked || !current.audio) {
+                    let position = Instant::now().saturating_duration_since(origin);
+                    audio = play_audio(&sequence, position);
+                } else if !new.audio {
+                    audio = None;
+                }
+                current = new;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_player_position() {
+        let line = "  12.34 M-A:  0.000 fd=   0 aq=   22KB vq=    0KB sq=    0B f=0/0   ";
+        assert_eq!(player_position(line), Some(Duration::from_millis(12340)));
+        assert_eq!(player_position("    nan M-A:    nan fd=   0 aq=    0KB"), None);
+        assert_eq!(player_position(" -0.50 M-A:  0.000"), None);
+        assert_eq!(player_position(""), None);
+    }
+}
@@ -1,11 +1,11 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio::task::JoinHandle;
 
-use crate::lights::{LedColor, LightsRemote};
+use crate::lights::{LedColor, LightsCommand, LightsRemote};
 
 use super::{LightsMode, Param, Value, Meta};
 
-struct SolidMode {
+pub struct SolidMode {
     remote: LightsRemote,
     color: LedColor,
     task: Option<JoinHandle<()>>,
@@ -24,22 +24,31 @@ impl LightsMode for SolidMode {
     }
 
     fn start(&mut self) -> Result<Vec<Param>> {
+        let remote = self.remote.clone();
+        let color = self.color;
         self.task = Some(tokio::spawn( async move {
-
+            let _ = remote.send(LightsCommand::Fill(color)).await;
         }));
         self.params()
     }
 
     fn stop(&mut self) -> Result<()> {
         if let Some(task) = self.task.take() {
-            // send stop command
-            // wait for task to finish
-            // send off-command
+            task.abort();
         }
         Ok(())
     }
 
     fn update(&mut self, params: Vec<Param>) -> Result<()> {
+        for param in params {
+            match (param.name.as_str(), param.value) {
+                ("color", Value::Color(color)) => self.color = color,
+                (name, _) => return Err(anyhow!("Unknown parameter {name}")),
+            }
+        }
+        if self.task.is_some() {
+            self.start()?;
+        }
         Ok(())
     }
 }
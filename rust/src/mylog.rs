@@ -1,22 +1,282 @@
+//! Logging to stderr, syslog, the systemd journal and rotating files
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result};
 use syslog::{Facility, Formatter3164, BasicLogger};
-use log::LevelFilter;
-
-pub fn init_log(log_level: LevelFilter) -> Result<()> {
-    let formatter = Formatter3164 {
-        facility: Facility::LOG_USER,
-        hostname: None,
-        process: "lights".into(),
-        pid: 0,
-    };
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::config::{LogConfig, LogSink};
 
-    let logger = syslog::unix(formatter)
-    .context("Failed to connect to syslog")?;
+/// Socket for the journal's native protocol
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// Name the messages are logged under
+const IDENTIFIER: &str = "lights";
 
-    log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
+/// Set up logging to the configured sinks, falling back to stderr when none
+/// of them can be opened
+pub fn init_log(config: &LogConfig) -> Result<()> {
+    let mut sinks = Vec::new();
+    for sink in config.sinks.iter() {
+        match Sink::open(sink) {
+            Ok(sink) => sinks.push(Mutex::new(sink)),
+            Err(e) => eprintln!("Unable to log to {sink:?}: {e:?}"),
+        }
+    }
+    if sinks.is_empty() {
+        sinks.push(Mutex::new(Sink::Stderr));
+    }
+
+    // longest module paths first so the most specific filter wins
+    let mut modules: Vec<(String, LevelFilter)> = config.modules.clone().into_iter().collect();
+    modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+    let max_level = modules.iter().map(|(_, level)| *level).fold(config.level, Ord::max);
+
+    let logger = Logger { level: config.level, modules, json: config.json, sinks };
+    log::set_boxed_logger(Box::new(logger))
     .context("Failed to initialize logging")?;
 
-    log::set_max_level(log_level);
+    log::set_max_level(max_level);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+enum Sink {
+    Stderr,
+    Syslog(BasicLogger),
+    Journald(UnixDatagram),
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn open(sink: &LogSink) -> Result<Self> {
+        match sink {
+            LogSink::Stderr => Ok(Sink::Stderr),
+            LogSink::Syslog => {
+                let formatter = Formatter3164 {
+                    facility: Facility::LOG_USER,
+                    hostname: None,
+                    process: IDENTIFIER.into(),
+                    pid: 0,
+                };
+                let logger = syslog::unix(formatter)
+                .context("Failed to connect to syslog")?;
+                Ok(Sink::Syslog(BasicLogger::new(logger)))
+            },
+            LogSink::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNAL_SOCKET).context("Failed to connect to the journal")?;
+                Ok(Sink::Journald(socket))
+            },
+            LogSink::File { path, max_size, keep } => {
+                Ok(Sink::File(RotatingFile::open(path.clone(), *max_size, *keep)?))
+            },
+        }
+    }
+
+    fn write(&mut self, record: &Record, line: &str) -> Result<()> {
+        match self {
+            Sink::Stderr => writeln!(std::io::stderr(), "{line}")?,
+            // syslog adds its own time and level, so only the message goes
+            Sink::Syslog(logger) => logger.log(&Record::builder()
+                .args(format_args!("{line}"))
+                .level(record.level())
+                .target(record.target())
+                .build()),
+            Sink::Journald(socket) => {
+                socket.send(&journal_entry(record))?;
+            },
+            Sink::File(file) => file.write_line(line)?,
+        }
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    json: bool,
+    sinks: Vec<Mutex<Sink>>,
+}
+
+impl Logger {
+    /// The level for a module, from the most specific filter that matches it
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(module, _)| {
+                target == module
+                    || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = timestamp(SystemTime::now());
+        for sink in self.sinks.iter() {
+            let mut sink = sink.lock().unwrap();
+            let line = match (&*sink, self.json) {
+                (_, true) => json!({
+                    "time": time,
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                }).to_string(),
+                (Sink::Syslog(_), false) => record.args().to_string(),
+                _ => format!("{time} {:<5} {}: {}", record.level(), record.target(), record.args()),
+            };
+            if let Err(e) = sink.write(record, &line) {
+                eprintln!("Failed to write log message: {e:?}");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for sink in self.sinks.iter() {
+            if let Sink::File(file) = &mut *sink.lock().unwrap() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+/// A log file that is moved to `<path>.1` (and so on) when it gets too big
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_size, keep, file, size })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(numbered(n), numbered(n + 1));
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// A message in the journal's native format
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let priority = match record.level() {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let mut entry = Vec::new();
+    for (key, value) in [
+        ("MESSAGE", record.args().to_string()),
+        ("PRIORITY", priority.to_string()),
+        ("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+        ("TARGET", record.target().to_string()),
+    ] {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // values with newlines are sent with their length instead of `=`
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// An RFC 3339 UTC timestamp with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600, rem / 60 % 60, rem % 60, since.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_734_567_890_123);
+        assert_eq!(timestamp(time), "2024-12-19T00:24:50.123Z");
+    }
+
+    #[test]
+    fn module_filters() {
+        let logger = Logger {
+            level: LevelFilter::Info,
+            modules: vec![
+                ("lights_app::webapp::auth".into(), LevelFilter::Trace),
+                ("lights_app::webapp".into(), LevelFilter::Warn),
+            ],
+            json: false,
+            sinks: Vec::new(),
+        };
+        assert_eq!(logger.level_for("lights_app::webapp::auth"), LevelFilter::Trace);
+        assert_eq!(logger.level_for("lights_app::webapp::modes"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("lights_app::webapp_extra"), LevelFilter::Info);
+        assert_eq!(logger.level_for("axum"), LevelFilter::Info);
+    }
+}
@@ -0,0 +1,255 @@
+//! Named palettes of colors that modes can sample from
+//!
+//! A palette is a list of color stops from 0 to 1, and the colors between the
+//! stops are blended in RGB or HSV. There are a few built-in palettes, and
+//! the ones added through the web-app are kept in the saved settings.
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use anyhow::{bail, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::lights::LedColor;
+use crate::saved::Saved;
+
+/// The palette the lights show when they start
+pub const DEFAULT_PALETTE: &str = "classic-christmas";
+
+/// The palettes that are always there
+static BUILTIN: LazyLock<BTreeMap<String, Arc<Palette>>> = LazyLock::new(|| {
+    let palette = |name: &str, colors: &[[u8; 3]], interpolation| {
+        (name.to_string(), Arc::new(Palette::even(colors, interpolation)))
+    };
+    BTreeMap::from([
+        palette(DEFAULT_PALETTE, &[[128, 0, 0], [0, 128, 0], [96, 96, 64]], Interpolation::Rgb),
+        palette("ice", &[[0, 32, 128], [64, 160, 255], [200, 230, 255]], Interpolation::Rgb),
+        palette("candy-cane", &[[255, 0, 0], [255, 255, 255]], Interpolation::Rgb),
+        palette("warm-white", &[[255, 147, 41], [255, 197, 143]], Interpolation::Rgb),
+        palette("rainbow", &[[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 0, 0]], Interpolation::Hsv),
+    ])
+});
+
+/// The palettes added through the web-app, loaded on first use
+static USER_PALETTES: LazyLock<RwLock<BTreeMap<String, Arc<Palette>>>> = LazyLock::new(|| {
+    let palettes = Saved::load().palettes;
+    RwLock::new(palettes.into_iter().map(|(name, palette)| (name, Arc::new(palette))).collect())
+});
+
+/// How the colors between two stops are blended
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    /// straight between the red, green and blue values
+    #[default]
+    Rgb,
+    /// around the color wheel, the short way
+    Hsv,
+}
+
+/// A color at a position in the palette
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Stop {
+    /// from 0 to 1
+    pub position: f64,
+    pub color: LedColor,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Palette {
+    /// the stops in order of position
+    pub stops: Vec<Stop>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+impl Palette {
+    /// Colors spread evenly over the palette
+    pub fn even(colors: &[[u8; 3]], interpolation: Interpolation) -> Self {
+        let last = colors.len().saturating_sub(1).max(1) as f64;
+        let stops = colors.iter()
+            .enumerate()
+            .map(|(i, color)| Stop { position: i as f64 / last, color: (*color).into() })
+            .collect();
+        Palette { stops, interpolation }
+    }
+
+    /// Check the stops are usable
+    pub fn validate(&self) -> Result<()> {
+        if self.stops.is_empty() {
+            bail!("A palette needs at least one color");
+        }
+        if self.stops.iter().any(|stop| !(0.0..=1.0).contains(&stop.position)) {
+            bail!("Stop positions have to be from 0 to 1");
+        }
+        if self.stops.windows(2).any(|pair| pair[0].position > pair[1].position) {
+            bail!("Stops have to be in order of position");
+        }
+        Ok(())
+    }
+
+    /// The color at `t` (from 0 to 1) in the palette
+    pub fn sample(&self, t: f64) -> LedColor {
+        let t = t.clamp(0.0, 1.0);
+        let (Some(first), Some(last)) = (self.stops.first(), self.stops.last()) else {
+            return [0, 0, 0].into();
+        };
+        if t <= first.position {
+            return first.color;
+        }
+        let Some(i) = self.stops.iter().position(|stop| stop.position >= t) else {
+            return last.color;
+        };
+        let (below, above) = (self.stops[i - 1], self.stops[i]);
+        let span = above.position - below.position;
+        let f = if span > 0.0 { (t - below.position) / span } else { 1.0 };
+        match self.interpolation {
+            Interpolation::Rgb => {
+                let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * f).round() as u8;
+                LedColor {
+                    r: mix(below.color.r, above.color.r),
+                    g: mix(below.color.g, above.color.g),
+                    b: mix(below.color.b, above.color.b),
+                }
+            },
+            Interpolation::Hsv => {
+                let (h1, s1, v1) = to_hsv(below.color);
+                let (h2, s2, v2) = to_hsv(above.color);
+                // go around the wheel the short way
+                let mut dh = h2 - h1;
+                if dh > 180.0 {
+                    dh -= 360.0;
+                } else if dh < -180.0 {
+                    dh += 360.0;
+                }
+                from_hsv(h1 + dh * f, s1 + (s2 - s1) * f, v1 + (v2 - v1) * f)
+            },
+        }
+    }
+
+    /// `count` colors, going through the whole palette every `period` LEDs
+    ///
+    /// The first and last LED of each repeat get the two ends of the palette,
+    /// so a period the same as the number of stops gives exactly the stop
+    /// colors.
+    pub fn pattern(&self, count: usize, period: usize) -> Vec<LedColor> {
+        let steps = period.saturating_sub(1).max(1) as f64;
+        (0..count)
+            .map(|i| self.sample((i % period.max(1)) as f64 / steps))
+            .collect()
+    }
+}
+
+/// Hue in degrees, saturation and value from 0 to 1
+fn to_hsv(color: LedColor) -> (f64, f64, f64) {
+    let (r, g, b) = (color.r as f64 / 255.0, color.g as f64 / 255.0, color.b as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    (h, s, max)
+}
+
+fn from_hsv(h: f64, s: f64, v: f64) -> LedColor {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    let byte = |v: f64| ((v + m).clamp(0.0, 1.0) * 255.0).round() as u8;
+    LedColor { r: byte(r), g: byte(g), b: byte(b) }
+}
+
+/// A palette by name, built-in or added
+pub fn get(name: &str) -> Option<Arc<Palette>> {
+    BUILTIN.get(name).cloned().or_else(|| USER_PALETTES.read().unwrap().get(name).cloned())
+}
+
+/// Every palette by name
+pub fn all() -> BTreeMap<String, Palette> {
+    let user = USER_PALETTES.read().unwrap();
+    user.iter()
+        .chain(BUILTIN.iter())
+        .map(|(name, palette)| (name.clone(), Palette::clone(palette)))
+        .collect()
+}
+
+/// The names of every palette
+pub fn names() -> Vec<String> {
+    all().into_keys().collect()
+}
+
+/// Save the added palettes, and only use them once they are saved
+fn save(palettes: &mut BTreeMap<String, Arc<Palette>>, changed: BTreeMap<String, Arc<Palette>>) -> Result<()> {
+    let saved = changed.iter().map(|(name, palette)| (name.clone(), Palette::clone(palette))).collect();
+    Saved::update(|settings| settings.palettes = saved)?;
+    *palettes = changed;
+    Ok(())
+}
+
+/// Add or replace a palette, and save it
+pub fn set(name: &str, palette: Palette) -> Result<()> {
+    palette.validate()?;
+    if BUILTIN.contains_key(name) {
+        bail!("The built-in palette {name} can't be changed");
+    }
+    let mut palettes = USER_PALETTES.write().unwrap();
+    let mut changed = palettes.clone();
+    changed.insert(name.to_string(), Arc::new(palette));
+    save(&mut palettes, changed)
+}
+
+/// Remove an added palette, returns false if there wasn't one
+pub fn remove(name: &str) -> Result<bool> {
+    let mut palettes = USER_PALETTES.write().unwrap();
+    let mut changed = palettes.clone();
+    if changed.remove(name).is_none() {
+        return Ok(false);
+    }
+    save(&mut palettes, changed)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        let palette = Palette::even(&[[0, 0, 0], [200, 100, 0]], Interpolation::Rgb);
+        assert_eq!(palette.sample(0.5), LedColor { r: 100, g: 50, b: 0 });
+        assert_eq!(palette.sample(2.0), LedColor { r: 200, g: 100, b: 0 });
+
+        // red to blue in HSV goes through magenta, not grey
+        let palette = Palette::even(&[[255, 0, 0], [0, 0, 255]], Interpolation::Hsv);
+        assert_eq!(palette.sample(0.5), LedColor { r: 255, g: 0, b: 255 });
+    }
+
+    #[test]
+    fn default_pattern() {
+        let palette = get(DEFAULT_PALETTE).unwrap();
+        let pattern = palette.pattern(6, 3);
+        let (red, green, white) = ([128, 0, 0].into(), [0, 128, 0].into(), [96, 96, 64].into());
+        assert_eq!(pattern, vec![red, green, white, red, green, white]);
+        assert!(Palette { stops: Vec::new(), interpolation: Interpolation::Rgb }.validate().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! Recording and reading back the frames shown by the lights controller
+//!
+//! A recording is a small binary file: a header followed by one record per
+//! frame. Every frame carries the number of milliseconds since the recording
+//! started, and the colors are stored either as a full frame or as a list of
+//! the LEDs that changed since the previous frame (whichever is smaller).
+//! Turning the lights off is its own kind of frame, the colors stay as they
+//! were for when the lights come back on (version 1 recorded it as a frame
+//! of black).
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! header: b"LREC" | version: u8 | led count: u32
+//! frame:  millis: u32 | kind: u8 | payload
+//!   kind 0 (full):  led count * [r, g, b]
+//!   kind 1 (delta): changed: u32 | changed * (index: u32, [r, g, b])
+//!   kind 2 (off):   nothing
+//! ```
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::lights::LedColor;
+
+/// Magic bytes at the start of every recording
+const MAGIC: &[u8; 4] = b"LREC";
+/// Current version of the recording format
+const VERSION: u8 = 2;
+/// Oldest version of the recording format that can still be read
+const MIN_VERSION: u8 = 1;
+/// Frame record with every LED color
+const KIND_FULL: u8 = 0;
+/// Frame record with only the LEDs that changed
+const KIND_DELTA: u8 = 1;
+/// Frame record for the lights turning off
+const KIND_OFF: u8 = 2;
+/// Most LEDs a recording can have, more is taken to be a broken file
+const MAX_LEDS: usize = 100_000;
+
+/// Directory (relative to the working directory) that holds the recordings
+pub const RECORDINGS_DIR: &str = "recordings";
+
+/// A single recorded frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Time since the start of the recording
+    pub timestamp: Duration,
+    /// Whether the lights were on
+    pub on: bool,
+    /// The color of every LED (as shown when the lights are on)
+    pub colors: Vec<LedColor>,
+}
+
+/// Writes frames into a recording
+pub struct FrameWriter<W: Write> {
+    writer: W,
+    last: Vec<LedColor>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Write the header for a recording with `count` LEDs
+    pub fn new(mut writer: W, count: usize) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(count as u32).to_le_bytes())?;
+        Ok(FrameWriter { writer, last: vec![[0, 0, 0].into(); count] })
+    }
+
+    /// Append a frame, colors beyond the LED count are ignored and missing
+    /// colors are recorded as off
+    pub fn write(&mut self, timestamp: Duration, colors: &[LedColor]) -> Result<()> {
+        let off: LedColor = [0, 0, 0].into();
+        let changed: Vec<(usize, LedColor)> = (0..self.last.len())
+            .map(|i| (i, colors.get(i).copied().unwrap_or(off)))
+            .filter(|(i, color)| self.last[*i] != *color)
+            .collect();
+        let millis = timestamp.as_millis().min(u32::MAX as u128) as u32;
+        self.writer.write_all(&millis.to_le_bytes())?;
+        // a delta entry is 7 bytes, a full entry is 3 bytes
+        if changed.len() * 7 + 4 < self.last.len() * 3 {
+            self.writer.write_all(&[KIND_DELTA])?;
+            self.writer.write_all(&(changed.len() as u32).to_le_bytes())?;
+            for (index, color) in changed {
+                self.writer.write_all(&(index as u32).to_le_bytes())?;
+                self.writer.write_all(&[color.r, color.g, color.b])?;
+                self.last[index] = color;
+            }
+        } else {
+            self.writer.write_all(&[KIND_FULL])?;
+            for (index, color) in changed {
+                self.last[index] = color;
+            }
+            for color in self.last.iter() {
+                self.writer.write_all(&[color.r, color.g, color.b])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a frame where the lights are turned off
+    pub fn write_off(&mut self, timestamp: Duration) -> Result<()> {
+        let millis = timestamp.as_millis().min(u32::MAX as u128) as u32;
+        self.writer.write_all(&millis.to_le_bytes())?;
+        self.writer.write_all(&[KIND_OFF])?;
+        Ok(())
+    }
+
+    /// Flush any buffered frames and give back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads frames back out of a recording
+pub struct FrameReader<R: Read> {
+    reader: R,
+    current: Vec<LedColor>,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Read and check the recording header
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).context("Recording is missing its header")?;
+        if &magic != MAGIC {
+            bail!("Not a lights recording");
+        }
+        let version = read_u8(&mut reader)?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            bail!("Unsupported recording version {version}");
+        }
+        let count = read_u32(&mut reader)? as usize;
+        if count > MAX_LEDS {
+            bail!("Recording is for {count} LEDs, at most {MAX_LEDS} are supported");
+        }
+        Ok(FrameReader { reader, current: vec![[0, 0, 0].into(); count] })
+    }
+
+    /// Read the next frame, or `None` at the end of the recording
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let mut millis = [0u8; 4];
+        match self.reader.read_exact(&mut millis) {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let timestamp = Duration::from_millis(u32::from_le_bytes(millis) as u64);
+        let mut on = true;
+        match read_u8(&mut self.reader)? {
+            KIND_FULL => {
+                for led in self.current.iter_mut() {
+                    *led = read_color(&mut self.reader)?;
+                }
+            },
+            KIND_DELTA => {
+                let changed = read_u32(&mut self.reader)?;
+                for _ in 0..changed {
+                    let index = read_u32(&mut self.reader)? as usize;
+                    let color = read_color(&mut self.reader)?;
+                    match self.current.get_mut(index) {
+                        Some(led) => *led = color,
+                        None => bail!("LED index {index} out of range in recording"),
+                    }
+                }
+            },
+            KIND_OFF => on = false,
+            kind => bail!("Unknown frame kind {kind} in recording"),
+        }
+        Ok(Some(Frame { timestamp, on, colors: self.current.clone() }))
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// List the names of the recordings in the recordings directory
+pub fn list_recordings() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(RECORDINGS_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_file())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf).context("Recording ended unexpectedly")?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).context("Recording ended unexpectedly")?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_color<R: Read>(reader: &mut R) -> Result<LedColor> {
+    let mut buf = [0u8; 3];
+    reader.read_exact(&mut buf).context("Recording ended unexpectedly")?;
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames() -> Vec<Frame> {
+        let red: LedColor = [255, 0, 0].into();
+        let green: LedColor = [0, 255, 0].into();
+        let mut second = vec![red; 20];
+        second[3] = green;
+        vec![
+            Frame { timestamp: Duration::from_millis(0), on: true, colors: vec![red; 20] },
+            Frame { timestamp: Duration::from_millis(33), on: true, colors: second.clone() },
+            Frame { timestamp: Duration::from_millis(50), on: false, colors: second },
+            Frame { timestamp: Duration::from_millis(66), on: true, colors: vec![green; 20] },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = FrameWriter::new(Vec::new(), 20).unwrap();
+        for frame in frames() {
+            if frame.on {
+                writer.write(frame.timestamp, &frame.colors).unwrap();
+            } else {
+                writer.write_off(frame.timestamp).unwrap();
+            }
+        }
+        let bytes = writer.finish().unwrap();
+        let read: Vec<Frame> = FrameReader::new(bytes.as_slice()).unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(read, frames());
+    }
+
+    #[test]
+    fn small_changes_use_delta() {
+        let frames = frames();
+        let mut writer = FrameWriter::new(Vec::new(), 20).unwrap();
+        writer.write(frames[0].timestamp, &frames[0].colors).unwrap();
+        let full = writer.writer.len();
+        writer.write(frames[1].timestamp, &frames[1].colors).unwrap();
+        // timestamp + kind + count + one (index, color) entry
+        assert_eq!(writer.writer.len() - full, 4 + 1 + 4 + 7);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert!(FrameReader::new(&b"NOPE\x01\x00\x00\x00\x00"[..]).is_err());
+        // far too many LEDs to be real
+        assert!(FrameReader::new(&b"LREC\x02\xff\xff\xff\xff"[..]).is_err());
+        assert!(FrameReader::new(&b"LREC\x01\x14\x00\x00\x00"[..]).is_ok());
+    }
+}
@@ -0,0 +1,50 @@
+//! Settings changed through the web-app that are kept across restarts
+//!
+//! Unlike `config.json` this file is written by the app, so it shouldn't need
+//! editing by hand.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use serde::{Deserialize, Serialize};
+
+use crate::palette::Palette;
+
+/// File with the saved settings
+pub const SAVED_FILE: &str = "saved.json";
+
+/// Keeps concurrent updates from losing each other's changes
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Saved {
+    /// Overall brightness of the lights
+    pub brightness: Option<u8>,
+    /// Palettes added through the web-app
+    pub palettes: BTreeMap<String, Palette>,
+}
+
+impl Saved {
+    /// Read the saved settings, falling back to the defaults
+    pub fn load() -> Self {
+        match std::fs::read_to_string(SAVED_FILE) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable saved settings: {e}");
+                Saved::default()
+            }),
+            Err(_) => Saved::default(),
+        }
+    }
+
+    /// Change and write the saved settings
+    pub fn update(f: impl FnOnce(&mut Saved)) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        let mut saved = Saved::load();
+        f(&mut saved);
+        std::fs::write(SAVED_FILE, serde_json::to_string_pretty(&saved)?)
+            .context("Failed to write saved settings")
+    }
+}
@@ -0,0 +1,329 @@
+//! Host for user-defined lights effects written in Rhai
+//!
+//! A script defines two functions:
+//!
+//! - `params()` returns an array of maps describing the parameters, e.g.
+//!   `#{ name: "speed", type: "range", value: 50, min: 0, max: 100 }`. The
+//!   types are `range`, `toggle`, `color` and `select` (with `options`).
+//! - `frame(t, pixels)` gets the animation time in seconds (the same on lights
+//!   that are synced) and the current pixels, and
+//!   returns the new pixels. Pixels are integers of the form `0xRRGGBB` and
+//!   the parameter values are the fields of `this` (colors are also
+//!   `0xRRGGBB`).
+//!
+//! The helpers `rgb(r, g, b)` and `hsv(h, s, v)` (all 0 to 1 floats except `h`
+//! in degrees) are available to build pixel values, and `palette(name, t)`
+//! samples a palette at `t` from 0 to 1.
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST, FLOAT, INT};
+
+use crate::lights::LedColor;
+use crate::mode::{Meta, Param, Value};
+use crate::palette;
+
+/// Directory (relative to the working directory) that holds the scripts
+pub const SCRIPTS_DIR: &str = "scripts";
+/// Extension of the script files
+const SCRIPT_EXT: &str = "rhai";
+/// Longest a single call into a script may run
+const TIME_LIMIT: Duration = Duration::from_millis(50);
+/// Most operations a single call into a script may run
+const MAX_OPERATIONS: u64 = 10_000_000;
+/// Largest array a script may build, enough for the pixels of big setups
+const MAX_ARRAY_SIZE: usize = 100_000;
+/// Longest string a script may build
+const MAX_STRING_SIZE: usize = 100_000;
+/// Deepest a script may nest function calls
+const MAX_CALL_LEVELS: usize = 32;
+
+/// A compiled effect script
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    /// the current value of every parameter, bound to `this` in `frame`
+    values: Dynamic,
+    /// when the running call has to be finished by
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Script {
+    /// Compile a script
+    pub fn compile(source: &str) -> Result<Self> {
+        let deadline: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_ARRAY_SIZE);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        let limit = deadline.clone();
+        engine.on_progress(move |_| {
+            match *limit.lock().unwrap() {
+                Some(deadline) if Instant::now() > deadline => Some("time limit exceeded".into()),
+                _ => None,
+            }
+        });
+        engine.register_fn("rgb", |r: FLOAT, g: FLOAT, b: FLOAT| pack(r, g, b));
+        engine.register_fn("hsv", hsv);
+        engine.register_fn("palette", |name: &str, t: FLOAT| {
+            let color = palette::get(name).map(|palette| palette.sample(t)).unwrap_or([0, 0, 0].into());
+            ((color.r as INT) << 16) | ((color.g as INT) << 8) | color.b as INT
+        });
+        let ast = engine.compile(source).map_err(|e| anyhow!("{e}"))?;
+        for name in ["params", "frame"] {
+            if !ast.iter_functions().any(|f| f.name == name) {
+                bail!("Script does not define a `{name}` function");
+            }
+        }
+        let mut script = Script { engine, ast, values: Map::new().into(), deadline };
+        let values: Map = script.params()?
+            .into_iter()
+            .map(|param| (param.name.as_str().into(), to_dynamic(&param.value)))
+            .collect();
+        script.values = values.into();
+        Ok(script)
+    }
+
+    /// Load and compile a script from the scripts directory
+    pub fn load(name: &str) -> Result<Self> {
+        let source = std::fs::read_to_string(script_path(name)?)
+            .with_context(|| format!("Unable to read script {name}"))?;
+        Script::compile(&source).with_context(|| format!("Error in script {name}"))
+    }
+
+    /// The parameters declared by the script with their current values
+    pub fn params(&mut self) -> Result<Vec<Param>> {
+        let declared = self.call("params", CallFnOptions::new(), ())?
+            .into_array()
+            .map_err(|_| anyhow!("`params` must return an array"))?;
+        let values = self.values.read_lock::<Map>().map(|map| (*map).clone()).unwrap_or_default();
+        declared.into_iter()
+            .map(|param| {
+                let mut param = to_param(param)?;
+                if let Some(value) = values.get(param.name.as_str()) {
+                    param.value = from_dynamic(&param.value, value).unwrap_or(param.value);
+                }
+                Ok(param)
+            })
+            .collect()
+    }
+
+    /// Set the value of a parameter declared by the script
+    pub fn set(&mut self, param: &Param) -> Result<()> {
+        let mut values = self.values.write_lock::<Map>().context("Script values are not a map")?;
+        match values.get_mut(param.name.as_str()) {
+            Some(value) => {
+                *value = to_dynamic(&param.value);
+                Ok(())
+            },
+            None => Err(anyhow!("Unknown parameter {}", param.name)),
+        }
+    }
+
+    /// Compute the next frame from the time (in seconds) and current pixels
+    pub fn frame(&mut self, t: f64, pixels: &[LedColor]) -> Result<Vec<LedColor>> {
+        let input: Array = pixels.iter()
+            .map(|c| Dynamic::from_int(((c.r as INT) << 16) | ((c.g as INT) << 8) | c.b as INT))
+            .collect();
+        let mut values = std::mem::take(&mut self.values);
+        let res = self.call(
+            "frame",
+            CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut values),
+            (t as FLOAT, input),
+        );
+        self.values = values;
+        let output = res?
+            .into_array()
+            .map_err(|_| anyhow!("`frame` must return an array of pixels"))?;
+        Ok(pixels.iter()
+            .enumerate()
+            .map(|(i, old)| output.get(i).and_then(|p| p.as_int().ok()).map(unpack).unwrap_or(*old))
+            .collect())
+    }
+
+    /// Call a script function with the time limit
+    fn call(
+        &mut self,
+        name: &str,
+        options: CallFnOptions,
+        args: impl rhai::FuncArgs,
+    ) -> Result<Dynamic> {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + TIME_LIMIT);
+        let res = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, args);
+        *self.deadline.lock().unwrap() = None;
+        res.map_err(|e| anyhow!("Error in `{name}`: {e}"))
+    }
+}
+
+/// List the names of the scripts in the scripts directory
+pub fn list_scripts() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(SCRIPTS_DIR)
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == SCRIPT_EXT))
+                .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Path to a script file, the name can only use letters, numbers, `-` and `_`
+pub fn script_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("Invalid script name {name}");
+    }
+    Ok(PathBuf::from(SCRIPTS_DIR).join(name).with_extension(SCRIPT_EXT))
+}
+
+fn pack(r: FLOAT, g: FLOAT, b: FLOAT) -> INT {
+    let byte = |v: FLOAT| (v.clamp(0.0, 1.0) * 255.0).round() as INT;
+    (byte(r) << 16) | (byte(g) << 8) | byte(b)
+}
+
+fn unpack(pixel: INT) -> LedColor {
+    [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8].into()
+}
+
+fn hsv(h: FLOAT, s: FLOAT, v: FLOAT) -> INT {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as INT {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    pack(r + m, g + m, b + m)
+}
+
+/// The script value for a parameter value
+fn to_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Toggle(on) => (*on).into(),
+        Value::Button => Dynamic::UNIT,
+        Value::Range(v) => (*v as INT).into(),
+        Value::Color(c) => (((c.r as INT) << 16) | ((c.g as INT) << 8) | c.b as INT).into(),
+        Value::Select(s) => s.clone().into(),
+    }
+}
+
+/// A parameter value of the same kind as `like` from a script value
+fn from_dynamic(like: &Value, value: &Dynamic) -> Option<Value> {
+    match like {
+        Value::Toggle(_) => value.as_bool().ok().map(Value::Toggle),
+        Value::Button => Some(Value::Button),
+        Value::Range(_) => value.as_int().ok().map(|v| Value::Range(v as isize)),
+        Value::Color(_) => value.as_int().ok().map(|v| Value::Color(unpack(v))),
+        Value::Select(_) => value.clone().into_string().ok().map(Value::Select),
+    }
+}
+
+/// A parameter from one of the maps returned by the script's `params()`
+fn to_param(param: Dynamic) -> Result<Param> {
+    let map = param.try_cast::<Map>().context("`params` must return an array of maps")?;
+    let field = |key: &str| map.get(key).cloned().unwrap_or(Dynamic::UNIT);
+    let name = field("name").into_string().map_err(|_| anyhow!("Parameter is missing a name"))?;
+    let kind = field("type").into_string().map_err(|_| anyhow!("Parameter {name} is missing a type"))?;
+    let int = |key: &str| field(key).as_int().map(|v| v as isize);
+    let (value, meta) = match kind.as_str() {
+        "range" => (
+            Value::Range(int("value").unwrap_or(0)),
+            Meta::Range { min: int("min").unwrap_or(0), max: int("max").unwrap_or(100) },
+        ),
+        "toggle" => (
+            Value::Toggle(field("value").as_bool().unwrap_or(false)),
+            Meta::Toggle {
+                on: field("on").into_string().unwrap_or("On".into()),
+                off: field("off").into_string().unwrap_or("Off".into()),
+            },
+        ),
+        "color" => {
+            let color = match field("value").into_string() {
+                Ok(hex) => serde_json::from_value(serde_json::Value::String(hex))?,
+                Err(_) => unpack(int("value").unwrap_or(0) as INT),
+            };
+            (Value::Color(color), Meta::Color)
+        },
+        "select" => {
+            let options: Vec<String> = field("options")
+                .try_cast::<Array>()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|o| o.into_string().ok())
+                .collect();
+            let value = field("value").into_string()
+                .unwrap_or_else(|_| options.first().cloned().unwrap_or_default());
+            (Value::Select(value), Meta::Select { options })
+        },
+        other => bail!("Parameter {name} has unknown type {other}"),
+    };
+    Ok(Param { name, value, meta: Some(meta) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHASE: &str = r##"
+        fn params() {
+            [
+                #{ name: "speed", type: "range", value: 10, min: 1, max: 100 },
+                #{ name: "color", type: "color", value: "#ff0000" },
+            ]
+        }
+
+        fn frame(t, pixels) {
+            let lit = (t * this.speed).to_int() % pixels.len();
+            for i in 0..pixels.len() {
+                pixels[i] = if i == lit { this.color } else { 0 };
+            }
+            pixels
+        }
+    "##;
+
+    #[test]
+    fn runs_frames_with_params() {
+        let mut script = Script::compile(CHASE).unwrap();
+        let params = script.params().unwrap();
+        assert_eq!(params.len(), 2);
+        let pixels = vec![LedColor { r: 0, g: 0, b: 0 }; 5];
+        let frame = script.frame(0.25, &pixels).unwrap();
+        // 0.25s at speed 10 lights pixel 2
+        assert_eq!(frame[2], LedColor { r: 255, g: 0, b: 0 });
+        assert_eq!(frame[1], LedColor { r: 0, g: 0, b: 0 });
+
+        script.set(&Param { name: "speed".into(), value: Value::Range(4), meta: None }).unwrap();
+        let frame = script.frame(0.25, &pixels).unwrap();
+        assert_eq!(frame[1], LedColor { r: 255, g: 0, b: 0 });
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(Script::compile("fn params() { [] }").is_err());
+        assert!(Script::compile("fn params() { [ }").is_err());
+        let mut script = Script::compile("fn params() { [] } fn frame(t, p) { loop {} }").unwrap();
+        let err = script.frame(0.0, &[]).unwrap_err();
+        assert!(err.to_string().contains("frame"));
+    }
+
+    #[test]
+    fn limits_scripts() {
+        let mut script = Script::compile("fn params() { [] } fn frame(t, p) { f(0) } fn f(n) { f(n + 1) }").unwrap();
+        assert!(script.frame(0.0, &[]).is_err());
+        let mut script = Script::compile("fn params() { [] } fn frame(t, p) { let s = \"x\"; loop { s += s; } }").unwrap();
+        assert!(script.frame(0.0, &[]).is_err());
+        let mut script = Script::compile("fn params() { [] } fn frame(t, p) { let a = []; loop { a.push(0); } }").unwrap();
+        assert!(script.frame(0.0, &[]).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Turning the lights off later, by timer or when nobody has used them
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::events::{Event, EVENTS};
+use crate::lights::{LightsCommand, LightsError, LightsRemote};
+
+/// How long the lights fade out when they are turned off for being idle
+const IDLE_FADE: Duration = Duration::from_secs(60);
+/// How often the lights are checked for being idle
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+/// A scheduled turn off
+struct Scheduled {
+    /// when the lights go off
+    off_at: Instant,
+    /// how long the brightness ramps down before that
+    fade: Duration,
+    /// brightness to go back to afterwards
+    level: u8,
+    task: JoinHandle<()>,
+}
+
+/// The sleep timer as reported to the front-end
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepStatus {
+    /// time left until the lights go off
+    pub remaining_ms: u64,
+    /// length of the fade to off
+    pub fade_ms: u64,
+    /// if the lights are fading out right now
+    pub fading: bool,
+}
+
+/// Turns the lights off after a delay, optionally fading them out first
+pub struct SleepTimer {
+    remote: LightsRemote,
+    scheduled: Mutex<Option<Scheduled>>,
+    /// last time someone did something with the lights
+    last_activity: Mutex<Instant>,
+}
+
+impl SleepTimer {
+    pub fn new(remote: LightsRemote) -> Self {
+        SleepTimer {
+            remote,
+            scheduled: Mutex::new(None),
+            last_activity: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Turn the lights off after `delay`, fading out over `fade` before that,
+    /// replacing any timer that is already set
+    pub async fn schedule(&self, delay: Duration, fade: Duration) -> Result<SleepStatus, LightsError> {
+        self.cancel().await;
+        let level = self.remote.state().await?.config.brightness;
+        let off_at = Instant::now() + delay + fade;
+        debug!("Turning the lights off in {:?}", delay + fade);
+        let task = tokio::spawn(sleep(self.remote.clone(), off_at, fade, level));
+        let scheduled = Scheduled { off_at, fade, level, task };
+        let status = scheduled.status(Instant::now());
+        *self.scheduled.lock().unwrap() = Some(scheduled);
+        Ok(status)
+    }
+
+    /// Stop the timer, putting the brightness back if the lights were fading
+    /// out. Returns false if there was no timer
+    pub async fn cancel(&self) -> bool {
+        let scheduled = self.scheduled.lock().unwrap().take();
+        let Some(scheduled) = scheduled else {
+            return false;
+        };
+        if scheduled.task.is_finished() {
+            return false;
+        }
+        scheduled.task.abort();
+        if scheduled.status(Instant::now()).fading {
+            let restore = LightsCommand::Brightness { level: scheduled.level, fade: Duration::ZERO };
+            if let Err(e) = self.remote.send(restore).await {
+                warn!("Unable to restore the brightness: {e:?}");
+            }
+        }
+        true
+    }
+
+    /// The timer that is set, if any
+    pub fn status(&self) -> Option<SleepStatus> {
+        self.scheduled.lock().unwrap().as_ref()
+            .filter(|scheduled| !scheduled.task.is_finished())
+            .map(|scheduled| scheduled.status(Instant::now()))
+    }
+
+    /// Note that someone used the lights, which holds off the idle auto-off
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+}
+
+impl Scheduled {
+    fn status(&self, now: Instant) -> SleepStatus {
+        let remaining = self.off_at.saturating_duration_since(now);
+        SleepStatus {
+            remaining_ms: remaining.as_millis() as u64,
+            fade_ms: self.fade.as_millis() as u64,
+            fading: remaining <= self.fade,
+        }
+    }
+}
+
+/// Wait for the fade to start, fade out, then turn the lights off and put the
+/// brightness back for the next time they are turned on
+async fn sleep(remote: LightsRemote, off_at: Instant, fade: Duration, level: u8) {
+    if !fade.is_zero() {
+        tokio::time::sleep_until(off_at - fade).await;
+        let _ = remote.send(LightsCommand::Brightness { level: 0, fade }).await;
+    }
+    tokio::time::sleep_until(off_at).await;
+    info!("Sleep timer turning the lights off");
+    EVENTS.send(Event::ScheduleFired { schedule: "sleep" });
+    if let Err(e) = remote.send(LightsCommand::Off).await {
+        error!("Sleep timer failed to turn the lights off: {e:?}");
+    }
+    let _ = remote.send(LightsCommand::Brightness { level, fade: Duration::ZERO }).await;
+}
+
+/// Turn the lights off when they have been on and unused for `after`
+pub async fn auto_off(timer: Arc<SleepTimer>, after: Duration) {
+    let mut interval = tokio::time::interval(IDLE_CHECK);
+    loop {
+        interval.tick().await;
+        if timer.idle_for() < after || timer.status().is_some() {
+            continue;
+        }
+        match timer.remote.state().await {
+            Ok(state) if state.on => {
+                info!("Lights unused for {after:?}, turning them off");
+                if let Err(e) = timer.schedule(Duration::ZERO, IDLE_FADE).await {
+                    error!("Unable to turn off idle lights: {e:?}");
+                }
+                timer.touch();
+            },
+            Ok(_) => {},
+            Err(LightsError::Closed) => return,
+            Err(e) => warn!("Unable to check the lights: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// The commands the controller got since the last time
+    fn received(commands: &mut UnboundedReceiver<LightsCommand>) -> Vec<LightsCommand> {
+        std::iter::from_fn(|| commands.try_recv().ok()).collect()
+    }
+
+    fn names(commands: &[LightsCommand]) -> Vec<&'static str> {
+        commands.iter().map(LightsCommand::name).collect()
+    }
+
+    #[tokio::test]
+    async fn fades_then_turns_off() {
+        tokio::time::pause();
+        let (remote, mut commands) = LightsRemote::stand_in(10);
+        let timer = SleepTimer::new(remote);
+        let status = timer.schedule(Duration::from_secs(10), Duration::from_secs(5)).await.unwrap();
+        assert_eq!((status.remaining_ms, status.fading), (15_000, false));
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(timer.status().unwrap().fading);
+        let fade = received(&mut commands);
+        assert!(matches!(fade.last(), Some(LightsCommand::Brightness { level: 0, .. })));
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let off = received(&mut commands);
+        assert_eq!(names(&off), ["off", "brightness"]);
+        // the brightness is back for the next time the lights go on
+        assert!(matches!(off[1], LightsCommand::Brightness { level: 255, .. }));
+        assert!(timer.status().is_none());
+    }
+
+    #[tokio::test]
+    async fn cancel_while_fading() {
+        tokio::time::pause();
+        let (remote, mut commands) = LightsRemote::stand_in(10);
+        let timer = SleepTimer::new(remote);
+        timer.schedule(Duration::from_secs(1), Duration::from_secs(10)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(timer.status().unwrap().fading);
+        received(&mut commands);
+
+        assert!(timer.cancel().await);
+        let restore = received(&mut commands);
+        assert!(matches!(restore[..], [LightsCommand::Brightness { level: 255, fade }] if fade.is_zero()));
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert!(received(&mut commands).is_empty());
+        assert!(!timer.cancel().await);
+    }
+
+    #[tokio::test]
+    async fn turns_off_idle_lights() {
+        tokio::time::pause();
+        let (remote, mut commands) = LightsRemote::stand_in(10);
+        let timer = Arc::new(SleepTimer::new(remote));
+        tokio::spawn(auto_off(timer.clone(), Duration::from_secs(3600)));
+
+        tokio::time::sleep(Duration::from_secs(1800)).await;
+        timer.touch();
+        // an hour after the start, but only half an hour after being used
+        tokio::time::sleep(Duration::from_secs(2400)).await;
+        assert!(!names(&received(&mut commands)).contains(&"off"));
+
+        // idle for an hour, then the fade
+        tokio::time::sleep(Duration::from_secs(1800)).await;
+        assert!(names(&received(&mut commands)).contains(&"off"));
+    }
+}
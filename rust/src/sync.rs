@@ -0,0 +1,567 @@
+//! Keeping the lights of several instances of the app in step
+//!
+//! One instance leads and the others follow it. A follower asks the leader
+//! for its state over UDP a few times a second, and the leader answers with
+//! its mode, the mode's parameters, whether the lights are on and the time on
+//! its animation clock. The follower works out how far its clock is from the
+//! leader's from the round trip (the way NTP does, trusting the quickest of the
+//! recent round trips), and copies the mode, parameters and on/off whenever
+//! the leader's change. A follower without a leader set finds one through
+//! mDNS.
+//!
+//! With a `secret` set on the leader and its followers, every message starts
+//! with an HMAC-SHA256 of the rest keyed with the secret, and the leader only
+//! answers signed requests it hasn't seen before. Without one, anyone can ask,
+//! so followers pad their requests and the leader won't send an answer much
+//! bigger than the request (which would make it useful for flooding a forged
+//! sender), telling the follower how big to make its requests instead.
+//!
+//! Followers only use answers to requests they are still waiting on, so an
+//! answer can't be sent to them again later.
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::clock;
+use crate::lights::{LightsCommand, LightsRemote};
+use crate::mdns;
+use crate::mode::{Param, SharedModes, Value};
+
+/// Port the leader listens on when none is given
+const DEFAULT_PORT: u16 = 7117;
+/// Time between requests from a follower
+const POLL_TIME: Duration = Duration::from_millis(500);
+/// The leader is lost when it hasn't answered for this long
+const LOST_AFTER: Duration = Duration::from_secs(5);
+/// The leader's address is looked up again when it hasn't answered for this
+/// long
+const LOOKUP_AFTER: Duration = Duration::from_secs(30);
+/// Round trips kept for estimating the clock offset
+const SAMPLES: usize = 8;
+/// Answers that took longer than this are too stale to use (seconds)
+const MAX_ROUND_TRIP: f64 = 1.0;
+/// Smallest change made to the clock offset (seconds)
+const MIN_CORRECTION: f64 = 0.002;
+/// First wait before looking up the leader's address again
+const RETRY_TIME: Duration = Duration::from_secs(1);
+/// Longest wait between looking up the leader's address
+const MAX_RETRY_TIME: Duration = Duration::from_secs(60);
+/// Time spent waiting for leaders to answer when looking for one
+const DISCOVER_TIME: Duration = Duration::from_secs(3);
+/// Largest message, the parameters are small
+const MAX_MESSAGE: usize = 64 * 1024;
+/// Size followers pad their requests to, unless the leader asks for more
+const REQUEST_SIZE: usize = 1200;
+/// Most times bigger than an unsigned request its answer may be
+const MAX_AMPLIFICATION: usize = 2;
+/// Length of the signature at the start of signed messages
+const TAG_LEN: usize = 32;
+/// Signed requests remembered by the leader, to drop ones sent again
+const SEEN_REQUESTS: usize = 4096;
+
+/// Which part this instance plays
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "role")]
+pub enum SyncConfig {
+    /// Answer followers on a UDP port
+    Leader {
+        #[serde(default = "default_port")]
+        port: u16,
+        /// only answer followers that sign their requests with this
+        #[serde(default)]
+        secret: Option<String>,
+    },
+    /// Mirror the leader at a host name or address, with an optional port,
+    /// or the one found on the network when missing
+    Follower {
+        #[serde(default)]
+        leader: Option<String>,
+        /// sign requests with this, and only trust answers signed with it
+        #[serde(default)]
+        secret: Option<String>,
+    },
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+/// What the leader is showing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Shown {
+    mode: Option<String>,
+    params: Vec<Param>,
+    on: bool,
+}
+
+/// Messages between the leader and a follower
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+enum Message {
+    /// A follower asking for the state, with its local clock when it was sent
+    Request { sent: f64 },
+    /// The leader's answer, with the follower's time sent back and the time
+    /// on the leader's animation clock
+    State { sent: f64, clock: f64, shown: Shown },
+    /// The leader's answer to an unsigned request too small for the state,
+    /// with the size requests have to be padded to
+    Larger { sent: f64, size: usize },
+}
+
+/// HMAC-SHA256 of `data` keyed with `key`
+fn hmac(key: &[u8], data: &[u8]) -> [u8; TAG_LEN] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..TAG_LEN].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// A message as it is sent, padded with spaces to at least `size` and signed
+/// when there is a secret
+fn encode(message: &Message, size: usize, secret: Option<&str>) -> Result<Vec<u8>> {
+    let mut json = serde_json::to_vec(message)?;
+    json.resize(json.len().max(size), b' ');
+    Ok(match secret {
+        Some(secret) => [&hmac(secret.as_bytes(), &json)[..], &json].concat(),
+        None => json,
+    })
+}
+
+/// A received message, which has to be signed when there is a secret
+fn decode(buf: &[u8], secret: Option<&str>) -> Result<Message> {
+    let json = match secret {
+        Some(secret) => {
+            if buf.len() < TAG_LEN {
+                bail!("Message is not signed");
+            }
+            let (tag, json) = buf.split_at(TAG_LEN);
+            // look at every byte so the time taken doesn't give the tag away
+            let diff = tag.iter().zip(hmac(secret.as_bytes(), json)).fold(0, |diff, (a, b)| diff | (a ^ b));
+            if diff != 0 {
+                bail!("Message is not signed with the secret");
+            }
+            json
+        },
+        None => buf,
+    };
+    Ok(serde_json::from_slice(json)?)
+}
+
+/// Recent round trips to the leader, for estimating the clock offset
+#[derive(Default)]
+struct Samples {
+    /// (round trip, offset) in seconds
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl Samples {
+    /// Add a round trip sent at `sent`, answered with `leader` on the
+    /// leader's clock and received at `received` (all in seconds), returning
+    /// false when the answer took too long to use
+    fn add(&mut self, sent: f64, leader: f64, received: f64) -> bool {
+        let round_trip = received - sent;
+        if !(0.0..MAX_ROUND_TRIP).contains(&round_trip) {
+            return false;
+        }
+        // assume the answer was sent half way through the round trip
+        let offset = leader - (sent + received) / 2.0;
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((round_trip, offset));
+        true
+    }
+
+    /// The offset from the quickest round trip, which has the least room for
+    /// error
+    fn offset(&self) -> Option<f64> {
+        self.samples.iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, offset)| *offset)
+    }
+}
+
+/// The times of the requests sent to the leader that haven't been answered
+#[derive(Default)]
+struct Pending {
+    sent: VecDeque<f64>,
+}
+
+impl Pending {
+    /// Remember a request sent at `sent`, forgetting the ones too old to be
+    /// answered in time
+    fn sent(&mut self, sent: f64) {
+        self.sent.retain(|old| sent - old < MAX_ROUND_TRIP);
+        self.sent.push_back(sent);
+    }
+
+    /// Whether an answer to the request sent at `sent` is expected, it isn't
+    /// after that
+    fn answered(&mut self, sent: f64) -> bool {
+        // the time went through JSON, so allow for rounding
+        match self.sent.iter().position(|old| (old - sent).abs() < 1e-6) {
+            Some(i) => {
+                self.sent.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// The parameters that have to be sent to a mode to get from `from` to `to`
+///
+/// Buttons are left out, pressing one on the leader doesn't say when.
+fn changed_params(from: &[Param], to: &[Param]) -> Vec<Param> {
+    to.iter()
+        .filter(|param| param.value != Value::Button)
+        .filter(|param| !from.iter().any(|old| old.name == param.name && old.value == param.value))
+        .cloned()
+        .collect()
+}
+
+/// Lead or follow until the socket fails
+pub async fn run(config: SyncConfig, modes: SharedModes, remote: LightsRemote) {
+    let res = match config {
+        SyncConfig::Leader { port, secret } => lead(port, secret.as_deref(), modes, remote).await,
+        SyncConfig::Follower { leader: configured, secret } => {
+            let mut mirrored = None;
+            loop {
+                let leader = match &configured {
+                    Some(leader) => leader.clone(),
+                    None => find_leader().await,
+                };
+                if let Err(e) = follow(&leader, secret.as_deref(), &mut mirrored, &modes, &remote).await {
+                    break Err(e);
+                }
+            }
+        },
+    };
+    if let Err(e) = res {
+        error!("Sync stopped: {e:#}");
+    }
+}
+
+/// Look for a leader on the network until there is one
+async fn find_leader() -> String {
+    info!("Looking for a sync leader");
+    loop {
+        match mdns::discover(mdns::LIGHTS_SERVICE, DISCOVER_TIME).await {
+            Ok(found) => {
+                let leader = found.iter().find_map(|found| {
+                    let port = found.txt.get("sync")?.parse().ok()?;
+                    Some(SocketAddr::new(found.addr?, port))
+                });
+                if let Some(leader) = leader {
+                    return leader.to_string();
+                }
+            },
+            Err(e) => warn!("Unable to look for a sync leader: {e:#}"),
+        }
+        tokio::time::sleep(DISCOVER_TIME).await;
+    }
+}
+
+/// The address of the leader at a host name or address, with an optional port
+async fn lookup_leader(leader: &str) -> Result<SocketAddr> {
+    let addr = match lookup_host(leader).await {
+        Ok(mut addrs) => addrs.next(),
+        Err(_) => lookup_host((leader, DEFAULT_PORT)).await
+            .with_context(|| format!("Unable to resolve {leader}"))?
+            .next(),
+    };
+    match addr {
+        Some(addr) => Ok(addr),
+        None => bail!("No address for {leader}"),
+    }
+}
+
+/// Look up the leader's address until it is found, waiting longer after each
+/// failure
+async fn leader_addr(leader: &str) -> SocketAddr {
+    let mut wait = RETRY_TIME;
+    loop {
+        match lookup_leader(leader).await {
+            Ok(addr) => return addr,
+            Err(e) => warn!("{e:#}, trying again in {wait:?}"),
+        }
+        tokio::time::sleep(wait).await;
+        wait = (wait * 2).min(MAX_RETRY_TIME);
+    }
+}
+
+/// What this instance is showing
+async fn shown(modes: &SharedModes, remote: &LightsRemote) -> Result<Shown> {
+    let (mode, params) = {
+        let modes = modes.lock().await;
+        (modes.active().map(str::to_string), modes.params()?)
+    };
+    let on = remote.state().await?.on;
+    Ok(Shown { mode, params, on })
+}
+
+/// Answer followers
+async fn lead(port: u16, secret: Option<&str>, modes: SharedModes, remote: LightsRemote) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port)).await
+        .with_context(|| format!("Unable to listen for followers on port {port}"))?;
+    info!("Leading lights on port {port}");
+    if secret.is_none() {
+        warn!("Answering sync requests from anyone, set a secret to only answer followers");
+    }
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    // signatures of recent requests, a request sent again is dropped
+    let mut seen: VecDeque<[u8; TAG_LEN]> = VecDeque::with_capacity(SEEN_REQUESTS);
+    let mut too_big = false;
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let sent = match decode(&buf[..len], secret) {
+            Ok(Message::Request { sent }) => sent,
+            Ok(message) => {
+                debug!("Unexpected sync message from {from}: {message:?}");
+                continue;
+            },
+            Err(e) => {
+                debug!("Bad sync message from {from}: {e:#}");
+                continue;
+            },
+        };
+        if secret.is_some() {
+            let mut tag = [0u8; TAG_LEN];
+            tag.copy_from_slice(&buf[..TAG_LEN]);
+            if seen.contains(&tag) {
+                debug!("Sync request from {from} was sent before");
+                continue;
+            }
+            if seen.len() == SEEN_REQUESTS {
+                seen.pop_front();
+            }
+            seen.push_back(tag);
+        }
+        let shown = match shown(&modes, &remote).await {
+            Ok(shown) => shown,
+            Err(e) => {
+                warn!("Unable to get the state for followers: {e:#}");
+                continue;
+            },
+        };
+        let mut answer = encode(&Message::State { sent, clock: clock::seconds(), shown }, 0, secret)?;
+        if answer.len() > MAX_MESSAGE {
+            if !too_big {
+                warn!("The state is too big to send to followers");
+                too_big = true;
+            }
+            continue;
+        }
+        too_big = false;
+        if secret.is_none() && answer.len() > len * MAX_AMPLIFICATION {
+            let size = answer.len().div_ceil(MAX_AMPLIFICATION);
+            debug!("Asking {from} for requests of {size} bytes");
+            answer = encode(&Message::Larger { sent, size }, 0, None)?;
+        }
+        if let Err(e) = socket.send_to(&answer, from).await {
+            debug!("Unable to answer {from}: {e}");
+        }
+    }
+}
+
+/// Mirror the leader at `leader` until it hasn't answered for
+/// `LOOKUP_AFTER`, `mirrored` is what was last copied from the leader
+async fn follow(
+    leader: &str,
+    secret: Option<&str>,
+    mirrored: &mut Option<Shown>,
+    modes: &SharedModes,
+    remote: &LightsRemote,
+) -> Result<()> {
+    let addr = leader_addr(leader).await;
+    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(addr).await?;
+    info!("Following the lights at {addr}");
+
+    let mut samples = Samples::default();
+    let mut pending = Pending::default();
+    let mut request_size = REQUEST_SIZE;
+    let started = Instant::now();
+    let mut last_answer: Option<Instant> = None;
+    let mut lost = true;
+    let mut interval = tokio::time::interval(POLL_TIME);
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let silent = last_answer.unwrap_or(started).elapsed();
+                if !lost && silent > LOST_AFTER {
+                    warn!("Lost the sync leader at {addr}");
+                    lost = true;
+                }
+                if silent > LOOKUP_AFTER {
+                    info!("Looking up the sync leader {leader} again");
+                    return Ok(());
+                }
+                let sent = clock::local();
+                pending.sent(sent);
+                let request = encode(&Message::Request { sent }, request_size, secret)?;
+                // a leader that isn't up yet is reported as lost above
+                let _ = socket.send(&request).await;
+            },
+            res = socket.recv(&mut buf) => {
+                let received = clock::local();
+                let Ok(len) = res else { continue };
+                let (sent, leader_clock, shown) = match decode(&buf[..len], secret) {
+                    Ok(Message::State { sent, clock, shown }) => (sent, clock, shown),
+                    Ok(Message::Larger { sent, size }) if secret.is_none() && pending.answered(sent) => {
+                        let size = size.clamp(REQUEST_SIZE, MAX_MESSAGE / MAX_AMPLIFICATION);
+                        if size != request_size {
+                            debug!("Padding sync requests to {size} bytes");
+                            request_size = size;
+                        }
+                        continue;
+                    },
+                    Ok(message) => {
+                        debug!("Unexpected sync message: {message:?}");
+                        continue;
+                    },
+                    Err(e) => {
+                        debug!("Bad sync message: {e:#}");
+                        continue;
+                    },
+                };
+                if !pending.answered(sent) {
+                    debug!("Sync answer to a request that isn't waiting for one");
+                    continue;
+                }
+                if !samples.add(sent, leader_clock, received) {
+                    debug!("Sync answer came too late to use");
+                    continue;
+                }
+                if lost {
+                    info!("Sync leader at {addr} answered");
+                    lost = false;
+                }
+                last_answer = Some(Instant::now());
+                // small corrections would only make the animations jitter
+                if let Some(offset) = samples.offset().filter(|offset| (offset - clock::offset()).abs() > MIN_CORRECTION) {
+                    debug!("Animation clock is {offset:.3}s from the leader's");
+                    clock::set_offset(offset);
+                }
+                // changes made here stay until the leader changes something
+                if mirrored.as_ref() != Some(&shown) {
+                    mirror(mirrored.as_ref(), &shown, modes, remote).await;
+                    *mirrored = Some(shown);
+                }
+            },
+        }
+    }
+}
+
+/// Change what is shown here from the leader's `old` state to its `new` one
+async fn mirror(old: Option<&Shown>, new: &Shown, modes: &SharedModes, remote: &LightsRemote) {
+    let mut modes = modes.lock().await;
+    let res = if old.map(|old| &old.mode) != Some(&new.mode) {
+        match &new.mode {
+            Some(mode) => modes.select(mode)
+                .and_then(|started| modes.update(changed_params(&started, &new.params))),
+            None => modes.stop(),
+        }
+    } else {
+        let params = changed_params(old.map(|old| old.params.as_slice()).unwrap_or_default(), &new.params);
+        if params.is_empty() { Ok(()) } else { modes.update(params) }
+    };
+    if let Err(e) = res {
+        warn!("Unable to follow the leader's mode: {e:#}");
+    }
+    drop(modes);
+
+    if old.map(|old| old.on) != Some(new.on) {
+        let command = if new.on { LightsCommand::On } else { LightsCommand::Off };
+        if let Err(e) = remote.send(command).await {
+            warn!("Unable to follow the leader's lights: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock_offset() {
+        let mut samples = Samples::default();
+        assert_eq!(samples.offset(), None);
+        // leader is 10s ahead, 20ms each way
+        samples.add(1.0, 11.02, 1.04);
+        // a slow answer that was held up on the way back
+        samples.add(2.0, 12.02, 2.30);
+        // too late to be any use
+        assert!(!samples.add(3.0, 13.0, 5.0));
+        assert!((samples.offset().unwrap() - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn only_pending_answers() {
+        let mut pending = Pending::default();
+        pending.sent(1.0);
+        pending.sent(1.5);
+        // answers come back through JSON
+        let sent: f64 = serde_json::from_str(&serde_json::to_string(&1.5).unwrap()).unwrap();
+        assert!(pending.answered(sent));
+        // the same answer again
+        assert!(!pending.answered(sent));
+        assert!(!pending.answered(0.5));
+        // too old to be answered in time
+        pending.sent(3.0);
+        assert!(!pending.answered(1.0));
+        assert!(pending.answered(3.0));
+    }
+
+    #[test]
+    fn signing() {
+        // RFC 4231 test case 2
+        let tag = hmac(b"Jefe", b"what do ya want for nothing?");
+        let hex: String = tag.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        let request = Message::Request { sent: 1.5 };
+        let signed = encode(&request, REQUEST_SIZE, Some("secret")).unwrap();
+        assert_eq!(signed.len(), TAG_LEN + REQUEST_SIZE);
+        assert!(matches!(decode(&signed, Some("secret")), Ok(Message::Request { sent }) if sent == 1.5));
+        assert!(decode(&signed, Some("other")).is_err());
+        let unsigned = encode(&request, REQUEST_SIZE, None).unwrap();
+        assert_eq!(unsigned.len(), REQUEST_SIZE);
+        assert!(decode(&unsigned, None).is_ok());
+        assert!(decode(&unsigned, Some("secret")).is_err());
+    }
+
+    #[test]
+    fn params_to_send() {
+        let param = |name: &str, value| Param { name: name.into(), value, meta: None };
+        let from = vec![param("speed", Value::Range(10)), param("color", Value::Color([1, 2, 3].into()))];
+        let to = vec![
+            param("speed", Value::Range(20)),
+            param("color", Value::Color([1, 2, 3].into())),
+            param("reset", Value::Button),
+        ];
+        let changed = changed_params(&from, &to);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "speed");
+    }
+}
@@ -0,0 +1,89 @@
+//! Telling systemd when the app is ready and still alive (`sd_notify`)
+//!
+//! Only does anything when started by systemd with `Type=notify`, which sets
+//! `NOTIFY_SOCKET` (and `WATCHDOG_USEC` when `WatchdogSec` is set).
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+use crate::lights::LightsRemote;
+
+/// Send a state (e.g. `READY=1`) to systemd, if it is listening
+pub fn notify(state: &str) -> Result<()> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => notify_socket(&path, state),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Send a state to the socket at `path`, a leading `@` is an abstract socket
+fn notify_socket(path: &str, state: &str) -> Result<()> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        },
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr).context("Failed to notify systemd")?;
+    Ok(())
+}
+
+/// How often systemd expects to hear from us, if the watchdog is enabled
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Report ready once the lights controller answers, then keep pinging the
+/// watchdog for as long as it keeps answering
+pub async fn run(remote: LightsRemote) {
+    while !remote.responding().await {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    if let Err(e) = notify("READY=1") {
+        warn!("{e:?}");
+    }
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    debug!("Pinging the systemd watchdog every {:?}", interval / 2);
+    let mut timer = tokio::time::interval(interval / 2);
+    loop {
+        timer.tick().await;
+        // a stalled controller stops the pings, so systemd restarts us
+        if remote.responding().await {
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("{e:?}");
+            }
+        } else {
+            warn!("Lights controller is not responding, skipping watchdog ping");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notifies_socket() {
+        let path = std::env::temp_dir().join(format!("lights-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,74 @@
+//! Alerts for doorbells, timers and the like
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::alert::{Alert, AlertEffect};
+use crate::lights::LedColor;
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+/// Length of one flash, pulse or chase when the request doesn't say
+const DEFAULT_PERIOD_MS: u64 = 1000;
+/// Longest alert that can be asked for
+const MAX_LENGTH: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize, JsonSchema)]
+struct ShowAlert {
+    effect: AlertEffect,
+    color: LedColor,
+    /// length of one flash, pulse or chase in milliseconds
+    period: Option<u64>,
+    /// number of flashes, pulses or chases
+    count: Option<u32>,
+    /// show the alert for this many milliseconds instead of a count
+    duration: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(super) struct AlertStatus {
+    pub(super) active: bool,
+}
+
+async fn status(State(state): State<AppState>) -> Json<AlertStatus> {
+    Json(AlertStatus { active: state.alerts.active() })
+}
+
+async fn show(
+    State(state): State<AppState>,
+    Json(req): Json<ShowAlert>,
+) -> Result<StatusCode, AppError> {
+    let period = Duration::from_millis(req.period.unwrap_or(DEFAULT_PERIOD_MS));
+    if period.is_zero() {
+        return Err(AppError::bad_request(anyhow::anyhow!("The period can't be zero")));
+    }
+    let length = match req.duration {
+        Some(ms) => Some(Duration::from_millis(ms)),
+        None => period.checked_mul(req.count.unwrap_or(1)),
+    };
+    let Some(length) = length.filter(|length| period <= MAX_LENGTH && *length <= MAX_LENGTH) else {
+        return Err(AppError::bad_request(anyhow::anyhow!("Alerts can't be longer than {MAX_LENGTH:?}")));
+    };
+    state.alerts.play(Alert { effect: req.effect, color: req.color, period, length });
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn cancel(State(state): State<AppState>) -> Result<StatusCode, AppError> {
+    if !state.alerts.cancel().await {
+        return Err(AppError::not_found(anyhow::anyhow!("No alert is showing")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn api() -> Api {
+    Api::new("alerts")
+        .get("/alert", status, Doc::new("If an alert is showing").json::<AlertStatus>())
+        .post("/alert", show, Doc::new("Show an alert over the lights").body::<ShowAlert>().status(202))
+        .delete("/alert", cancel, Doc::new("Stop the alert").status(204))
+}
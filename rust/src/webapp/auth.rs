@@ -0,0 +1,165 @@
+//! Login sessions, API tokens and role checks for the web-app routes
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{LoginError, Role};
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+/// Name of the session cookie
+const SESSION_COOKIE: &str = "lights_session";
+
+/// The role of the request from its bearer token or session cookie, or
+/// `Admin` when authentication isn't set up
+fn request_role(state: &AppState, req: &Request) -> Option<Role> {
+    if !state.auth.file().enabled() {
+        return Some(Role::Admin);
+    }
+    let bearer = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return state.auth.file().check_token(token.trim());
+    }
+    CookieJar::from_headers(req.headers())
+        .get(SESSION_COOKIE)
+        .and_then(|cookie| state.auth.session(cookie.value()))
+}
+
+async fn authorize(state: AppState, role: Role, req: Request, next: Next) -> Response {
+    match request_role(&state, &req) {
+        Some(have) if have >= role => next.run(req).await,
+        Some(_) => StatusCode::FORBIDDEN.into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Middleware for routes that guests can use
+pub async fn require_guest(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    authorize(state, Role::Guest, req, next).await
+}
+
+/// Middleware for routes that only admins can use
+pub async fn require_admin(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    authorize(state, Role::Admin, req, next).await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct Login {
+    password: String,
+}
+
+async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    jar: CookieJar,
+    Json(req): Json<Login>,
+) -> Result<CookieJar, Response> {
+    // checking the password is slow on purpose, keep it off the async threads
+    let auth = state.auth.clone();
+    let res = tokio::task::spawn_blocking(move || auth.login(client.ip(), &req.password)).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    match res {
+        Ok(session) => {
+            info!("Web-app login");
+            let cookie = Cookie::build((SESSION_COOKIE, session))
+                .path("/")
+                .http_only(true)
+                .secure(!state.http_only)
+                .same_site(SameSite::Strict);
+            Ok(jar.add(cookie))
+        },
+        Err(LoginError::WrongPassword) => {
+            warn!("Failed web-app login from {}", client.ip());
+            Err(StatusCode::UNAUTHORIZED.into_response())
+        },
+        Err(LoginError::TooSoon(wait)) => {
+            let retry = wait.as_secs_f64().ceil().to_string();
+            Err((StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry)]).into_response())
+        },
+    }
+}
+
+async fn logout(State(state): State<AppState>, jar: CookieJar) -> CookieJar {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        state.auth.logout(cookie.value());
+    }
+    jar.remove(Cookie::build(SESSION_COOKIE).path("/"))
+}
+
+/// A token as listed by the API (without the secret)
+#[derive(Serialize, JsonSchema)]
+pub(super) struct TokenInfo {
+    pub(super) name: String,
+    pub(super) role: Role,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct NewToken {
+    name: String,
+    role: Role,
+}
+
+/// A newly created token, the only time the token itself is sent back
+#[derive(Serialize, JsonSchema)]
+pub(super) struct CreatedToken {
+    pub(super) name: String,
+    pub(super) role: Role,
+    pub(super) token: String,
+}
+
+async fn list_tokens(State(state): State<AppState>) -> Json<Vec<TokenInfo>> {
+    let file = state.auth.file();
+    Json(file.tokens()
+        .iter()
+        .map(|t| TokenInfo { name: t.name.clone(), role: t.role })
+        .collect())
+}
+
+async fn add_token(
+    State(state): State<AppState>,
+    Json(req): Json<NewToken>,
+) -> Result<Json<CreatedToken>, AppError> {
+    let token = state.auth
+        .update(|file| file.add_token(&req.name, req.role))
+        .map_err(AppError::bad_request)?;
+    Ok(Json(CreatedToken { name: req.name, role: req.role, token }))
+}
+
+async fn remove_token(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.auth
+        .update(|file| file.remove_token(&name))
+        .map_err(AppError::not_found)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Login and logout, these don't need authentication
+pub fn api() -> Api {
+    Api::new("auth")
+        .post("/login", login, Doc::new("Log in, setting a session cookie (429 after too many wrong passwords)").body::<Login>())
+        .post("/logout", logout, Doc::new("Log out, removing the session cookie"))
+}
+
+/// Token management, only for admins
+pub fn token_api() -> Api {
+    Api::new("auth")
+        .get("/tokens", list_tokens, Doc::new("The API tokens").json::<Vec<TokenInfo>>())
+        .post("/tokens", add_token, Doc::new("Create an API token").body::<NewToken>().json::<CreatedToken>())
+        .delete("/tokens/:name", remove_token, Doc::new("Remove an API token").status(204))
+}
@@ -0,0 +1,57 @@
+//! The overall brightness of the lights
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    Json,
+};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::lights::LightsCommand;
+use crate::saved::Saved;
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+/// How long a brightness change takes when the request doesn't say
+const DEFAULT_FADE_MS: u64 = 500;
+
+#[derive(Serialize, JsonSchema)]
+pub(super) struct Brightness {
+    pub(super) brightness: u8,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct SetBrightness {
+    brightness: u8,
+    /// length of the fade in milliseconds
+    fade: Option<u64>,
+}
+
+async fn get_brightness(State(state): State<AppState>) -> Result<Json<Brightness>, AppError> {
+    let lights = state.remote.state().await?;
+    Ok(Json(Brightness { brightness: lights.config.brightness }))
+}
+
+async fn set_brightness(
+    State(state): State<AppState>,
+    Json(req): Json<SetBrightness>,
+) -> Result<Json<Brightness>, AppError> {
+    let fade = Duration::from_millis(req.fade.unwrap_or(DEFAULT_FADE_MS));
+    state.remote.send(LightsCommand::Brightness { level: req.brightness, fade }).await?;
+    // the lights already changed, so a failure to save isn't the caller's problem
+    if let Err(e) = Saved::update(|saved| saved.brightness = Some(req.brightness)) {
+        warn!("Unable to save brightness: {e:?}");
+    }
+    Ok(Json(Brightness { brightness: req.brightness }))
+}
+
+pub fn api() -> Api {
+    Api::new("lights")
+        .get("/brightness", get_brightness, Doc::new("The overall brightness").json::<Brightness>())
+        .put("/brightness", set_brightness, Doc::new("Fade to a new brightness, saved across restarts")
+            .body::<SetBrightness>()
+            .json::<Brightness>())
+}
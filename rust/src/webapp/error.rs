@@ -0,0 +1,40 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+
+/// Error returned from the web-app handlers
+///
+/// Wraps an `anyhow::Error` so handlers can use `?` and the error gets sent
+/// back to the front-end with a status code.
+pub struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl AppError {
+    /// Error caused by a bad request from the front-end
+    pub fn bad_request<E: Into<anyhow::Error>>(error: E) -> Self {
+        AppError { status: StatusCode::BAD_REQUEST, error: error.into() }
+    }
+
+    /// Error for something the front-end asked for that doesn't exist
+    pub fn not_found<E: Into<anyhow::Error>>(error: E) -> Self {
+        AppError { status: StatusCode::NOT_FOUND, error: error.into() }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        debug!("Request failed: {:?}", self.error);
+        (self.status, format!("{:#}", self.error)).into_response()
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(error: E) -> Self {
+        AppError { status: StatusCode::INTERNAL_SERVER_ERROR, error: error.into() }
+    }
+}
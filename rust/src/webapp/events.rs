@@ -0,0 +1,34 @@
+//! A stream of controller events as Server-Sent Events
+use std::convert::Infallible;
+
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::{Event, EVENTS};
+
+use super::openapi::{Api, Doc};
+
+/// Every event as JSON in the `data` of an SSE message
+async fn events() -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let stream = stream::unfold(EVENTS.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    return Some((Ok(SseEvent::default().data(data)), events));
+                },
+                Err(RecvError::Lagged(missed)) => debug!("Event listener missed {missed} events"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub fn api() -> Api {
+    Api::new("events")
+        .get("/events", events, Doc::new("Server-Sent Events, each with an Event as JSON data").events::<Event>())
+}
@@ -0,0 +1,32 @@
+//! Liveness and readiness checks for monitoring
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Json,
+};
+
+use crate::lights::HealthStatus;
+
+use super::{AppState, openapi::{Api, Doc}};
+
+/// Alive while the lights controller answers requests
+async fn healthz(State(state): State<AppState>) -> (StatusCode, Json<HealthStatus>) {
+    let alive = state.remote.responding().await;
+    let status = if alive { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(state.remote.health().status()))
+}
+
+/// Ready while the controller answers and the driver is rendering
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<HealthStatus>) {
+    let alive = state.remote.responding().await;
+    let health = state.remote.health().status();
+    let status = if alive && health.driver_ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+/// Health checks, these don't need authentication
+pub fn api() -> Api {
+    Api::new("health")
+        .get("/healthz", healthz, Doc::new("Alive while the controller answers (503 if not)").json::<HealthStatus>())
+        .get("/readyz", readyz, Doc::new("Ready while the LED driver is rendering (503 if not)").json::<HealthStatus>())
+}
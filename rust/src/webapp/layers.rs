@@ -0,0 +1,80 @@
+//! Layers shown on top of the lights, e.g. a highlight over a running mode
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::lights::{Blend, LedColor, Layer, LayerInfo, LightsCommand, LightsError};
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct SetLayer {
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    blend: Blend,
+    #[serde(default = "full_opacity")]
+    opacity: u8,
+    /// one color for every LED
+    color: Option<LedColor>,
+    /// a color for each LED
+    colors: Option<Vec<LedColor>>,
+    /// remove the layer after this many milliseconds
+    timeout: Option<u64>,
+}
+
+fn full_opacity() -> u8 {
+    255
+}
+
+async fn list(State(state): State<AppState>) -> Result<Json<Vec<LayerInfo>>, AppError> {
+    Ok(Json(state.remote.state().await?.layers))
+}
+
+async fn set(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<SetLayer>,
+) -> Result<StatusCode, AppError> {
+    let colors = match (req.colors, req.color) {
+        (Some(colors), _) => colors,
+        (None, Some(color)) => vec![color; state.remote.led_count()],
+        (None, None) => return Err(AppError::bad_request(anyhow::anyhow!("A color or colors are needed"))),
+    };
+    let layer = Layer {
+        name,
+        priority: req.priority,
+        blend: req.blend,
+        opacity: req.opacity,
+        colors,
+        timeout: req.timeout.map(Duration::from_millis),
+    };
+    state.remote.send(LightsCommand::SetLayer(layer)).await
+        .map_err(AppError::bad_request)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    match state.remote.send(LightsCommand::ClearLayer(name)).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(e @ LightsError::NoLayer(_)) => Err(AppError::not_found(e)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn api() -> Api {
+    Api::new("layers")
+        .get("/layers", list, Doc::new("The layers shown on top of the lights").json::<Vec<LayerInfo>>())
+        .put("/layers/:name", set, Doc::new("Add or replace a layer").body::<SetLayer>().status(204))
+        .delete("/layers/:name", remove, Doc::new("Remove a layer").status(204))
+}
@@ -3,37 +3,112 @@ use anyhow::{Context, Result};
 use log::{debug, error, info, trace, warn};
 
 use axum::{
+    extract::State,
+    middleware,
     response::{Html, IntoResponse},
     routing::get,
     Router,
 };
+use serde_json::Value;
 use tower_http::services::ServeDir;
 
-use crate::lights::{LightsCommand, LightsRemote};
+use crate::lights::LightsCommand;
 
-pub async fn build(remote: &LightsRemote) -> Result<Router> {
+use super::{AppError, AppState, alert, auth, brightness, events, health, layers, metrics, modes, openapi, palettes, preview, recording, scripts, sleep, state};
+use super::openapi::{Access, Api, Doc};
+
+/// Quick and dirty on-off switch
+async fn on(State(state): State<AppState>) -> Result<(), AppError> {
+    Ok(state.remote.send(LightsCommand::On).await?)
+}
+
+async fn off(State(state): State<AppState>) -> Result<(), AppError> {
+    Ok(state.remote.send(LightsCommand::Off).await?)
+}
+
+/// Routes that don't need authentication
+fn public_api() -> Api {
+    Api::new("auth")
+        // login and logout
+        .merge(auth::api())
+        // health checks
+        .merge(health::api())
+        // API description
+        .merge(openapi::api())
+        .access(Access::Public)
+}
+
+/// Routes that guests are allowed to use
+fn guest_api() -> Api {
+    Api::new("lights")
+        .get("/on", on, Doc::new("Turn the lights on"))
+        .get("/off", off, Doc::new("Turn the lights off"))
+        // lights modes and their parameters
+        .merge(modes::api())
+        // what the lights are showing
+        .merge(state::api())
+        // overall brightness
+        .merge(brightness::api())
+        // palettes for the modes
+        .merge(palettes::api())
+        // layers on top of the lights
+        .merge(layers::api())
+        // temporary alert effects
+        .merge(alert::api())
+        // turning the lights off later
+        .merge(sleep::api())
+        // prometheus metrics
+        .merge(metrics::api())
+        // stream of controller events
+        .merge(events::api())
+        // the lights drawn in the browser
+        .merge(preview::api())
+        .access(Access::Guest)
+}
+
+/// Routes that only admins are allowed to use
+fn admin_api() -> Api {
+    Api::new("admin")
+        // recording the frames shown
+        .merge(recording::api())
+        // user effect scripts
+        .merge(scripts::api())
+        // API tokens
+        .merge(auth::token_api())
+        .access(Access::Admin)
+}
+
+/// The OpenAPI document for every route of the app
+pub fn document() -> Value {
+    openapi::document([public_api(), guest_api(), admin_api()])
+}
+
+pub async fn build(state: AppState) -> Result<Router> {
     trace!("Building Axum web-app objects");
-    // clone the remote to pass to router
-    let remote_on = remote.clone();
-    let remote_off = remote.clone();
     // read in the html for a single page applications
     let index = tokio::fs::read_to_string("www/index.html")
         .await
         .context("Failed to read index.html")?;
 
+    let guest = guest_api()
+        .into_router()
+        .route_layer(middleware::from_fn_with_state(state.clone(), sleep::track_activity))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_guest));
+
+    let admin = admin_api()
+        .into_router()
+        .route_layer(middleware::from_fn_with_state(state.clone(), sleep::track_activity))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin));
+
     // build our application
     Ok(Router::new()
         // default route
         .route("/", get(|| async { Html(index).into_response() }))
         // static files
         .nest_service("/assets", ServeDir::new("www/assets"))
-        // quick and dirty on-off switch
-        .route(
-            "/on",
-            get(|| async move { remote_on.send(LightsCommand::On).await.unwrap() }),
-        )
-        .route(
-            "/off",
-            get(|| async move { remote_off.send(LightsCommand::Off).await.unwrap() }),
-        ))
+        .merge(public_api().into_router())
+        .merge(guest)
+        .merge(admin)
+        .layer(middleware::from_fn(metrics::count_requests))
+        .with_state(state))
 }
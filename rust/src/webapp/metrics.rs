@@ -0,0 +1,57 @@
+//! Prometheus metrics
+//!
+//! `/metrics` needs a guest login like the rest of the API when a password is
+//! set, so Prometheus scrapes it with an API token as the bearer token.
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::metrics::{Gauges, METRICS};
+
+use super::{AppState, openapi::{Api, Doc}};
+
+/// Longest wait for the lights controller, the metrics are still worth having
+/// when it is stuck
+const STATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let modes = {
+        let modes = state.modes.lock().await;
+        modes.available()
+            .into_iter()
+            .map(|name| {
+                let active = modes.active() == Some(name.as_str());
+                (name, active)
+            })
+            .collect()
+    };
+    let lights = match tokio::time::timeout(STATE_TIMEOUT, state.remote.state()).await {
+        Ok(Ok(lights)) => Some(lights),
+        _ => None,
+    };
+    let gauges = Gauges {
+        queue_depth: state.remote.queue_depth(),
+        modes,
+        power_watts: lights.as_ref().map(|lights| lights.power_watts()),
+        fps: lights.as_ref().map(|lights| lights.frames.fps),
+    };
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], METRICS.render(&gauges))
+}
+
+/// Middleware counting every request by method and response status
+pub async fn count_requests(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let res = next.run(req).await;
+    METRICS.request(method.as_str(), res.status().as_u16());
+    res
+}
+
+pub fn api() -> Api {
+    Api::new("metrics")
+        .get("/metrics", get_metrics, Doc::new("Prometheus metrics").text("text/plain"))
+}
@@ -1,15 +1,21 @@
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
 
-use axum_server::{
-    Handle,
-    tls_rustls::RustlsConfig
-};
+use axum_server::Handle;
 
-use crate::lights::LightsRemote;
+use crate::alert::Alerts;
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::lights::{LightsRemote, PixelMap};
+use crate::mode::SharedModes;
+use crate::sleep::SleepTimer;
+
+mod error;
+use error::AppError;
 
 mod redirect;
 pub use redirect::redirect_http_to_https;
@@ -17,25 +23,67 @@ pub use redirect::redirect_http_to_https;
 mod shutdown;
 pub use shutdown::shutdown_signal;
 
+mod tls;
+
 mod main_app;
+mod alert;
+mod auth;
+mod brightness;
+mod events;
+mod health;
+mod layers;
+mod metrics;
+mod modes;
+mod openapi;
+mod palettes;
+mod preview;
+mod recording;
+mod scripts;
+mod sleep;
+mod state;
+
+/// Shared state for the web-app handlers
+#[derive(Clone)]
+pub struct AppState {
+    /// remote to send commands to the lights controller
+    pub remote: LightsRemote,
+    /// the running lights mode
+    pub modes: SharedModes,
+    /// the alert showing on top of the lights
+    pub alerts: Arc<Alerts>,
+    /// turning the lights off later
+    pub sleep: Arc<SleepTimer>,
+    /// login sessions and API tokens
+    pub auth: Arc<Auth>,
+    /// the web-app is served over plain HTTP, where browsers drop `Secure`
+    /// cookies
+    pub http_only: bool,
+    /// where the LEDs are, for the preview
+    pub map: Arc<PixelMap>,
+}
 
 /// start the web-app by starting the web-server
-pub async fn start(handle: Handle, remote: &LightsRemote) -> Result<()> {
+pub async fn start(handle: Handle, state: AppState, config: Config) -> Result<()> {
     debug!("Starting the lights controller web-server");
 
-    // set up the TLS config
-    let config = RustlsConfig::from_pem_file(
-        PathBuf::from("secrets/lights.crt"),
-        PathBuf::from("secrets/lights.key"),
-    )
-    .await
-    .context("Failed to load TLS config")?;
+    let app = main_app::build(state).await?;
+
+    if config.http_only {
+        warn!("Serving plain HTTP on port {}", config.http_port);
+        axum_server::bind(([0,0,0,0], config.http_port).into())
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await.context("Error starting web-server")?;
+        return Ok(());
+    }
 
-    let app = main_app::build(remote).await?;
+    // set up the TLS config, and pick up new certificates as they show up
+    let tls_config = tls::load(&config.tls).await?;
+    let _reload_task = tokio::spawn(tls::watch(tls_config.clone(), config.tls.clone()));
 
-    axum_server::bind_rustls(([0,0,0,0],443).into(), config)
+    axum_server::bind_rustls(([0,0,0,0], config.https_port).into(), tls_config)
     .handle(handle)
-    .serve(app.into_make_service())
+    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
     .await.context("Error starting web-server")?;
 
     Ok(())
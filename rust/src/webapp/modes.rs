@@ -0,0 +1,68 @@
+//! Selecting lights modes and adjusting their parameters
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::mode::Param;
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+/// The available modes and the one that is running
+#[derive(Serialize, JsonSchema)]
+pub(super) struct Modes {
+    pub(super) available: Vec<String>,
+    pub(super) active: Option<String>,
+    /// last error from the running mode
+    pub(super) error: Option<String>,
+}
+
+async fn get_modes(State(state): State<AppState>) -> Json<Modes> {
+    let modes = state.modes.lock().await;
+    Json(Modes {
+        available: modes.available(),
+        active: modes.active().map(String::from),
+        error: modes.error(),
+    })
+}
+
+async fn select_mode(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<Param>>, AppError> {
+    let mut modes = state.modes.lock().await;
+    if !modes.available().contains(&name) {
+        return Err(AppError::bad_request(anyhow::anyhow!("Unknown mode {name}")));
+    }
+    Ok(Json(modes.select(&name)?))
+}
+
+async fn stop_mode(State(state): State<AppState>) -> Result<(), AppError> {
+    Ok(state.modes.lock().await.stop()?)
+}
+
+async fn get_params(State(state): State<AppState>) -> Result<Json<Vec<Param>>, AppError> {
+    Ok(Json(state.modes.lock().await.params()?))
+}
+
+async fn update_params(
+    State(state): State<AppState>,
+    Json(params): Json<Vec<Param>>,
+) -> Result<Json<Vec<Param>>, AppError> {
+    let mut modes = state.modes.lock().await;
+    modes.update(params).map_err(AppError::bad_request)?;
+    Ok(Json(modes.params()?))
+}
+
+pub fn api() -> Api {
+    Api::new("modes")
+        .get("/modes", get_modes, Doc::new("The available modes and the one running").json::<Modes>())
+        .delete("/modes", stop_mode, Doc::new("Stop the running mode"))
+        .post("/modes/:name", select_mode, Doc::new("Start a mode").json::<Vec<Param>>())
+        .get("/params", get_params, Doc::new("The parameters of the running mode").json::<Vec<Param>>())
+        .put("/params", update_params, Doc::new("Change parameters of the running mode")
+            .body::<Vec<Param>>()
+            .json::<Vec<Param>>())
+}
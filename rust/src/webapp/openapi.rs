@@ -0,0 +1,431 @@
+//! OpenAPI description of the web API, served with a page to browse it
+//!
+//! The modules add their routes to an `Api` with a `Doc` for each, so the
+//! router and the document come from the same table. The JSON schemas are
+//! generated from the request and response types.
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use axum::{
+    handler::Handler,
+    response::Html,
+    routing::{MethodFilter, MethodRouter},
+    Json, Router,
+};
+use schemars::{generate::SchemaSettings, JsonSchema, Schema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+
+use super::AppState;
+
+/// Page rendering the document, kept in the binary so it works without
+/// access to the internet
+const DOCS_PAGE: &str = include_str!("docs.html");
+
+/// How a JSON type is added to the document
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn subschema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+/// What a request or response carries
+#[derive(Clone, Copy)]
+enum Content {
+    Json(SchemaFn),
+    /// text with the media type
+    Text(&'static str),
+    /// Server-Sent Events, each with JSON data
+    Events(SchemaFn),
+}
+
+impl Content {
+    fn describe(self, generator: &mut SchemaGenerator) -> Value {
+        let (media, schema) = match self {
+            Content::Json(schema) => ("application/json", schema(generator).to_value()),
+            Content::Text(media) => (media, json!({ "type": "string" })),
+            Content::Events(schema) => ("text/event-stream", json!({
+                "type": "string",
+                "contentMediaType": "application/json",
+                "contentSchema": schema(generator).to_value(),
+            })),
+        };
+        json!({ media: { "schema": schema } })
+    }
+}
+
+/// Who can use a route
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    Guest,
+    Admin,
+}
+
+/// The description of a route, what it takes and gives back
+#[derive(Clone, Copy)]
+pub struct Doc {
+    summary: &'static str,
+    body: Option<Content>,
+    status: u16,
+    response: Option<Content>,
+}
+
+impl Doc {
+    pub fn new(summary: &'static str) -> Self {
+        Doc { summary, body: None, status: 200, response: None }
+    }
+
+    /// Takes a JSON body
+    pub fn body<T: JsonSchema>(self) -> Self {
+        Doc { body: Some(Content::Json(subschema::<T>)), ..self }
+    }
+
+    /// Takes a plain text body
+    pub fn text_body(self) -> Self {
+        Doc { body: Some(Content::Text("text/plain")), ..self }
+    }
+
+    /// Answers with JSON
+    pub fn json<T: JsonSchema>(self) -> Self {
+        Doc { response: Some(Content::Json(subschema::<T>)), ..self }
+    }
+
+    /// Answers with text of the media type
+    pub fn text(self, media: &'static str) -> Self {
+        Doc { response: Some(Content::Text(media)), ..self }
+    }
+
+    /// Answers with a stream of Server-Sent Events, each with JSON data
+    pub fn events<T: JsonSchema>(self) -> Self {
+        Doc { response: Some(Content::Events(subschema::<T>)), ..self }
+    }
+
+    /// Answers with another status than 200
+    pub fn status(self, status: u16) -> Self {
+        Doc { status, ..self }
+    }
+}
+
+/// A documented route
+struct Operation {
+    tag: &'static str,
+    /// the path as given to axum
+    path: &'static str,
+    method: &'static str,
+    access: Access,
+    doc: Doc,
+}
+
+impl Operation {
+    /// The path with the parameters written the OpenAPI way, and their names
+    fn path(&self) -> (String, Vec<&'static str>) {
+        let mut params = Vec::new();
+        let parts: Vec<String> = self.path.split('/')
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => {
+                    params.push(name);
+                    format!("{{{name}}}")
+                },
+                None => part.to_string(),
+            })
+            .collect();
+        (parts.join("/"), params)
+    }
+
+    fn describe(&self, params: &[&str], generator: &mut SchemaGenerator) -> Value {
+        let summary = match self.access {
+            Access::Admin => format!("{} (admin)", self.doc.summary),
+            _ => self.doc.summary.to_string(),
+        };
+        let mut response = json!({ "description": "OK" });
+        if let Some(content) = self.doc.response {
+            response["content"] = content.describe(generator);
+        }
+        let mut op = json!({
+            "tags": [self.tag],
+            "summary": summary,
+            "responses": Map::from_iter([(self.doc.status.to_string(), response)]),
+        });
+        if !params.is_empty() {
+            op["parameters"] = params.iter()
+                .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+                .collect();
+        }
+        if let Some(content) = self.doc.body {
+            op["requestBody"] = json!({ "required": true, "content": content.describe(generator) });
+        }
+        if self.access == Access::Public {
+            op["security"] = json!([]);
+        }
+        op
+    }
+}
+
+/// Routes and their descriptions
+///
+/// Adding a route needs its `Doc`, so there is nothing routed that isn't
+/// documented. Pages for people are added with `page` and left out.
+pub struct Api {
+    tag: &'static str,
+    routes: BTreeMap<&'static str, MethodRouter<AppState>>,
+    operations: Vec<Operation>,
+}
+
+impl Api {
+    /// Routes grouped under `tag` in the document
+    pub fn new(tag: &'static str) -> Self {
+        Api { tag, routes: BTreeMap::new(), operations: Vec::new() }
+    }
+
+    fn on<H, T>(mut self, method: &'static str, filter: MethodFilter, path: &'static str, handler: H, doc: Doc) -> Self
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let route = self.routes.remove(path).unwrap_or_default();
+        self.routes.insert(path, route.on(filter, handler));
+        self.operations.push(Operation { tag: self.tag, path, method, access: Access::Guest, doc });
+        self
+    }
+
+    pub fn get<H: Handler<T, AppState>, T: 'static>(self, path: &'static str, handler: H, doc: Doc) -> Self {
+        self.on("get", MethodFilter::GET, path, handler, doc)
+    }
+
+    pub fn put<H: Handler<T, AppState>, T: 'static>(self, path: &'static str, handler: H, doc: Doc) -> Self {
+        self.on("put", MethodFilter::PUT, path, handler, doc)
+    }
+
+    pub fn post<H: Handler<T, AppState>, T: 'static>(self, path: &'static str, handler: H, doc: Doc) -> Self {
+        self.on("post", MethodFilter::POST, path, handler, doc)
+    }
+
+    pub fn delete<H: Handler<T, AppState>, T: 'static>(self, path: &'static str, handler: H, doc: Doc) -> Self {
+        self.on("delete", MethodFilter::DELETE, path, handler, doc)
+    }
+
+    /// A page for people rather than the API, not documented
+    pub fn page<H: Handler<T, AppState>, T: 'static>(mut self, path: &'static str, handler: H) -> Self {
+        let route = self.routes.remove(path).unwrap_or_default();
+        self.routes.insert(path, route.on(MethodFilter::GET, handler));
+        self
+    }
+
+    /// Add the routes of another `Api`, keeping their tags
+    pub fn merge(mut self, other: Api) -> Self {
+        for (path, route) in other.routes {
+            let route = match self.routes.remove(path) {
+                Some(existing) => existing.merge(route),
+                None => route,
+            };
+            self.routes.insert(path, route);
+        }
+        self.operations.extend(other.operations);
+        self
+    }
+
+    /// Set who can use the routes added so far
+    pub fn access(mut self, access: Access) -> Self {
+        for op in self.operations.iter_mut() {
+            op.access = access;
+        }
+        self
+    }
+
+    pub fn into_router(self) -> Router<AppState> {
+        self.routes.into_iter().fold(Router::new(), |router, (path, route)| router.route(path, route))
+    }
+}
+
+/// The whole OpenAPI document for the routes
+pub fn document(apis: impl IntoIterator<Item = Api>) -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| settings.definitions_path = "/components/schemas".into())
+        .into_generator();
+    let mut paths = Map::new();
+    for op in apis.into_iter().flat_map(|api| api.operations) {
+        let (path, params) = op.path();
+        let ops = paths.entry(path).or_insert_with(|| json!({}));
+        ops[op.method] = op.describe(&params, &mut generator);
+    }
+    let schemas: Map<String, Value> = generator.take_definitions(true).into_iter().collect();
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Lights",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Controls the LED lights. Routes need a guest login unless marked otherwise, \
+                and the ones marked (admin) need an admin. There is no login when no password is set.",
+        },
+        "security": [{ "session": [] }, { "token": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "session": { "type": "apiKey", "in": "cookie", "name": "lights_session" },
+                "token": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+/// The document for the routes of the app, built once
+static DOCUMENT: LazyLock<Value> = LazyLock::new(super::main_app::document);
+
+async fn openapi() -> Json<&'static Value> {
+    Json(&DOCUMENT)
+}
+
+async fn docs() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
+
+/// The API description, this doesn't need authentication
+pub fn api() -> Api {
+    Api::new("docs")
+        .get("/openapi.json", openapi, Doc::new("This description of the API").json::<Value>())
+        .page("/docs", docs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use serde::Serialize;
+
+    use super::*;
+    use crate::auth::Role;
+    use crate::events::Event;
+    use crate::lights::{Blend, DriverConfig, HealthStatus, LayerInfo, LedColor, LightsState, PixelMap};
+    use crate::mode::{Meta, Param, Value as ParamValue};
+    use crate::palette::{Interpolation, Palette, Stop};
+    use crate::sleep::SleepStatus;
+    use crate::webapp::{alert::AlertStatus, auth::{CreatedToken, TokenInfo}, brightness::Brightness, modes::Modes, state::{CurrentState, Source}};
+
+    fn document() -> Value {
+        super::super::main_app::document()
+    }
+
+    /// Every `$ref` in a value
+    fn refs(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(target)) = map.get("$ref") {
+                    found.push(target.clone());
+                }
+                map.values().for_each(|value| refs(value, found));
+            },
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => {},
+        }
+    }
+
+    #[test]
+    fn references_resolve() {
+        let doc = document();
+        let mut found = Vec::new();
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(doc["components"]["schemas"].get(name).is_some(), "missing schema {name}");
+        }
+    }
+
+    #[test]
+    fn operations() {
+        let doc = document();
+        let put = &doc["paths"]["/params"]["put"];
+        assert_eq!(put["requestBody"]["content"]["application/json"]["schema"]["items"], json!({ "$ref": "#/components/schemas/Param" }));
+        assert_eq!(doc["paths"]["/healthz"]["get"]["security"], json!([]));
+        assert!(doc["paths"]["/layers/{name}"]["delete"]["responses"].get("204").is_some());
+        assert_eq!(doc["paths"]["/modes/{name}"]["post"]["parameters"][0]["name"], "name");
+        assert_eq!(doc["paths"]["/tokens"]["get"]["summary"], "The API tokens (admin)");
+        assert!(doc["paths"].get("/docs").is_none());
+    }
+
+    /// The schema of the JSON a route answers with
+    fn response_schema(doc: &Value, path: &str, method: &str) -> Value {
+        let responses = doc["paths"][path][method]["responses"].as_object().unwrap();
+        let (_, response) = responses.iter().next().unwrap();
+        let mut schema = response["content"]["application/json"]["schema"].clone();
+        assert!(!schema.is_null(), "no JSON answer for {method} {path}");
+        schema["components"] = doc["components"].clone();
+        schema
+    }
+
+    fn check<T: Serialize>(doc: &Value, path: &str, method: &str, value: T) {
+        let schema = response_schema(doc, path, method);
+        let instance = serde_json::to_value(value).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let errors: Vec<String> = validator.iter_errors(&instance).map(|e| format!("{e} at {}", e.instance_path)).collect();
+        assert!(errors.is_empty(), "{method} {path} answered {instance} which doesn't match: {errors:?}");
+    }
+
+    fn params() -> Vec<Param> {
+        vec![
+            Param { name: "on".into(), value: ParamValue::Toggle(true), meta: Some(Meta::Toggle { on: "On".into(), off: "Off".into() }) },
+            Param { name: "go".into(), value: ParamValue::Button, meta: Some(Meta::Button { label: "Go".into() }) },
+            Param { name: "speed".into(), value: ParamValue::Range(100), meta: Some(Meta::Range { min: 10, max: 400 }) },
+            Param { name: "color".into(), value: ParamValue::Color([255, 136, 0].into()), meta: None },
+            Param { name: "kind".into(), value: ParamValue::Select("fast".into()), meta: Some(Meta::Select { options: vec!["fast".into()] }) },
+        ]
+    }
+
+    #[test]
+    fn real_answers_match() {
+        let doc = document();
+        let color: LedColor = [255, 136, 0].into();
+        let layer = LayerInfo { name: "flash".into(), priority: 1, blend: Blend::Alpha, opacity: 255, remaining_ms: Some(10) };
+        let lights = LightsState {
+            on: true,
+            config: DriverConfig {
+                strips: vec![serde_json::from_value(json!({ "name": "tree", "pin": 18, "count": 2 })).unwrap()],
+                order: vec!["tree".into(), "roof".into()],
+                outputs: vec![serde_json::from_value(json!({ "name": "roof", "protocol": "ddp", "address": "10.0.0.9", "count": 1 })).unwrap()],
+                brightness: 200,
+                fps: 50,
+            },
+            recording: false,
+            leds: vec![color; 3],
+            layers: vec![layer.clone(), LayerInfo { remaining_ms: None, ..layer.clone() }],
+            frames: Default::default(),
+        };
+        for source in [Source::Mode { name: "rainbow".into() }, Source::Manual] {
+            check(&doc, "/state", "get", CurrentState { source, lights: lights.clone() });
+        }
+        check(&doc, "/layers", "get", vec![layer]);
+        check(&doc, "/params", "get", params());
+        check(&doc, "/modes/{name}", "post", params());
+        check(&doc, "/modes", "get", Modes { available: vec!["rainbow".into()], active: Some("rainbow".into()), error: None });
+        check(&doc, "/brightness", "get", Brightness { brightness: 128 });
+        check(&doc, "/alert", "get", AlertStatus { active: true });
+        check(&doc, "/healthz", "get", HealthStatus { running: true, driver_ok: false, restarts: 2 });
+        check(&doc, "/sleep", "get", Some(SleepStatus { remaining_ms: 1000, fade_ms: 500, fading: false }));
+        check(&doc, "/sleep", "get", None::<SleepStatus>);
+        check(&doc, "/sleep", "put", SleepStatus { remaining_ms: 0, fade_ms: 500, fading: true });
+        let palette = Palette { stops: vec![Stop { position: 0.0, color }, Stop { position: 1.0, color }], interpolation: Interpolation::Hsv };
+        check(&doc, "/palettes/{name}", "get", palette.clone());
+        check(&doc, "/palettes", "get", BTreeMap::from([("fire".to_string(), palette)]));
+        check(&doc, "/preview/map", "get", PixelMap { positions: vec![[0.0, 1.0, 2.0]] });
+        check(&doc, "/tokens", "get", vec![TokenInfo { name: "tv".into(), role: Role::Guest }]);
+        check(&doc, "/tokens", "post", CreatedToken { name: "tv".into(), role: Role::Admin, token: "secret".into() });
+        check(&doc, "/recordings", "get", vec!["evening".to_string()]);
+
+        // each event is the data of one Server-Sent Event
+        let events = [
+            Event::Command { command: "on" },
+            Event::ModeChanged { mode: None },
+            Event::ParamsUpdated { params: params() },
+            Event::DriverError { error: "gone".into() },
+            Event::ScheduleFired { schedule: "evening" },
+        ];
+        let mut schema = doc["paths"]["/events"]["get"]["responses"]["200"]["content"]["text/event-stream"]["schema"]["contentSchema"].clone();
+        schema["components"] = doc["components"].clone();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        for event in events {
+            let instance = serde_json::to_value(event).unwrap();
+            assert!(validator.is_valid(&instance), "{instance} doesn't match the Event schema");
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Listing the palettes and adding new ones
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    Json,
+};
+
+use crate::palette::{self, Palette};
+
+use super::{AppError, openapi::{Api, Doc}};
+
+async fn list() -> Json<BTreeMap<String, Palette>> {
+    Json(palette::all())
+}
+
+async fn read(Path(name): Path<String>) -> Result<Json<Palette>, AppError> {
+    match palette::get(&name) {
+        Some(palette) => Ok(Json(Palette::clone(&palette))),
+        None => Err(AppError::not_found(anyhow::anyhow!("No palette named {name}"))),
+    }
+}
+
+async fn write(Path(name): Path<String>, Json(palette): Json<Palette>) -> Result<StatusCode, AppError> {
+    palette::set(&name, palette).map_err(AppError::bad_request)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove(Path(name): Path<String>) -> Result<StatusCode, AppError> {
+    if !palette::remove(&name)? {
+        return Err(AppError::not_found(anyhow::anyhow!("No added palette named {name}")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn api() -> Api {
+    Api::new("palettes")
+        .get("/palettes", list, Doc::new("Every palette by name").json::<BTreeMap<String, Palette>>())
+        .get("/palettes/:name", read, Doc::new("A palette").json::<Palette>())
+        .put("/palettes/:name", write, Doc::new("Add or replace a palette (not a built-in one)").body::<Palette>().status(204))
+        .delete("/palettes/:name", remove, Doc::new("Remove an added palette").status(204))
+}
@@ -0,0 +1,63 @@
+//! Watching the lights from the browser: the LED positions, a stream of the
+//! frames and a page that draws them
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        Html,
+    },
+    Json,
+};
+use futures_util::stream::{self, Stream};
+use tokio::time::MissedTickBehavior;
+
+use crate::lights::{LedColor, PixelMap};
+
+use super::{AppState, openapi::{Api, Doc}};
+
+/// Shortest time between frames sent to the preview
+const PREVIEW_TIME: Duration = Duration::from_millis(100);
+
+const PREVIEW_PAGE: &str = include_str!("preview.html");
+
+async fn page() -> Html<&'static str> {
+    Html(PREVIEW_PAGE)
+}
+
+async fn map(State(state): State<AppState>) -> Json<PixelMap> {
+    Json(state.map.as_ref().clone())
+}
+
+/// The colors as `rrggbb` for every LED, one after the other
+fn encode(colors: &[LedColor]) -> String {
+    colors.iter()
+        .map(|color| format!("{:02x}{:02x}{:02x}", color.r, color.g, color.b))
+        .collect()
+}
+
+/// The frames the controller shows, at most every `PREVIEW_TIME`, starting
+/// with the one showing now
+async fn frames(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let mut frames = state.remote.frames();
+    frames.mark_changed();
+    let mut interval = tokio::time::interval(PREVIEW_TIME);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let stream = stream::unfold((frames, interval), |(mut frames, mut interval)| async move {
+        frames.changed().await.ok()?;
+        interval.tick().await;
+        let data = encode(&frames.borrow_and_update());
+        Some((Ok(SseEvent::default().data(data)), (frames, interval)))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub fn api() -> Api {
+    Api::new("preview")
+        .get("/preview", page, Doc::new("A page that draws the lights as they are now").text("text/html"))
+        .get("/preview/map", map, Doc::new("The position of every LED").json::<PixelMap>())
+        .get("/preview/frames", frames, Doc::new("Server-Sent Events with the colors of every LED as rrggbb hex, \
+            one after the other, at most ten times a second").text("text/event-stream"))
+}
@@ -0,0 +1,47 @@
+//! Recording the frames shown by the lights
+use std::path::PathBuf;
+
+use axum::{
+    extract::State,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::lights::LightsCommand;
+use crate::recording::{self, RECORDINGS_DIR};
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+#[derive(Deserialize, JsonSchema)]
+struct StartRecording {
+    /// file name for the recording inside the recordings directory
+    name: String,
+}
+
+async fn list(State(_state): State<AppState>) -> Json<Vec<String>> {
+    Json(recording::list_recordings())
+}
+
+async fn start(
+    State(state): State<AppState>,
+    Json(req): Json<StartRecording>,
+) -> Result<(), AppError> {
+    // only allow plain file names so recordings stay in the recordings directory
+    if req.name.is_empty() || req.name.contains(['/', '\\']) || req.name.starts_with('.') {
+        return Err(AppError::bad_request(anyhow::anyhow!("Invalid recording name {}", req.name)));
+    }
+    let path = PathBuf::from(RECORDINGS_DIR).join(req.name);
+    Ok(state.remote.send(LightsCommand::StartRecording(path)).await?)
+}
+
+async fn stop(State(state): State<AppState>) -> Result<(), AppError> {
+    Ok(state.remote.send(LightsCommand::StopRecording).await?)
+}
+
+pub fn api() -> Api {
+    Api::new("recordings")
+        .get("/recordings", list, Doc::new("The saved recordings").json::<Vec<String>>())
+        .post("/recordings/start", start, Doc::new("Start recording the frames shown").body::<StartRecording>())
+        .post("/recordings/stop", stop, Doc::new("Stop recording"))
+}
@@ -1,5 +1,5 @@
-//! A simple redirect from port 80 to 443
-use std::future::Future;
+//! A simple redirect from plain HTTP to HTTPS
+use std::net::SocketAddr;
 use anyhow::{Context, Result};
 #[allow(unused_imports)]
 use log::{trace, debug, info, warn, error};
@@ -7,46 +7,79 @@ use log::{trace, debug, info, warn, error};
 use axum::{
     handler::HandlerWithoutStateExt,
     extract::Host,
-    http::{StatusCode, Uri},
+    http::{uri::{Authority, Scheme}, StatusCode, Uri},
     response::Redirect,
     BoxError,
 };
+use axum_server::Handle;
 
-pub async fn redirect_http_to_https<F>(signal: F) -> Result<()> 
-where 
-    F: Future<Output = ()> + Send + 'static
-{
-    fn make_https(host: String, uri: Uri) -> Result<Uri, BoxError> {
-        let mut parts = uri.into_parts();
+/// The HTTPS version of a request to `host` (which may include a port)
+fn make_https(host: &str, uri: Uri, https_port: u16) -> Result<Uri, BoxError> {
+    let mut parts = uri.into_parts();
 
-        parts.scheme = Some(axum::http::uri::Scheme::HTTPS);
+    parts.scheme = Some(Scheme::HTTPS);
 
-        if parts.path_and_query.is_none() {
-            parts.path_and_query = Some("/".parse().unwrap());
-        }
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some("/".parse().unwrap());
+    }
 
-        let https_host = host.replace("80", "443");
-        parts.authority = Some(https_host.parse()?);
+    // the host keeps the brackets around IPv6 addresses, and only the port
+    // is swapped out
+    let host = host.parse::<Authority>()?;
+    let https_host = match https_port {
+        443 => host.host().to_string(),
+        port => format!("{}:{port}", host.host()),
+    };
+    parts.authority = Some(https_host.parse()?);
 
-        Ok(Uri::from_parts(parts)?)
-    }
+    Ok(Uri::from_parts(parts)?)
+}
 
+/// Redirect requests on `http_port` to `https_port` until `handle` is shut down
+pub async fn redirect_http_to_https(handle: Handle, http_port: u16, https_port: u16) -> Result<()> {
     let redirect = move |Host(host): Host, uri: Uri| async move {
-        match make_https(host, uri) {
+        match make_https(&host, uri, https_port) {
             Ok(uri) => Ok(Redirect::permanent(&uri.to_string())),
-            Err(_error) => {
+            Err(error) => {
+                debug!("Unable to redirect request for {host}: {error}");
                 Err(StatusCode::BAD_REQUEST)
             }
         }
     };
 
-    // let addr = SocketAddr::from(([127, 0, 0, 1], ports.http));
-    debug!("Starting redirect servce from http port 80 to https port 443");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:80").await.unwrap();
-    axum::serve(listener, redirect.into_make_service())
-    .with_graceful_shutdown(signal)
-    .await
-    .context("Redirect service failed")?;
+    debug!("Starting redirect servce from http port {http_port} to https port {https_port}");
+    axum_server::bind(SocketAddr::from(([0, 0, 0, 0], http_port)))
+        .handle(handle)
+        .serve(redirect.into_make_service())
+        .await
+        .with_context(|| format!("Redirect service failed on port {http_port}"))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn redirect(host: &str, https_port: u16) -> String {
+        make_https(host, "/modes?x=1".parse().unwrap(), https_port).unwrap().to_string()
+    }
+
+    #[test]
+    fn keeps_hosts_intact() {
+        assert_eq!(redirect("192.168.1.80", 443), "https://192.168.1.80/modes?x=1");
+        assert_eq!(redirect("lights80.local:80", 443), "https://lights80.local/modes?x=1");
+        assert_eq!(redirect("lights.local:8080", 8443), "https://lights.local:8443/modes?x=1");
+    }
+
+    #[test]
+    fn ipv6_hosts() {
+        assert_eq!(redirect("[fe80::80]:80", 443), "https://[fe80::80]/modes?x=1");
+        assert_eq!(redirect("[::1]", 8443), "https://[::1]:8443/modes?x=1");
+    }
+
+    #[test]
+    fn rejects_bad_hosts() {
+        assert!(make_https("bad host", "/".parse().unwrap(), 443).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+//! Uploading and editing the effect scripts
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    Json,
+};
+
+use crate::script::{self, Script, SCRIPTS_DIR};
+
+use super::{AppError, openapi::{Api, Doc}};
+
+async fn list() -> Json<Vec<String>> {
+    Json(script::list_scripts())
+}
+
+async fn read(Path(name): Path<String>) -> Result<String, AppError> {
+    let path = script::script_path(&name).map_err(AppError::bad_request)?;
+    match tokio::fs::read_to_string(path).await {
+        Ok(source) => Ok(source),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(AppError::not_found(e)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Save a script, scripts that don't compile are rejected with the error
+async fn write(Path(name): Path<String>, source: String) -> Result<StatusCode, AppError> {
+    let path = script::script_path(&name).map_err(AppError::bad_request)?;
+    let source = tokio::task::spawn_blocking(move || Script::compile(&source).map(|_| source)).await?
+        .map_err(AppError::bad_request)?;
+    tokio::fs::create_dir_all(SCRIPTS_DIR).await?;
+    tokio::fs::write(path, source).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove(Path(name): Path<String>) -> Result<StatusCode, AppError> {
+    let path = script::script_path(&name).map_err(AppError::bad_request)?;
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(AppError::not_found(e)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn api() -> Api {
+    Api::new("scripts")
+        .get("/scripts", list, Doc::new("The effect scripts").json::<Vec<String>>())
+        .get("/scripts/:name", read, Doc::new("The source of a script").text("text/plain"))
+        .put("/scripts/:name", write, Doc::new("Save a script, rejected if it doesn't compile").text_body().status(204))
+        .delete("/scripts/:name", remove, Doc::new("Remove a script").status(204))
+}
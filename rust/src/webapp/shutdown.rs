@@ -7,7 +7,9 @@ use axum_server::Handle;
 
 use crate::lights::{LightsCommand, LightsRemote};
 
-pub async fn shutdown_signal(handle: Handle, remote: LightsRemote) {
+/// Wait for ctrl-c or SIGTERM, then turn the lights off and shut down the
+/// servers behind `handles`
+pub async fn shutdown_signal(handles: Vec<Handle>, remote: LightsRemote) {
     let mut sigterm = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
         Ok(sigterm) => {sigterm},
         Err(e) => {
@@ -25,6 +27,9 @@ pub async fn shutdown_signal(handle: Handle, remote: LightsRemote) {
     }
 
     info!("Received termination signal shutting down");
+    let _ = crate::systemd::notify("STOPPING=1");
     let _ = remote.send(LightsCommand::Off).await;
-    handle.graceful_shutdown(Some(Duration::from_secs(10)));
+    for handle in handles {
+        handle.graceful_shutdown(Some(Duration::from_secs(10)));
+    }
 }
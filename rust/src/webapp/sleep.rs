@@ -0,0 +1,64 @@
+//! The sleep timer, and keeping track of when the lights were last used
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::sleep::SleepStatus;
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+#[derive(Deserialize, JsonSchema)]
+struct SetTimer {
+    /// milliseconds before the lights start to fade out
+    #[serde(default)]
+    delay: u64,
+    /// milliseconds the lights take to fade out
+    #[serde(default)]
+    fade: u64,
+}
+
+async fn get_timer(State(state): State<AppState>) -> Json<Option<SleepStatus>> {
+    Json(state.sleep.status())
+}
+
+async fn set_timer(
+    State(state): State<AppState>,
+    Json(req): Json<SetTimer>,
+) -> Result<Json<SleepStatus>, AppError> {
+    let delay = Duration::from_millis(req.delay);
+    let fade = Duration::from_millis(req.fade);
+    Ok(Json(state.sleep.schedule(delay, fade).await?))
+}
+
+async fn cancel_timer(State(state): State<AppState>) -> Result<StatusCode, AppError> {
+    if !state.sleep.cancel().await {
+        return Err(AppError::not_found(anyhow::anyhow!("No sleep timer is set")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Middleware noting when someone changes the lights, for the idle auto-off
+///
+/// Reading the state (e.g. a dashboard polling it) doesn't count, but the
+/// quick on switch does.
+pub async fn track_activity(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if req.method() != Method::GET || req.uri().path() == "/on" {
+        state.sleep.touch();
+    }
+    next.run(req).await
+}
+
+pub fn api() -> Api {
+    Api::new("sleep")
+        .get("/sleep", get_timer, Doc::new("The sleep timer, null when none is set").json::<Option<SleepStatus>>())
+        .put("/sleep", set_timer, Doc::new("Turn the lights off later").body::<SetTimer>().json::<SleepStatus>())
+        .delete("/sleep", cancel_timer, Doc::new("Cancel the sleep timer").status(204))
+}
@@ -0,0 +1,47 @@
+//! What the lights are showing right now
+use axum::{
+    extract::State,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::lights::LightsState;
+
+use super::{AppError, AppState, openapi::{Api, Doc}};
+
+/// What is driving the lights
+///
+/// There is no source for E1.31 (or any other network input): the app only
+/// sends E1.31, Art-Net and DDP to other controllers and never takes frames from
+/// the network, so the lights are always driven by a mode or set by hand. A
+/// sync follower shows the mode it copied from its leader.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(super) enum Source {
+    /// A lights mode is running
+    Mode { name: String },
+    /// Nothing is running, the lights show what was last set
+    Manual,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub(super) struct CurrentState {
+    pub(super) source: Source,
+    #[serde(flatten)]
+    pub(super) lights: LightsState,
+}
+
+async fn get_state(State(state): State<AppState>) -> Result<Json<CurrentState>, AppError> {
+    let source = match state.modes.lock().await.active() {
+        Some(name) => Source::Mode { name: name.to_string() },
+        None => Source::Manual,
+    };
+    let lights = state.remote.state().await?;
+    Ok(Json(CurrentState { source, lights }))
+}
+
+pub fn api() -> Api {
+    Api::new("lights")
+        .get("/state", get_state, Doc::new("What the lights are showing").json::<CurrentState>())
+}
@@ -0,0 +1,105 @@
+//! Self-signed certificate generation and reloading changed certificates
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+#[allow(unused_imports)]
+use log::{trace, debug, info, warn, error};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::process::Command;
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK: Duration = Duration::from_secs(30);
+/// Lifetime of a generated certificate, browsers reject much longer ones
+const CERT_DAYS: u32 = 365;
+
+/// Load the certificate, generating a self-signed one first if there is none
+pub async fn load(tls: &TlsConfig) -> Result<RustlsConfig> {
+    if !tls.cert.exists() || !tls.key.exists() {
+        generate(tls).await?;
+    }
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .context("Failed to load TLS config")
+}
+
+/// Create a self-signed certificate and key with openssl
+async fn generate(tls: &TlsConfig) -> Result<()> {
+    let Some(common_name) = tls.names.first() else {
+        bail!("No names configured for the certificate");
+    };
+    info!("Generating a self-signed certificate for {}", tls.names.join(", "));
+    for path in [&tls.cert, &tls.key] {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+    }
+    let status = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "rsa:4096", "-sha256", "-nodes"])
+        .args(["-days", &CERT_DAYS.to_string()])
+        .arg("-keyout").arg(&tls.key)
+        .arg("-out").arg(&tls.cert)
+        .args(["-subj", &format!("/CN={common_name}")])
+        .args(["-addext", &subject_alt_names(&tls.names)])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .context("Failed to run openssl")?;
+    if !status.success() {
+        bail!("openssl failed to generate the certificate ({status})");
+    }
+    Ok(())
+}
+
+/// The openssl `subjectAltName` extension for host names and IP addresses
+fn subject_alt_names(names: &[String]) -> String {
+    let names: Vec<String> = names.iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(_) => format!("IP:{name}"),
+            Err(_) => format!("DNS:{name}"),
+        })
+        .collect();
+    format!("subjectAltName={}", names.join(","))
+}
+
+/// Reload the certificate whenever the files change
+pub async fn watch(config: RustlsConfig, tls: TlsConfig) {
+    let mut last = modified(&tls.cert, &tls.key);
+    let mut interval = tokio::time::interval(RELOAD_CHECK);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let changed = modified(&tls.cert, &tls.key);
+        if changed == last {
+            continue;
+        }
+        match config.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => {
+                info!("Reloaded the TLS certificate");
+                last = changed;
+            },
+            // the files may be half written, so try again next time
+            Err(e) => warn!("Failed to reload the TLS certificate: {e:?}"),
+        }
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let time = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((time(cert)?, time(key)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alt_names() {
+        let names = vec!["lights.local".to_string(), "192.168.1.20".to_string()];
+        assert_eq!(subject_alt_names(&names), "subjectAltName=DNS:lights.local,IP:192.168.1.20");
+    }
+}